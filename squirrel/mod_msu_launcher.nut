@@ -2,9 +2,11 @@
 	ID = $ID$,
 	Version = $Version$,
 	Name = $Name$,
+	Description = $Description$,
 	Resources = {
 		OnRunning = $OnRunning$,
 		OnStart = $OnStart$,
+		Sources = $Sources$,
 	},
 }
 
@@ -20,3 +22,7 @@
 ::$NameSpace$.hasResource <- function(_resourceName) {
 	return this.hasResourceOnStart(_resourceName) || this.hasResourceOnRunning(_resourceName);
 }
+
+::$NameSpace$.getResourceSources <- function(_resourceName) {
+	return _resourceName in this.Resources.Sources ? this.Resources.Sources[_resourceName] : [];
+}
@@ -0,0 +1,95 @@
+//! Files written through a temporary `<name>.partial` file next to them, which replaces the file
+//! once it's complete. A write that is cut short, e.g. by `--timeout`, leaves the previous file
+//! intact instead of a truncated one, and at most the `.partial` file behind. A write that fails,
+//! or is dropped before `commit`, removes the `.partial` file.
+
+use anyhow::{Context, Result};
+use std::fs::File;
+use std::io::{Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+pub struct AtomicFile {
+	/// Taken by `commit`, which closes it before renaming
+	file: Option<File>,
+	temp_path: PathBuf,
+	path: PathBuf,
+	committed: bool,
+}
+
+fn temp_path(path: &Path) -> PathBuf {
+	let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+	file_name.push(".partial");
+	path.with_file_name(file_name)
+}
+
+impl AtomicFile {
+	pub fn create(path: &Path) -> Result<Self> {
+		let temp_path = temp_path(path);
+		let file =
+			File::create(&temp_path).with_context(|| format!("Couldn't create {:?}", temp_path))?;
+		Ok(Self {
+			file: Some(file),
+			temp_path,
+			path: path.to_path_buf(),
+			committed: false,
+		})
+	}
+
+	/// Replaces the file with what was written, keeping the permissions of the file it replaces
+	pub fn commit(mut self) -> Result<()> {
+		let mut file = self.file.take().expect("AtomicFile is only committed once");
+		file.flush()?;
+		drop(file);
+		if let Ok(metadata) = std::fs::metadata(&self.path) {
+			std::fs::set_permissions(&self.temp_path, metadata.permissions())?;
+		}
+		std::fs::rename(&self.temp_path, &self.path).with_context(|| {
+			format!("Couldn't replace {:?} with {:?}", self.path, self.temp_path)
+		})?;
+		self.committed = true;
+		Ok(())
+	}
+
+	fn file(&mut self) -> &mut File {
+		self.file
+			.as_mut()
+			.expect("AtomicFile isn't used after commit")
+	}
+}
+
+impl Drop for AtomicFile {
+	fn drop(&mut self) {
+		if !self.committed {
+			drop(self.file.take());
+			let _ = std::fs::remove_file(&self.temp_path);
+		}
+	}
+}
+
+impl Write for AtomicFile {
+	fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+		self.file().write(buf)
+	}
+
+	fn flush(&mut self) -> std::io::Result<()> {
+		self.file().flush()
+	}
+}
+
+impl Seek for AtomicFile {
+	fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+		self.file().seek(pos)
+	}
+}
+
+pub fn write(path: &Path, contents: impl AsRef<[u8]>) -> Result<()> {
+	let mut file = AtomicFile::create(path)?;
+	file.write_all(contents.as_ref())?;
+	file.commit()
+}
+
+pub fn copy(from: &Path, to: &Path) -> Result<()> {
+	let mut file = AtomicFile::create(to)?;
+	std::io::copy(&mut File::open(from)?, &mut file)?;
+	file.commit()
+}
@@ -3,24 +3,66 @@ use std::path::{Path, PathBuf};
 use anyhow::{anyhow, Context, Result};
 use serde::{Deserialize, Serialize};
 
+use crate::atomic_file;
+use crate::patcher_laa::ForcedVersion;
+
 const CONFIG_FILE: &str = "bb-patcher-config.toml";
 const BB_GAME_ID: u32 = 365360;
 
+/// Folders that some installs or archives put the actual game folder in
+const GAME_SUBDIRS: [&str; 2] = ["game", "Battle Brothers"];
+/// Folders inside the game folder that people tend to point at instead of the game folder itself
+const GAME_INNER_DIRS: [&str; 2] = ["win32", "data"];
+/// How many edits a sibling folder's name may be away from the given one to be suggested
+const MAX_SIBLING_DISTANCE: usize = 3;
+
 #[derive(Deserialize, Serialize, Clone, Debug)]
 pub struct Config {
+	#[serde(default)]
 	pub bb_path: Option<PathBuf>,
+	/// Game path from --assume-path or --env-file, used instead of `bb_path` but never saved
+	#[serde(skip)]
+	pub assumed_path: Option<PathBuf>,
+	/// SHA-256 of BattleBrothers.exe after it was last patched, used to detect it being reverted.
+	/// Belongs to the install at `bb_path`, so it's cleared when the game path changes.
+	#[serde(default)]
+	pub last_patched_hash: Option<String>,
+	/// Whether `preload` and `all` generate the preload, allows turning it off for troubleshooting
+	#[serde(default = "default_preload_enabled")]
+	pub preload_enabled: bool,
+	/// Kind of exe to patch this install's exe as when it isn't a known version. Belongs to the
+	/// install at `bb_path`, so it's cleared when the game path changes.
+	#[serde(default)]
+	pub force_unknown_as: Option<ForcedVersion>,
+	/// Refuse to patch unless a backup of a known unpatched version exists, as if
+	/// `--require-clean-backup` was always passed
+	#[serde(default)]
+	pub require_clean_backup: bool,
+	/// How many backups of each kind patching keeps, as if `--backup-retention` was always passed
+	#[serde(default)]
+	pub backup_retention: Option<usize>,
+}
+
+fn default_preload_enabled() -> bool {
+	true
 }
 
 impl Default for Config {
 	fn default() -> Self {
 		Self {
 			bb_path: find_bb().ok(),
+			assumed_path: None,
+			last_patched_hash: None,
+			preload_enabled: default_preload_enabled(),
+			force_unknown_as: None,
+			require_clean_backup: false,
+			backup_retention: None,
 		}
 	}
 }
 
 #[cfg(feature = "steam-detect")]
-fn find_bb() -> Result<PathBuf> {
+pub fn find_bb() -> Result<PathBuf> {
 	let steam_dir = steamlocate::SteamDir::locate().context("steamlocate couldn't locate Steam")?;
 	match steam_dir.find_app(BB_GAME_ID)? {
 		Some((app, lib)) => Ok(lib.resolve_app_dir(&app)),
@@ -29,12 +71,93 @@ fn find_bb() -> Result<PathBuf> {
 }
 
 #[cfg(not(feature = "steam-detect"))]
-fn find_bb() -> Result<PathBuf> {
+pub fn find_bb() -> Result<PathBuf> {
 	Err(anyhow!(
 		"Steam auto-detection disabled. Please specify game path manually."
 	))
 }
 
+/// Whether `path` is a game folder, i.e. it has `win32/BattleBrothers.exe` and `data/data_001.dat`
+pub fn is_game_dir(path: &Path) -> bool {
+	path.join("win32").join("BattleBrothers.exe").is_file()
+		&& path.join("data").join("data_001.dat").is_file()
+}
+
+/// The game folder `path` refers to, which may be the exe or the game folder, without checking it
+pub fn game_dir_from_path(path: &Path) -> PathBuf {
+	match path.is_file() {
+		true => path
+			.parent()
+			.and_then(Path::parent)
+			.unwrap_or(path)
+			.to_path_buf(),
+		false => path.to_path_buf(),
+	}
+}
+
+/// A game folder in `path` itself, or one that's inside it or next to it
+fn find_game_dir_near(path: &Path) -> Option<PathBuf> {
+	if is_game_dir(path) {
+		return Some(path.to_path_buf());
+	}
+	GAME_SUBDIRS
+		.iter()
+		.map(|subdir| path.join(subdir))
+		.find(|candidate| is_game_dir(candidate))
+}
+
+pub fn edit_distance(a: &str, b: &str) -> usize {
+	let b: Vec<char> = b.chars().collect();
+	let mut previous: Vec<usize> = (0..=b.len()).collect();
+	for (i, a_char) in a.chars().enumerate() {
+		let mut current = vec![i + 1];
+		for (j, b_char) in b.iter().enumerate() {
+			let substitution = previous[j] + usize::from(a_char != *b_char);
+			current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+		}
+		previous = current;
+	}
+	previous[b.len()]
+}
+
+/// Best guess at the game folder the user meant when `path` isn't one: a game folder inside it,
+/// the game folder around a `win32` or `data` folder, or a sibling folder with a similar name.
+pub fn complete_game_path(path: &Path) -> Option<PathBuf> {
+	if path.is_dir() {
+		if let Some(candidate) = find_game_dir_near(path) {
+			return Some(candidate);
+		}
+	}
+	let name = path.file_name()?.to_string_lossy().to_lowercase();
+	let parent = path
+		.parent()
+		.filter(|parent| !parent.as_os_str().is_empty())
+		.unwrap_or(Path::new("."));
+	if GAME_INNER_DIRS.contains(&name.as_str()) && is_game_dir(parent) {
+		return Some(parent.to_path_buf());
+	}
+	let mut siblings: Vec<(bool, usize, PathBuf)> = std::fs::read_dir(parent)
+		.ok()?
+		.filter_map(|entry| entry.ok())
+		.filter(|entry| entry.path() != path)
+		.filter_map(|entry| {
+			let sibling_name = entry.file_name().to_string_lossy().to_lowercase();
+			let is_prefix = sibling_name.starts_with(&name);
+			let distance = edit_distance(&name, &sibling_name);
+			if !is_prefix && distance > MAX_SIBLING_DISTANCE {
+				return None;
+			}
+			let candidate = find_game_dir_near(&entry.path())?;
+			Some((!is_prefix, distance, candidate))
+		})
+		.collect();
+	siblings.sort();
+	siblings
+		.into_iter()
+		.next()
+		.map(|(_, _, candidate)| candidate)
+}
+
 #[derive(Debug)]
 pub struct DataPath(PathBuf);
 
@@ -76,10 +199,36 @@ impl Config {
 
 	pub fn save(&self) -> Result<()> {
 		let config_text = toml::to_string(self).context("Couldn't serialize config file")?;
-		std::fs::write(CONFIG_FILE, config_text).context("Couldn't write config file")?;
+		atomic_file::write(Path::new(CONFIG_FILE), config_text)
+			.context("Couldn't write config file")?;
 		Ok(())
 	}
 
+	/// Where the config file is read from and written to
+	pub fn file_path() -> PathBuf {
+		std::env::current_dir()
+			.map(|dir| dir.join(CONFIG_FILE))
+			.unwrap_or_else(|_| PathBuf::from(CONFIG_FILE))
+	}
+
+	/// Deletes the config file, returns whether there was one
+	pub fn remove_file() -> Result<bool> {
+		if !Path::new(CONFIG_FILE).exists() {
+			return Ok(false);
+		}
+		std::fs::remove_file(CONFIG_FILE).context("Couldn't remove config file")?;
+		Ok(true)
+	}
+
+	/// Keys that are set in the config file, as opposed to falling back to their defaults
+	pub fn stored_keys() -> Vec<String> {
+		std::fs::read_to_string(CONFIG_FILE)
+			.ok()
+			.and_then(|config_text| toml::from_str::<toml::Table>(&config_text).ok())
+			.map(|table| table.keys().cloned().collect())
+			.unwrap_or_default()
+	}
+
 	fn load() -> Result<Self> {
 		let config_text =
 			std::fs::read_to_string(CONFIG_FILE).context("Couldn't read config file")?;
@@ -88,16 +237,94 @@ impl Config {
 		Ok(config)
 	}
 
+	/// Rewrites the config file in the current format, filling in defaults for missing fields
+	/// and dropping keys that are no longer used. Returns a description of each change.
+	pub fn migrate() -> Result<Vec<String>> {
+		let config_text =
+			std::fs::read_to_string(CONFIG_FILE).context("Couldn't read config file")?;
+		let old_table: toml::Table =
+			toml::from_str(&config_text).context("Couldn't parse config file")?;
+		let config: Config = old_table
+			.clone()
+			.try_into()
+			.context("Couldn't deserialize config file")?;
+		let new_table = toml::Table::try_from(&config).context("Couldn't serialize config file")?;
+
+		let mut changes = Vec::new();
+		for key in new_table.keys().filter(|key| !old_table.contains_key(*key)) {
+			changes.push(format!(
+				"added missing key '{}' with its default value",
+				key
+			));
+		}
+		for key in old_table.keys().filter(|key| !new_table.contains_key(*key)) {
+			changes.push(format!("dropped obsolete key '{}'", key));
+		}
+		config.save()?;
+		Ok(changes)
+	}
+
+	pub fn set_last_patched_hash(&mut self, hash: String) -> Result<()> {
+		self.last_patched_hash = Some(hash);
+		self.save()
+	}
+
+	pub fn clear_last_patched_hash(&mut self) -> Result<()> {
+		self.last_patched_hash = None;
+		self.save()
+	}
+
+	pub fn set_force_unknown_as(&mut self, forced: Option<ForcedVersion>) -> Result<()> {
+		self.force_unknown_as = forced;
+		self.save()
+	}
+
+	pub fn set_require_clean_backup(&mut self, required: bool) -> Result<()> {
+		self.require_clean_backup = required;
+		self.save()
+	}
+
+	pub fn set_backup_retention(&mut self, retention: Option<usize>) -> Result<()> {
+		self.backup_retention = retention;
+		self.save()
+	}
+
+	/// Switches to another install, dropping the settings that only apply to the previous one
+	fn change_bb_path(&mut self, bb_path: &Path) {
+		if self.bb_path.as_deref() != Some(bb_path) {
+			self.last_patched_hash = None;
+			self.force_unknown_as = None;
+		}
+		self.bb_path = Some(bb_path.to_path_buf());
+	}
+
+	pub fn set_preload_enabled(&mut self, enabled: bool) -> Result<()> {
+		self.preload_enabled = enabled;
+		self.save()
+	}
+
+	/// The game folder commands work on, an assumed one over the saved one
+	pub fn game_path(&self) -> Option<&Path> {
+		self.assumed_path.as_deref().or(self.bb_path.as_deref())
+	}
+
+	/// `force_unknown_as` if the game folder is the saved one it was set for, an assumed other
+	/// install is a different exe it was never meant for
+	pub fn force_unknown_as_for_game_path(&self) -> Option<ForcedVersion> {
+		match self.game_path() == self.bb_path.as_deref() {
+			true => self.force_unknown_as,
+			false => None,
+		}
+	}
+
 	pub fn get_bb_exe_path(&self) -> Option<ExePath> {
-		self.bb_path
-			.as_ref()
+		self.game_path()
 			.map(|bb_path| ExePath::new(bb_path.join("win32").join("BattleBrothers.exe")))
 			.filter(|exe_path| exe_path.as_ref().exists())
 	}
 
 	pub fn get_bb_data_path(&self) -> Option<DataPath> {
-		self.bb_path
-			.as_ref()
+		self.game_path()
 			.map(|bb_path| DataPath::new(bb_path.join("data")))
 			.filter(|data_path| data_path.join("data_001.dat").exists())
 	}
@@ -112,7 +339,7 @@ impl Config {
 		if !data_path.exists() {
 			return Err(anyhow!("data_001.dat not found at {:?}", data_path));
 		}
-		self.bb_path = Some(bb_path.to_path_buf());
+		self.change_bb_path(bb_path);
 		self.save()?;
 		Ok(())
 	}
@@ -133,9 +360,98 @@ impl Config {
 		if !bb_dir.join("data").join("data_001.dat").exists() {
 			return Err(anyhow!("Couldn't find valid data folder"));
 		}
-		self.bb_path = Some(bb_dir.to_path_buf());
+		self.change_bb_path(bb_dir);
 		self.save()?;
 
 		Ok(bb_dir.to_path_buf())
 	}
 }
+
+/// Settings read from `--env-file`, one `KEY=VALUE` per line. They take precedence over the
+/// config file but not over flags, and are never saved to the config file.
+#[derive(Default)]
+pub struct EnvFile {
+	/// Used like `--assume-path`, without validating or saving it
+	pub game_path: Option<PathBuf>,
+	pub skip_steam_drm: Option<bool>,
+	pub backup_compress: Option<bool>,
+	pub require_clean_backup: Option<bool>,
+	pub backup_retention: Option<usize>,
+	pub strict_backup_verify: Option<bool>,
+}
+
+const ENV_FILE_KEYS: [&str; 6] = [
+	"game_path",
+	"skip_steam_drm",
+	"backup_compress",
+	"require_clean_backup",
+	"backup_retention",
+	"strict_backup_verify",
+];
+
+fn parse_env_bool(value: &str) -> Result<bool> {
+	match value.to_ascii_lowercase().as_str() {
+		"true" | "1" | "yes" => Ok(true),
+		"false" | "0" | "no" => Ok(false),
+		_ => Err(anyhow!("expected true or false, got {:?}", value)),
+	}
+}
+
+impl EnvFile {
+	/// Blank lines and lines starting with `#` are skipped, values may be quoted
+	pub fn read(path: &Path) -> Result<Self> {
+		let contents = std::fs::read_to_string(path)
+			.with_context(|| format!("Couldn't read env file {:?}", path))?;
+		let mut env_file = Self::default();
+		for (i, line) in contents.lines().enumerate() {
+			let line = line.trim();
+			if line.is_empty() || line.starts_with('#') {
+				continue;
+			}
+			env_file
+				.set(line)
+				.with_context(|| format!("Invalid env file {:?}, line {}", path, i + 1))?;
+		}
+		Ok(env_file)
+	}
+
+	fn set(&mut self, line: &str) -> Result<()> {
+		let (key, value) = line.split_once('=').context("expected KEY=VALUE")?;
+		let (key, value) = (key.trim(), value.trim());
+		let value = value
+			.strip_prefix('"')
+			.and_then(|value| value.strip_suffix('"'))
+			.unwrap_or(value);
+		let key_context = || format!("invalid value for {}", key);
+		match key {
+			"game_path" => self.game_path = Some(PathBuf::from(value)),
+			"skip_steam_drm" => {
+				self.skip_steam_drm = Some(parse_env_bool(value).with_context(key_context)?)
+			}
+			"backup_compress" => {
+				self.backup_compress = Some(parse_env_bool(value).with_context(key_context)?)
+			}
+			"require_clean_backup" => {
+				self.require_clean_backup = Some(parse_env_bool(value).with_context(key_context)?)
+			}
+			"backup_retention" => {
+				let retention: usize = value.parse().with_context(key_context)?;
+				if retention == 0 {
+					return Err(anyhow!("{}: must be at least 1", key_context()));
+				}
+				self.backup_retention = Some(retention);
+			}
+			"strict_backup_verify" => {
+				self.strict_backup_verify = Some(parse_env_bool(value).with_context(key_context)?)
+			}
+			_ => {
+				return Err(anyhow!(
+					"unknown key {:?}, expected one of: {}",
+					key,
+					ENV_FILE_KEYS.join(", ")
+				))
+			}
+		}
+		Ok(())
+	}
+}
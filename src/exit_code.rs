@@ -0,0 +1,76 @@
+//! Exit codes of bb-patcher. Scripts rely on these, so existing codes must never change meaning.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitCode {
+	Success,
+	Error,
+	Usage,
+	HashChanged,
+	AnalysisWarnings,
+	AnalysisErrors,
+	AlreadyPatched,
+	TimedOut,
+	InstallProblems,
+	ChecksumMismatch,
+	ManifestMismatch,
+}
+
+impl ExitCode {
+	pub const ALL: [ExitCode; 11] = [
+		ExitCode::Success,
+		ExitCode::Error,
+		ExitCode::Usage,
+		ExitCode::HashChanged,
+		ExitCode::AnalysisWarnings,
+		ExitCode::AnalysisErrors,
+		ExitCode::AlreadyPatched,
+		ExitCode::TimedOut,
+		ExitCode::InstallProblems,
+		ExitCode::ChecksumMismatch,
+		ExitCode::ManifestMismatch,
+	];
+
+	pub fn code(self) -> u8 {
+		match self {
+			ExitCode::Success => 0,
+			ExitCode::Error => 1,
+			ExitCode::Usage => 2,
+			ExitCode::HashChanged => 3,
+			ExitCode::AnalysisWarnings => 4,
+			ExitCode::AnalysisErrors => 5,
+			ExitCode::AlreadyPatched => 6,
+			ExitCode::TimedOut => 7,
+			ExitCode::InstallProblems => 8,
+			ExitCode::ChecksumMismatch => 9,
+			ExitCode::ManifestMismatch => 10,
+		}
+	}
+
+	pub fn description(self) -> &'static str {
+		match self {
+			ExitCode::Success => "Success",
+			ExitCode::Error => "An error occurred, the message is printed to stderr",
+			ExitCode::Usage => "Invalid command line arguments",
+			ExitCode::HashChanged => {
+				"check: the exe's hash differs from the one it was compared against"
+			}
+			ExitCode::AnalysisWarnings => "preload --analyze --strict: the analysis found warnings",
+			ExitCode::AnalysisErrors => "preload --analyze --strict: the analysis found errors",
+			ExitCode::AlreadyPatched => {
+				"patch4gb: the exe was already patched, nothing changed (0 with --no-op-ok)"
+			}
+			ExitCode::TimedOut => "--timeout: the command didn't finish in time and was stopped",
+			ExitCode::InstallProblems => "verify-install: at least one check failed",
+			ExitCode::ChecksumMismatch => {
+				"checksum: the stored checksum is wrong (0 with --fix, which corrects it)"
+			}
+			ExitCode::ManifestMismatch => "import-manifest: the install differs from the manifest",
+		}
+	}
+}
+
+impl From<ExitCode> for std::process::ExitCode {
+	fn from(value: ExitCode) -> Self {
+		std::process::ExitCode::from(value.code())
+	}
+}
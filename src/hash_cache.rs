@@ -0,0 +1,109 @@
+//! Opt-in on-disk cache of exe hashes, so that scripts calling `detect --hash-cache` or
+//! `check --hash-cache` over and over don't rehash an exe that didn't change. Entries are keyed by the exe's path and only used while its
+//! size and modification time are the same; everything that writes the exe also drops its entry.
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use crate::atomic_file;
+use crate::config::Config;
+use crate::patcher_laa::sha_hash_path;
+
+const CACHE_FILE: &str = "bb-patcher-hash-cache.json";
+
+#[derive(Serialize, Deserialize, Default)]
+struct HashCache {
+	entries: BTreeMap<PathBuf, CacheEntry>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq)]
+struct CacheEntry {
+	size: u64,
+	modified_ns: u128,
+	sha256: String,
+}
+
+/// Next to the config file
+fn cache_path() -> PathBuf {
+	Config::file_path().with_file_name(CACHE_FILE)
+}
+
+fn key(path: &Path) -> PathBuf {
+	std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+/// A missing or unreadable cache is the same as an empty one
+fn load() -> HashCache {
+	std::fs::read_to_string(cache_path())
+		.ok()
+		.and_then(|cache_text| serde_json::from_str(&cache_text).ok())
+		.unwrap_or_default()
+}
+
+/// Failing to save the cache only costs a rehash next time, so it isn't an error
+fn save(cache: &HashCache) {
+	if let Ok(cache_text) = serde_json::to_string_pretty(cache) {
+		let _ = atomic_file::write(&cache_path(), cache_text);
+	}
+}
+
+fn file_stamp(path: &Path) -> Result<(u64, u128)> {
+	let metadata = std::fs::metadata(path)?;
+	let modified_ns = metadata
+		.modified()?
+		.duration_since(UNIX_EPOCH)
+		.map(|duration| duration.as_nanos())
+		.unwrap_or_default();
+	Ok((metadata.len(), modified_ns))
+}
+
+/// SHA-256 of the file, from the cache if it didn't change since it was cached. With `use_cache`
+/// false the file is hashed without reading or writing the cache.
+pub fn sha_hash_path_cached(path: &Path, use_cache: bool) -> Result<Vec<u8>> {
+	if !use_cache {
+		return sha_hash_path(path);
+	}
+	let (size, modified_ns) = file_stamp(path)?;
+	let mut cache = load();
+	let key = key(path);
+	let cached = cache
+		.entries
+		.get(&key)
+		.filter(|entry| entry.size == size && entry.modified_ns == modified_ns)
+		.and_then(|entry| const_hex::decode(&entry.sha256).ok());
+	if let Some(hash) = cached {
+		return Ok(hash);
+	}
+	let hash = sha_hash_path(path)?;
+	cache.entries.insert(
+		key,
+		CacheEntry {
+			size,
+			modified_ns,
+			sha256: const_hex::encode(&hash),
+		},
+	);
+	save(&cache);
+	Ok(hash)
+}
+
+/// Drops the cached hash of a file that is about to change or just changed
+pub fn invalidate(path: &Path) {
+	let mut cache = load();
+	if cache.entries.remove(&key(path)).is_some() {
+		save(&cache);
+	}
+}
+
+/// Deletes the cache file, returns whether there was one
+pub fn remove() -> Result<bool> {
+	let path = cache_path();
+	if !path.exists() {
+		return Ok(false);
+	}
+	std::fs::remove_file(&path)?;
+	Ok(true)
+}
@@ -1,15 +1,35 @@
 use anyhow::{anyhow, Context, Result};
-use clap::{Parser, Subcommand};
-use std::path::PathBuf;
+use clap::{Args, CommandFactory, Parser, Subcommand};
+use std::collections::HashSet;
+use std::io::{IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
+mod atomic_file;
 mod config;
+mod exit_code;
+mod hash_cache;
+mod manifest;
+mod output;
 mod patcher_laa;
 mod patcher_preload;
 mod pe;
+mod process;
 
-use config::Config;
-use patcher_laa::{detect_version, patch_exe, GameVersion};
-use patcher_preload::gather_and_create_mod;
+use config::{complete_game_path, is_game_dir, Config, DataPath, EnvFile, ExePath};
+use exit_code::ExitCode;
+use manifest::InstallManifest;
+use output::OutputOptions;
+use patcher_laa::{
+	patch_exe, sha_hash_path, Backup, ForcedVersion, GameVersion, GameVersionDb, HashAlgorithm,
+	PatchOptions,
+};
+use patcher_preload::{
+	gather_and_create_mod, Analysis, IntraModSort, PreloadInfo, PreloadOptions, ResourceHandler,
+	ScanProfile, Verdict,
+};
+use pe::{ImageDosHeader, ImageFileHeader, IMAGE_FILE_LARGE_ADDRESS_AWARE};
+use serde::Serialize;
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 
@@ -21,96 +41,924 @@ const VERSION: &str = env!("CARGO_PKG_VERSION");
 #[command(name = "bb-patcher")]
 #[command(version = VERSION)]
 #[command(about = "Battle Brothers CLI Patcher - Apply 4GB patch and create mod preloads")]
+#[command(arg_required_else_help = true)]
 struct Cli {
 	#[command(subcommand)]
-	command: Commands,
+	command: Option<Commands>,
+
+	/// Print every exit code and its meaning
+	#[arg(long, hide = true, exclusive = true)]
+	print_exit_codes: bool,
+
+	/// Emit machine readable JSON where supported, including errors
+	#[arg(long, global = true)]
+	json: bool,
+
+	/// Print more detail, such as the full chain of causes of an error
+	#[arg(short, long, global = true)]
+	verbose: bool,
+
+	/// Don't use colors in the output
+	#[arg(long, global = true)]
+	no_color: bool,
+
+	/// Print the settings the command would use and where each comes from, instead of running it
+	#[arg(long, global = true)]
+	dump_config: bool,
+
+	/// Read settings from a file of KEY=VALUE lines, e.g. `backup_retention=3`. They override
+	/// the config file, flags override them.
+	#[arg(long, global = true, value_name = "FILE")]
+	env_file: Option<PathBuf>,
+
+	/// Give up on the command after SECONDS and exit with 7, e.g. when a launcher can't hang
+	/// on a stalled network drive
+	#[arg(long, global = true, value_name = "SECONDS", value_parser = clap::value_parser!(u64).range(1..))]
+	timeout: Option<u64>,
+}
+
+#[derive(Args, Clone)]
+struct GamePathArgs {
+	/// Path to BattleBrothers.exe or the game directory
+	#[arg(short, long)]
+	path: Option<PathBuf>,
+
+	/// File containing the path to BattleBrothers.exe or the game directory
+	#[arg(long, value_name = "FILE", conflicts_with = "path")]
+	path_file: Option<PathBuf>,
+
+	/// Game directory resolved by an earlier command, used as-is without validating or saving it
+	#[arg(long, value_name = "DIR", env = ASSUME_PATH_ENV)]
+	assume_path: Option<PathBuf>,
+
+	/// `game_path` from --env-file, used like --assume-path
+	#[arg(skip)]
+	env_game_path: Option<PathBuf>,
+}
+
+/// Lets a script resolve the game path once and pass it on to every following command
+const ASSUME_PATH_ENV: &str = "BB_PATCHER_ASSUME_PATH";
+
+/// How a command gets its game path, see `GamePathArgs::choose`
+enum GamePathChoice {
+	/// From --path or --path-file, validated and saved to the config
+	Given(PathBuf),
+	/// From --assume-path, its environment variable or --env-file, used as-is and never saved
+	Assumed(PathBuf),
+	/// The config file's, or Steam detection's if there is no config file
+	Saved,
+}
+
+impl GamePathArgs {
+	/// The game path comes from, in order of precedence: --path or --path-file, --assume-path,
+	/// the BB_PATCHER_ASSUME_PATH environment variable, --env-file, the config file and Steam
+	/// detection. Returns the choice and a description of where it comes from.
+	fn choose(&self, config: &Config) -> Result<(GamePathChoice, String)> {
+		if let Some(path_file) = &self.path_file {
+			let contents = std::fs::read_to_string(path_file)
+				.with_context(|| format!("Couldn't read path file {:?}", path_file))?;
+			let path = contents.trim();
+			if path.is_empty() {
+				return Err(anyhow!("Path file {:?} is empty", path_file));
+			}
+			return Ok((
+				GamePathChoice::Given(PathBuf::from(path)),
+				"--path-file".to_owned(),
+			));
+		}
+		if let Some(path) = &self.path {
+			return Ok((GamePathChoice::Given(path.clone()), "--path".to_owned()));
+		}
+		if let Some(assume_path) = &self.assume_path {
+			let source = match std::env::var_os(ASSUME_PATH_ENV) {
+				Some(env) if env == assume_path.as_os_str() => format!("env {}", ASSUME_PATH_ENV),
+				_ => "--assume-path".to_owned(),
+			};
+			return Ok((GamePathChoice::Assumed(assume_path.clone()), source));
+		}
+		if let Some(env_game_path) = &self.env_game_path {
+			return Ok((
+				GamePathChoice::Assumed(env_game_path.clone()),
+				"--env-file".to_owned(),
+			));
+		}
+		Ok((GamePathChoice::Saved, saved_game_path_source(config)))
+	}
+}
+
+/// Where the game path of a loaded config comes from
+fn saved_game_path_source(config: &Config) -> String {
+	let stored = Config::stored_keys().iter().any(|key| key == "bb_path");
+	match (config.bb_path.is_some(), stored) {
+		(true, true) => "config".to_owned(),
+		(true, false) => "Steam detection".to_owned(),
+		(false, _) => "not found".to_owned(),
+	}
+}
+
+#[derive(Args)]
+struct PatchArgs {
+	/// Skip Steam DRM removal check (patch may not work correctly)
+	#[arg(long)]
+	skip_steam_drm: bool,
+
+	/// Store the backup of the exe compressed, as a .bak.zip next to it
+	#[arg(long)]
+	backup_compress: bool,
+
+	/// Expert: read the PE header at OFFSET (decimal or 0x hex) instead of locating it through
+	/// the DOS header, for exes with unusual packers or overlays. Bypasses the usual safety checks.
+	#[arg(long, value_name = "OFFSET", value_parser = parse_offset)]
+	pe_offset: Option<u64>,
+
+	/// Refuse to patch unless a backup of a known unpatched version exists or was just made
+	#[arg(long)]
+	require_clean_backup: bool,
+
+	/// Keep only the newest N backups of each kind, deleting older ones that were moved aside.
+	/// The oldest clean backup is always kept.
+	#[arg(long, value_name = "N", value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+	backup_retention: Option<usize>,
+
+	/// Read the backup back after making it and refuse to patch unless its hash matches the
+	/// exe's (costs an extra read of the exe and the backup)
+	#[arg(long)]
+	strict_backup_verify: bool,
+
+	/// `require_clean_backup` from --env-file, which unlike the flag can also turn it off
+	#[arg(skip)]
+	env_require_clean_backup: Option<bool>,
+}
+
+fn parse_offset(value: &str) -> Result<u64, std::num::ParseIntError> {
+	match value
+		.strip_prefix("0x")
+		.or_else(|| value.strip_prefix("0X"))
+	{
+		Some(hex) => u64::from_str_radix(hex, 16),
+		None => value.parse(),
+	}
+}
+
+impl PatchArgs {
+	fn options(&self, config: &Config) -> PatchOptions {
+		PatchOptions {
+			skip_steam_drm: self.skip_steam_drm,
+			force_unknown_as: config.force_unknown_as_for_game_path(),
+			backup_compress: self.backup_compress,
+			pe_offset: self.pe_offset,
+			require_clean_backup: self.require_clean_backup
+				|| self
+					.env_require_clean_backup
+					.unwrap_or(config.require_clean_backup),
+			backup_retention: self.backup_retention.or(config.backup_retention),
+			strict_backup_verify: self.strict_backup_verify,
+		}
+	}
+}
+
+#[derive(Args)]
+struct PreloadArgs {
+	/// Continue past mods that fail to scan and report them at the end
+	#[arg(long)]
+	keep_going: bool,
+
+	/// Description shown for the generated preload mod
+	#[arg(long, value_name = "TEXT")]
+	description: Option<String>,
+
+	/// Keep the source manifests' timestamps instead of fixed ones (breaks reproducibility)
+	#[arg(long)]
+	preserve_timestamps: bool,
+
+	/// Check that every registered resource exists in its mod's archive and has a name that is
+	/// valid on Windows (slower)
+	#[arg(long)]
+	verify_resources: bool,
+
+	/// Don't create the preload if --verify-resources finds problems or a mod is denylisted
+	#[arg(long)]
+	strict: bool,
+
+	/// Write the preload zip here instead of the data folder, use `-` for stdout
+	#[arg(short, long, value_name = "PATH")]
+	output: Option<PathBuf>,
+
+	/// Strip up to DEPTH wrapper folders in front of known game folders (gfx, scripts, ...)
+	/// from resource paths
+	#[arg(long, value_name = "DEPTH")]
+	flatten: Option<usize>,
+
+	/// Start from the resources of an existing preload and add the scanned ones on top
+	#[arg(long, value_name = "EXISTING_ZIP")]
+	merge: Option<PathBuf>,
+
+	/// With --merge, drop resources of the existing preload whose source mods are no longer installed
+	#[arg(long, requires = "merge")]
+	prune: bool,
+
+	/// Write metrics about the scan (mod and resource counts, sizes, conflicts) as JSON
+	#[arg(long, value_name = "PATH")]
+	stats: Option<PathBuf>,
+
+	/// Write the preload's files into DIR for inspection instead of the zip, or in addition
+	/// to it when --output is given
+	#[arg(long, value_name = "DIR")]
+	extract_to: Option<PathBuf>,
+
+	/// Force resources into a phase for debugging, FILE has one `<resource> on_start` or
+	/// `<resource> on_running` per line
+	#[arg(long, value_name = "FILE")]
+	registration_phase_override: Option<PathBuf>,
+
+	/// Fail if a mod overrides files of a critical base game archive, such as data_001.dat
+	#[arg(long)]
+	base_game_check: bool,
+
+	/// Mod archive that is meant to override base game files, exempt from --base-game-check
+	#[arg(long, value_name = "MOD_ZIP")]
+	allow_override: Vec<String>,
+
+	/// Write the resources each mod registered to PATH, sorted for diffing between builds
+	#[arg(long, value_name = "PATH")]
+	inventory: Option<PathBuf>,
+
+	/// Mod id MSU is installed under, used to check that it's present and loads before the preload
+	#[arg(long, value_name = "ID", default_value = patcher_preload::DEFAULT_MSU_ID)]
+	msu_id: String,
+
+	/// Scan the mods and print what the preload would contain, without writing it
+	#[arg(long, conflicts_with = "analyze")]
+	dry_run: bool,
+
+	/// Embed which mod registers each resource in the preload, for in-game diagnostics (makes
+	/// the preload larger)
+	#[arg(long)]
+	tag_sources: bool,
+
+	/// Write a report of the mods, resources and findings of the build to PATH, as JSON if it
+	/// ends in .json
+	#[arg(long, value_name = "PATH")]
+	report: Option<PathBuf>,
+
+	/// Note installed mods listed in FILE, one `<mod_id> <reason>` per line, on top of the
+	/// built-in list of known-bad mods. With --strict they are errors instead
+	#[arg(long, value_name = "FILE")]
+	denylist: Option<PathBuf>,
+
+	/// Order of each mod's resources in the preload: sorted by name, or grouped by mod in the
+	/// order the mod's manifests list them
+	#[arg(long, value_enum, value_name = "ORDER", default_value_t = IntraModSort::Name)]
+	intra_mod_sort: IntraModSort,
+
+	/// Abort without scanning if there are more than N mod archives, e.g. because the game path
+	/// points at a downloads folder
+	#[arg(long, value_name = "N")]
+	max_mods: Option<usize>,
+
+	/// With --merge, only scan the mods modified after TIMESTAMP (Unix seconds, or
+	/// YYYY-MM-DD[THH:MM[:SS]] in UTC) and keep the resources of the others from the merged
+	/// preload. Falls back to a full scan if that preload can't be read.
+	#[arg(long, value_name = "TIMESTAMP", requires = "merge", value_parser = parse_timestamp)]
+	since: Option<SystemTime>,
+
+	/// Time the scan of each archive and report the slowest ones, the largest resources and the
+	/// total scan time
+	#[arg(long)]
+	profile_resources: bool,
+}
+
+/// Unix seconds, or an ISO 8601 date with an optional time, in UTC
+fn parse_timestamp(value: &str) -> Result<SystemTime, String> {
+	if let Ok(secs) = value.parse::<u64>() {
+		return Ok(UNIX_EPOCH + Duration::from_secs(secs));
+	}
+	let invalid = || {
+		format!(
+			"expected Unix seconds or YYYY-MM-DD[THH:MM[:SS]], got {:?}",
+			value
+		)
+	};
+	let value = value.trim_end_matches('Z');
+	let (date, time) = value.split_once(['T', ' ']).unwrap_or((value, "00:00"));
+	let numbers = |s: &str, sep: char| -> Result<Vec<i64>, String> {
+		s.split(sep)
+			.map(|n| n.parse().map_err(|_| invalid()))
+			.collect()
+	};
+	let (date, time) = (numbers(date, '-')?, numbers(time, ':')?);
+	let (&[year, month, day], &[hour, minute, ref second @ ..]) = (&date[..], &time[..]) else {
+		return Err(invalid());
+	};
+	let second = match second {
+		[] => 0,
+		[second] => *second,
+		_ => return Err(invalid()),
+	};
+	if !(1..=12).contains(&month)
+		|| !(1..=31).contains(&day)
+		|| hour > 23
+		|| minute > 59
+		|| second > 60
+	{
+		return Err(invalid());
+	}
+	// Days since 1970-01-01 of a civil date, see http://howardhinnant.github.io/date_algorithms.html
+	let year = if month <= 2 { year - 1 } else { year };
+	let era = year.div_euclid(400);
+	let year_of_era = year - era * 400;
+	let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+	let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+	let days = era * 146097 + day_of_era - 719468;
+	let secs = days * 86400 + hour * 3600 + minute * 60 + second;
+	u64::try_from(secs)
+		.map(|secs| UNIX_EPOCH + Duration::from_secs(secs))
+		.map_err(|_| invalid())
+}
+
+impl PreloadArgs {
+	fn options(self) -> PreloadOptions {
+		PreloadOptions {
+			keep_going: self.keep_going,
+			description: self.description,
+			preserve_timestamps: self.preserve_timestamps,
+			verify_resources: self.verify_resources,
+			strict: self.strict,
+			output: self.output,
+			flatten: self.flatten,
+			merge: self.merge,
+			prune: self.prune,
+			stats: self.stats,
+			extract_to: self.extract_to,
+			phase_override: self.registration_phase_override,
+			base_game_check: self.base_game_check,
+			allow_override: self.allow_override,
+			inventory: self.inventory,
+			msu_id: Some(self.msu_id),
+			dry_run: self.dry_run,
+			report: self.report,
+			tag_sources: self.tag_sources,
+			denylist: self.denylist,
+			intra_mod_sort: self.intra_mod_sort,
+			max_mods: self.max_mods,
+			since: self.since,
+			profile_resources: self.profile_resources,
+		}
+	}
+}
+
+#[derive(Args)]
+struct RunningGameArgs {
+	/// Refuse to patch while the game is running
+	#[arg(long)]
+	no_patch_if_running: bool,
+
+	/// While the game is running, wait up to SECONDS for it to close before patching
+	#[arg(long, value_name = "SECONDS")]
+	wait: Option<u64>,
+}
+
+impl RunningGameArgs {
+	/// Returns once the exe can be patched, or errors if the game is running and either waiting
+	/// wasn't requested or it didn't close in time
+	fn wait_until_closed(&self, exe_path: &ExePath) -> Result<()> {
+		if !self.no_patch_if_running && self.wait.is_none() {
+			return Ok(());
+		}
+		let is_busy = || -> Result<bool> {
+			Ok(process::is_game_running()? || process::is_exe_locked(exe_path.as_ref()))
+		};
+		if !is_busy()? {
+			return Ok(());
+		}
+		let Some(wait) = self.wait else {
+			return Err(anyhow!(
+				"Battle Brothers is running, close it before patching or pass --wait <SECONDS>"
+			));
+		};
+		println!("Waiting for BattleBrothers.exe to close...");
+		let deadline = Instant::now() + Duration::from_secs(wait);
+		while Instant::now() < deadline {
+			std::thread::sleep(WAIT_POLL_INTERVAL.min(deadline - Instant::now()));
+			if !is_busy()? {
+				return Ok(());
+			}
+		}
+		Err(anyhow!(
+			"BattleBrothers.exe was still running after {} seconds, nothing was patched",
+			wait
+		))
+	}
 }
 
+const WAIT_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Copy-pasteable invocations for common workflows, printed by `bb-patcher examples`
+const EXAMPLES: &[(&str, &[&str])] = &[
+	(
+		"First-time setup of the Steam version on Linux (run Steamless on the exe first)",
+		&[
+			"bb-patcher set-path ~/.steam/steam/steamapps/common/Battle\\ Brothers",
+			"bb-patcher all",
+		],
+	),
+	(
+		"First-time setup of the Steam version on Windows (run Steamless on the exe first)",
+		&[
+			"bb-patcher.exe set-path \"C:\\Program Files (x86)\\Steam\\steamapps\\common\\Battle Brothers\"",
+			"bb-patcher.exe all",
+		],
+	),
+	(
+		"One-shot patch of the GOG version under WINE",
+		&["bb-patcher all --path ~/.wine/drive_c/GOG\\ Games/Battle\\ Brothers"],
+	),
+	(
+		"One-shot patch of the GOG version on Windows",
+		&["bb-patcher.exe all --path \"C:\\GOG Games\\Battle Brothers\""],
+	),
+	(
+		"Rebuild the preload after adding or removing mods",
+		&["bb-patcher preload"],
+	),
+	(
+		"Re-apply the patch after a Steam update reverted the exe",
+		&["bb-patcher repair"],
+	),
+	(
+		"Restore the unpatched exe from the backup made when patching",
+		&["bb-patcher list-backups", "bb-patcher restore"],
+	),
+	(
+		"Patch, keeping the backup compressed to save space",
+		&["bb-patcher patch4gb --backup-compress"],
+	),
+];
+
+const PATCH4GB_EXAMPLES: &str = "Examples:
+  bb-patcher patch4gb
+  bb-patcher patch4gb --dry-run-diff
+  bb-patcher patch4gb --path ~/.steam/steam/steamapps/common/Battle\\ Brothers
+  bb-patcher.exe patch4gb --path \"C:\\GOG Games\\Battle Brothers\"";
+
+const PRELOAD_EXAMPLES: &str = "Examples:
+  bb-patcher preload
+  bb-patcher preload --verify-resources --strict
+  bb-patcher preload --analyze --strict
+  bb-patcher preload --description \"My modpack\"";
+
+const ALL_EXAMPLES: &str = "Examples:
+  bb-patcher all
+  bb-patcher all --path ~/.wine/drive_c/GOG\\ Games/Battle\\ Brothers";
+
+const DETECT_EXAMPLES: &str = "Examples:
+  bb-patcher detect
+  bb-patcher detect --explain";
+
+const SET_PATH_EXAMPLES: &str = "Examples:
+  bb-patcher set-path ~/.steam/steam/steamapps/common/Battle\\ Brothers
+  bb-patcher.exe set-path \"C:\\GOG Games\\Battle Brothers\\win32\\BattleBrothers.exe\"";
+
 #[derive(Subcommand)]
 enum Commands {
 	/// Apply the 4GB (LAA) patch to BattleBrothers.exe
 	///
 	/// This patch allows the game to use up to 4GB of RAM instead of 2GB,
 	/// which is necessary for running heavy mod lists without crashes.
+	#[command(after_help = PATCH4GB_EXAMPLES)]
 	Patch4gb {
-		/// Path to BattleBrothers.exe or the game directory
-		#[arg(short, long)]
-		path: Option<PathBuf>,
+		#[command(flatten)]
+		path: GamePathArgs,
 
-		/// Skip Steam DRM removal check (patch may not work correctly)
+		#[command(flatten)]
+		patch: PatchArgs,
+
+		#[command(flatten)]
+		running: RunningGameArgs,
+
+		/// Print the bytes the patch would change, with their offsets and old and new values,
+		/// without modifying the exe. Fails like the patch would for an exe it refuses to patch
+		#[arg(long)]
+		dry_run_diff: bool,
+
+		/// Write the SHA-256 of the patched exe to FILE, also when it was already patched
+		#[arg(long, value_name = "FILE", conflicts_with = "dry_run_diff")]
+		output_hash: Option<PathBuf>,
+
+		/// Exit with 0 instead of 6 when the exe was already patched, for scripts that only
+		/// need it to end up patched
 		#[arg(long)]
-		skip_steam_drm: bool,
+		no_op_ok: bool,
 	},
 
 	/// Create the mod preload file (~mod_msu_launcher.zip)
 	///
 	/// Scans all mods in the data folder and creates a preload manifest
 	/// that registers mod resources with the game's mod system.
+	#[command(after_help = PRELOAD_EXAMPLES)]
 	Preload {
-		/// Path to BattleBrothers.exe or the game directory
-		#[arg(short, long)]
-		path: Option<PathBuf>,
+		#[command(flatten)]
+		path: GamePathArgs,
+
+		#[command(flatten)]
+		build: Box<PreloadArgs>,
+
+		/// Run every check over the mods and report the findings with an overall verdict, without
+		/// creating the preload. With --strict, exits with 4 on warnings and 5 on errors.
+		#[arg(long, conflicts_with_all = ["output", "extract_to", "disable"])]
+		analyze: bool,
+
+		/// Only count the mods and estimate their resources from their manifests, without
+		/// running any checks or creating the preload
+		#[arg(long, conflicts_with_all = ["analyze", "dry_run", "output", "extract_to", "disable"])]
+		count_only: bool,
+
+		/// Turn preload generation back on in the config, then build the preload
+		#[arg(long, conflicts_with = "disable")]
+		enable: bool,
+
+		/// Turn preload generation off in the config and remove the existing preload
+		#[arg(long)]
+		disable: bool,
 	},
 
 	/// Run both 4GB patch and preload creation
+	#[command(after_help = ALL_EXAMPLES)]
 	All {
-		/// Path to BattleBrothers.exe or the game directory
-		#[arg(short, long)]
-		path: Option<PathBuf>,
+		#[command(flatten)]
+		path: GamePathArgs,
+
+		#[command(flatten)]
+		patch: PatchArgs,
 
-		/// Skip Steam DRM removal check (patch may not work correctly)
+		#[command(flatten)]
+		running: RunningGameArgs,
+
+		/// Continue past mods that fail to scan and report them at the end
+		#[arg(long)]
+		keep_going: bool,
+
+		/// Description shown for the generated preload mod
+		#[arg(long, value_name = "TEXT")]
+		description: Option<String>,
+
+		/// Keep the source manifests' timestamps instead of fixed ones (breaks reproducibility)
 		#[arg(long)]
-		skip_steam_drm: bool,
+		preserve_timestamps: bool,
 	},
 
 	/// Detect the game version without making changes
+	#[command(after_help = DETECT_EXAMPLES)]
 	Detect {
-		/// Path to BattleBrothers.exe or the game directory
-		#[arg(short, long)]
-		path: Option<PathBuf>,
+		#[command(flatten)]
+		path: GamePathArgs,
+
+		/// Describe each check that led to the detected version
+		#[arg(long)]
+		explain: bool,
+
+		/// Reuse the exe's hash cached by an earlier run if the exe didn't change, and otherwise
+		/// cache it in bb-patcher-hash-cache.json next to the config file
+		#[arg(long, overrides_with = "no_hash_cache")]
+		hash_cache: bool,
+
+		/// Hash the exe without reading or writing the hash cache, overriding --hash-cache
+		#[arg(long, overrides_with = "hash_cache")]
+		no_hash_cache: bool,
+
+		/// TOML file mapping exe hashes to game versions (`<SHA256> = "1.5.1.12"`), used on
+		/// top of the built-in ones
+		#[arg(long, value_name = "FILE")]
+		game_version_db: Option<PathBuf>,
+
+		/// If the exe is an unknown version, open a GitHub issue pre-filled with its hash and
+		/// size in the browser, or print its URL if no browser can be opened. Nothing is
+		/// submitted without you doing so on GitHub.
+		#[arg(long)]
+		report_unknown_hash: bool,
+
+		/// Expert: read the PE header at OFFSET (decimal or 0x hex) instead of locating it
+		/// through the DOS header, as for an exe patched with patch4gb --pe-offset
+		#[arg(long, value_name = "OFFSET", value_parser = parse_offset)]
+		pe_offset: Option<u64>,
 	},
 
 	/// Check if the game is already patched with LAA
+	///
+	/// Also checks whether the exe changed since it was last patched (or since the
+	/// hash given with --since-hash), exiting with code 3 if it did.
 	Check {
-		/// Path to BattleBrothers.exe or the game directory
+		#[command(flatten)]
+		path: GamePathArgs,
+
+		/// SHA-256 to compare the exe against instead of the hash recorded when it was last patched
+		#[arg(long, value_name = "HEX")]
+		since_hash: Option<String>,
+
+		/// Reuse the exe's hash cached by an earlier run if the exe didn't change, and otherwise
+		/// cache it in bb-patcher-hash-cache.json next to the config file
+		#[arg(long, overrides_with = "no_hash_cache")]
+		hash_cache: bool,
+
+		/// Hash the exe without reading or writing the hash cache, overriding --hash-cache
+		#[arg(long, overrides_with = "hash_cache")]
+		no_hash_cache: bool,
+
+		/// Expert: read the PE header at OFFSET (decimal or 0x hex) instead of locating it
+		/// through the DOS header, as for an exe patched with patch4gb --pe-offset
+		#[arg(long, value_name = "OFFSET", value_parser = parse_offset)]
+		pe_offset: Option<u64>,
+	},
+
+	/// Report everything that can be learned about the install without changing anything
+	///
+	/// Never writes any file, not even the config. Prints JSON with --json, for GUIs and scripts.
+	Probe {
+		#[command(flatten)]
+		path: GamePathArgs,
+	},
+
+	/// Check that the install is in a good state for playing modded, with a verdict for each part
+	///
+	/// Checks that the exe is 4GB patched and a known version, the data folder, that MSU is
+	/// installed, that the mods scan cleanly and that the preload is up to date with them.
+	/// Exits with 8 if a check fails. Prints JSON with --json.
+	///
+	/// A 4GB patched exe that is neither a known version nor patched by bb-patcher is only a
+	/// warning: it was most likely patched by another tool and plays fine.
+	VerifyInstall {
+		#[command(flatten)]
+		path: GamePathArgs,
+
+		/// Mod id MSU is installed under
+		#[arg(long, value_name = "ID", default_value = patcher_preload::DEFAULT_MSU_ID)]
+		msu_id: String,
+	},
+
+	/// Re-apply the 4GB patch after Steam reverted the exe
+	///
+	/// Only does something if the exe is unpatched, the config records it being patched
+	/// before and a valid backup exists. Also refreshes the preload if it is stale.
+	Repair {
+		#[command(flatten)]
+		path: GamePathArgs,
+
+		#[command(flatten)]
+		patch: PatchArgs,
+
+		#[command(flatten)]
+		running: RunningGameArgs,
+
+		/// Don't ask for confirmation
+		#[arg(short, long)]
+		yes: bool,
+	},
+
+	/// Restore the unpatched exe from a backup made when patching
+	Restore {
+		#[command(flatten)]
+		path: GamePathArgs,
+
+		/// Backup to restore, needed when there is more than one valid backup
+		#[arg(long, value_name = "FILE")]
+		backup: Option<PathBuf>,
+
+		/// Don't ask for confirmation
+		#[arg(short, long)]
+		yes: bool,
+	},
+
+	/// Undo everything the tool did to the install
+	///
+	/// Restores the exe from its oldest clean backup (or its oldest unpatched one, for exes
+	/// patched with force_unknown_as), deletes all backups and the generated preload, and
+	/// optionally the config file. Without an unpatched backup, the backups are kept.
+	Uninstall {
+		#[command(flatten)]
+		path: GamePathArgs,
+
+		/// Also delete the config file and the hash cache next to it
+		#[arg(long)]
+		clear_config: bool,
+
+		/// Don't ask for confirmation
 		#[arg(short, long)]
-		path: Option<PathBuf>,
+		yes: bool,
+	},
+
+	/// List the backups of the exe, plain and compressed, with their versions
+	ListBackups {
+		#[command(flatten)]
+		path: GamePathArgs,
 	},
 
 	/// Set the game path in the config file
+	#[command(after_help = SET_PATH_EXAMPLES)]
 	SetPath {
 		/// Path to BattleBrothers.exe or the game directory
 		path: PathBuf,
 	},
 
 	/// Show current configuration
-	Config,
+	Config {
+		/// Rewrite the config file in the current format, reporting what changed
+		#[arg(long)]
+		migrate: bool,
+
+		/// Always patch this install's exe as KIND when it isn't a known version, e.g. because it
+		/// was modified. Forgotten when the game path changes, and not applied to another install
+		/// given with --assume-path.
+		#[arg(long, value_name = "KIND", conflicts_with = "clear_force_unknown_as")]
+		force_unknown_as: Option<ForcedVersion>,
+
+		/// Stop treating an unknown exe as another kind
+		#[arg(long)]
+		clear_force_unknown_as: bool,
+
+		/// Whether patching always requires a clean backup, as with --require-clean-backup
+		#[arg(long, value_name = "BOOL")]
+		require_clean_backup: Option<bool>,
+
+		/// How many backups of each kind patching keeps, as with --backup-retention; 0 keeps all
+		#[arg(long, value_name = "N")]
+		backup_retention: Option<usize>,
+	},
+
+	/// Print example invocations for common workflows
+	Examples,
+
+	/// Print the hash of BattleBrothers.exe, or of any other file
+	Hash {
+		#[command(flatten)]
+		path: GamePathArgs,
+
+		/// Hash this file instead of the game's exe
+		#[arg(long, value_name = "FILE")]
+		file: Option<PathBuf>,
+
+		/// Digest to compute
+		#[arg(long, value_enum, default_value_t)]
+		hash_algorithm: HashAlgorithm,
+	},
+
+	/// Write the parsed PE headers of an executable as JSON, for attaching to bug reports
+	DumpPe {
+		/// Executable to dump, defaults to the game's BattleBrothers.exe
+		#[arg(value_name = "EXE")]
+		exe: Option<PathBuf>,
+
+		#[command(flatten)]
+		path: GamePathArgs,
+
+		/// Write the JSON to this file instead of stdout
+		#[arg(short, long, value_name = "FILE")]
+		output: Option<PathBuf>,
+	},
+
+	/// Check the PE checksum of an executable, e.g. when a loader rejects it
+	///
+	/// Compares the checksum stored in the optional header to the one computed over the file.
+	Checksum {
+		/// Executable to check, defaults to the game's BattleBrothers.exe
+		#[arg(value_name = "EXE")]
+		exe: Option<PathBuf>,
+
+		#[command(flatten)]
+		path: GamePathArgs,
+
+		/// Write the computed checksum into the executable if the stored one is wrong, after
+		/// backing it up
+		#[arg(long)]
+		fix: bool,
+
+		#[command(flatten)]
+		running: RunningGameArgs,
+	},
+
+	/// Write a portable description of the install: the exe's version and hash, whether it's
+	/// patched, and the installed mods with their versions and hashes
+	ExportManifest {
+		#[command(flatten)]
+		path: GamePathArgs,
+
+		/// Write the manifest to this file instead of stdout
+		#[arg(short, long, value_name = "FILE")]
+		output: Option<PathBuf>,
+	},
+
+	/// Compare the install against a manifest written by export-manifest, e.g. by someone whose
+	/// setup works, listing missing, extra, mismatched and unscanned mods
+	ImportManifest {
+		#[command(flatten)]
+		path: GamePathArgs,
+
+		/// Manifest to compare against
+		#[arg(value_name = "FILE")]
+		manifest: PathBuf,
+	},
+
+	/// Compare the PE headers of two executables field by field
+	///
+	/// Useful to see what a third party patch changed, e.g. between a backup and the current exe.
+	Compare {
+		/// First executable
+		a: PathBuf,
+
+		/// Second executable
+		b: PathBuf,
+	},
+}
+
+impl Commands {
+	fn game_path_args(&self) -> Option<&GamePathArgs> {
+		match self {
+			Commands::Patch4gb { path, .. }
+			| Commands::Preload { path, .. }
+			| Commands::All { path, .. }
+			| Commands::Detect { path, .. }
+			| Commands::Check { path, .. }
+			| Commands::Probe { path }
+			| Commands::VerifyInstall { path, .. }
+			| Commands::Repair { path, .. }
+			| Commands::Restore { path, .. }
+			| Commands::Uninstall { path, .. }
+			| Commands::ListBackups { path }
+			| Commands::Hash { path, .. }
+			| Commands::DumpPe { path, .. }
+			| Commands::Checksum { path, .. }
+			| Commands::ExportManifest { path, .. }
+			| Commands::ImportManifest { path, .. } => Some(path),
+			Commands::SetPath { .. }
+			| Commands::Config { .. }
+			| Commands::Examples
+			| Commands::Compare { .. } => None,
+		}
+	}
+
+	fn patch_args(&self) -> Option<&PatchArgs> {
+		match self {
+			Commands::Patch4gb { patch, .. }
+			| Commands::All { patch, .. }
+			| Commands::Repair { patch, .. } => Some(patch),
+			_ => None,
+		}
+	}
+
+	/// Fills in the settings from --env-file that weren't given as flags
+	fn apply_env_file(&mut self, env_file: &EnvFile) {
+		match self {
+			Commands::Patch4gb { path, .. }
+			| Commands::Preload { path, .. }
+			| Commands::All { path, .. }
+			| Commands::Detect { path, .. }
+			| Commands::Check { path, .. }
+			| Commands::Probe { path }
+			| Commands::VerifyInstall { path, .. }
+			| Commands::Repair { path, .. }
+			| Commands::Restore { path, .. }
+			| Commands::Uninstall { path, .. }
+			| Commands::ListBackups { path }
+			| Commands::Hash { path, .. }
+			| Commands::DumpPe { path, .. }
+			| Commands::Checksum { path, .. }
+			| Commands::ExportManifest { path, .. }
+			| Commands::ImportManifest { path, .. } => {
+				path.env_game_path = env_file.game_path.clone();
+			}
+			Commands::SetPath { .. }
+			| Commands::Config { .. }
+			| Commands::Examples
+			| Commands::Compare { .. } => {}
+		}
+		if let Commands::Patch4gb { patch, .. }
+		| Commands::All { patch, .. }
+		| Commands::Repair { patch, .. } = self
+		{
+			patch.skip_steam_drm |= env_file.skip_steam_drm.unwrap_or_default();
+			patch.backup_compress |= env_file.backup_compress.unwrap_or_default();
+			patch.strict_backup_verify |= env_file.strict_backup_verify.unwrap_or_default();
+			patch.backup_retention = patch.backup_retention.or(env_file.backup_retention);
+			patch.env_require_clean_backup = env_file.require_clean_backup;
+		}
+	}
 }
 
-fn resolve_game_path(path: Option<PathBuf>) -> Result<Config> {
+/// The config with the game path chosen by `GamePathArgs::choose`
+fn resolve_game_path(path: GamePathArgs) -> Result<Config> {
 	let mut config = Config::load_or_default();
 
-	if let Some(p) = path {
+	match path.choose(&config)?.0 {
 		// User provided a path - validate and use it
-		if p.is_file()
-			&& p.file_name()
-				.map(|f| f == "BattleBrothers.exe")
-				.unwrap_or(false)
-		{
-			config.set_path_from_exe(&p)?;
-		} else if p.is_dir() {
-			config.set_path(&p)?;
-		} else {
-			return Err(anyhow!(
-				"Invalid path: {:?}\nExpected path to BattleBrothers.exe or the game directory",
-				p
-			));
+		GamePathChoice::Given(p) => {
+			apply_game_path(&mut config, &p)?;
 		}
+		GamePathChoice::Assumed(p) => config.assumed_path = Some(p),
+		GamePathChoice::Saved => {}
 	}
 
-	if config.bb_path.is_none() {
+	if config.game_path().is_none() {
 		return Err(anyhow!(
-			"Game path not found. Please specify with --path or run 'bb-patcher set-path <PATH>'\n\
+			"Game path not found. Please specify with --path or --path-file, or run 'bb-patcher set-path <PATH>'\n\
             Example: bb-patcher patch4gb --path /path/to/Battle\\ Brothers"
 		));
 	}
@@ -118,80 +966,368 @@ fn resolve_game_path(path: Option<PathBuf>) -> Result<Config> {
 	Ok(config)
 }
 
-fn cmd_patch4gb(path: Option<PathBuf>, skip_steam_drm: bool) -> Result<()> {
-	let config = resolve_game_path(path)?;
+/// Returns the recorded hash, hex encoded
+fn record_patched_hash(config: &mut Config, exe_path: &ExePath) -> Result<String> {
+	let hash = const_hex::encode(sha_hash_path(exe_path.as_ref())?);
+	config.set_last_patched_hash(hash.clone())?;
+	Ok(hash)
+}
+
+fn cmd_patch4gb(
+	path: GamePathArgs,
+	patch: PatchArgs,
+	running: RunningGameArgs,
+	dry_run_diff: bool,
+	output_hash: Option<PathBuf>,
+	no_op_ok: bool,
+) -> Result<ExitCode> {
+	let mut config = resolve_game_path(path)?;
 
 	let exe_path = config
 		.get_bb_exe_path()
 		.context("Could not find BattleBrothers.exe")?;
+	if dry_run_diff {
+		patcher_laa::check_patchable(exe_path.as_ref(), &patch.options(&config))?;
+		return print_laa_diff(exe_path.as_ref(), patch.pe_offset);
+	}
+	running.wait_until_closed(&exe_path)?;
 
 	println!("Applying 4GB (LAA) patch to: {:?}", exe_path.as_ref());
 
-	let result = patch_exe(exe_path.as_ref(), skip_steam_drm)?;
+	let result = patch_exe(exe_path.as_ref(), &patch.options(&config))?;
 	println!("  {}", result);
+	let hash = record_patched_hash(&mut config, &exe_path)?;
+	if let Some(output_hash) = output_hash {
+		std::fs::write(&output_hash, hash + "\n")
+			.with_context(|| format!("Couldn't write the hash to {:?}", output_hash))?;
+	}
 
-	Ok(())
-}
-
-fn cmd_preload(path: Option<PathBuf>) -> Result<()> {
-	let config = resolve_game_path(path)?;
-
-	let data_path = config
-		.get_bb_data_path()
-		.context("Could not find data folder")?;
+	if result.already_patched && !no_op_ok {
+		return Ok(ExitCode::AlreadyPatched);
+	}
 
-	println!("Creating mod preload from: {:?}", data_path.as_ref());
+	Ok(ExitCode::Success)
+}
 
-	let resources = gather_and_create_mod(&data_path)?;
+fn print_laa_diff(exe_path: &Path, pe_offset: Option<u64>) -> Result<ExitCode> {
+	let changes = patcher_laa::laa_diff(exe_path, pe_offset)?;
 	println!(
-		"  Created ~mod_msu_launcher.zip with {} on_start and {} on_running resources",
-		resources.on_start_count(),
-		resources.on_running_count()
+		"Dry run, bytes the 4GB patch would change in {:?}:",
+		exe_path
 	);
-
-	Ok(())
-}
-
-fn cmd_all(path: Option<PathBuf>, skip_steam_drm: bool) -> Result<()> {
-	let config = resolve_game_path(path)?;
-
-	// 4GB Patch
-	if let Some(exe_path) = config.get_bb_exe_path() {
-		println!("Applying 4GB (LAA) patch to: {:?}", exe_path.as_ref());
-		match patch_exe(exe_path.as_ref(), skip_steam_drm) {
-			Ok(result) => println!("  {}", result),
-			Err(e) => println!("  Warning: {}", e),
-		}
+	if changes.is_empty() {
+		println!("  None, the exe is already large address aware");
 	} else {
-		println!("Warning: Could not find BattleBrothers.exe, skipping 4GB patch");
+		println!("  {:<10}  {:<4}  {:<4}  Field", "Offset", "Old", "New");
 	}
-
-	// Preload
-	if let Some(data_path) = config.get_bb_data_path() {
-		println!("\nCreating mod preload from: {:?}", data_path.as_ref());
-		let resources = gather_and_create_mod(&data_path)?;
+	for change in changes {
 		println!(
-			"  Created ~mod_msu_launcher.zip with {} on_start and {} on_running resources",
-			resources.on_start_count(),
-			resources.on_running_count()
+			"  {:#010x}  {:02X}    {:02X}    {}",
+			change.offset, change.old, change.new, change.field
 		);
-	} else {
-		return Err(anyhow!("Could not find data folder"));
 	}
-
-	Ok(())
+	Ok(ExitCode::Success)
 }
 
-fn cmd_detect(path: Option<PathBuf>) -> Result<()> {
+/// How many of the slowest archives and largest resources --profile-resources lists
+const PROFILE_TOP_COUNT: usize = 10;
+
+fn print_scan_profile(out: &mut impl Write, profile: &ScanProfile) -> Result<()> {
+	let mut archives: Vec<_> = profile.archives.iter().collect();
+	archives.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+	let mut resources: Vec<_> = profile.resources.iter().collect();
+	resources.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+	writeln!(
+		out,
+		"  Scanned {} archive(s) in {:.1} ms",
+		archives.len(),
+		profile.total.as_secs_f64() * 1000.0
+	)?;
+	writeln!(out, "  Slowest archives:")?;
+	for (name, time) in archives.iter().take(PROFILE_TOP_COUNT) {
+		writeln!(out, "    {:>9.1} ms  {}", time.as_secs_f64() * 1000.0, name)?;
+	}
+	writeln!(out, "  Largest resources:")?;
+	for (name, size, archive) in resources.iter().take(PROFILE_TOP_COUNT) {
+		writeln!(out, "    {:>12} bytes  {} ({})", size, name, archive)?;
+	}
+	Ok(())
+}
+
+fn print_preload_summary(
+	out: &mut impl Write,
+	resources: &ResourceHandler,
+	options: &PreloadOptions,
+	verbose: bool,
+) -> Result<()> {
+	if resources.error_count() > 0 {
+		for finding in resources.findings() {
+			writeln!(out, "  {}", finding)?;
+		}
+		return Err(anyhow!(
+			"Preload was not created because of {} error(s)",
+			resources.error_count()
+		));
+	}
+	writeln!(
+		out,
+		"  {} preload to {} with {} on_start and {} on_running resources",
+		if options.dry_run {
+			"Dry run, would write"
+		} else {
+			"Wrote"
+		},
+		options.output_name(),
+		resources.on_start_count(),
+		resources.on_running_count()
+	)?;
+	writeln!(out, "  Load order: {}", resources.load_order_position())?;
+	if verbose {
+		for (name, target) in resources.symlinks() {
+			writeln!(out, "  Symlinked mod: {} -> {:?}", name, target)?;
+		}
+	}
+	for finding in resources.findings() {
+		writeln!(out, "  {}", finding)?;
+	}
+	if !resources.failures().is_empty() {
+		writeln!(
+			out,
+			"  Failed to scan {} archive(s):",
+			resources.failures().len()
+		)?;
+		for (name, reason) in resources.failures() {
+			writeln!(out, "    {}: {}", name, reason)?;
+		}
+		return Err(anyhow!(
+			"{} archive(s) failed to scan, their resources are missing from the preload",
+			resources.failures().len()
+		));
+	}
+	Ok(())
+}
+
+const PRELOAD_DISABLED_NOTE: &str =
+	"Preload generation is disabled in the config, run 'bb-patcher preload --enable' to turn it back on";
+
+fn print_analysis(analysis: &Analysis, json: bool) -> Result<()> {
+	if json {
+		println!(
+			"{}",
+			serde_json::to_string_pretty(analysis).context("Couldn't serialize the analysis")?
+		);
+		return Ok(());
+	}
+	println!("  Analyzed {} mod(s)", analysis.mod_count);
+	for finding in analysis.findings.iter() {
+		println!("  {}", finding);
+	}
+	println!(
+		"  Verdict: {} ({} error(s), {} warning(s), {} note(s))",
+		analysis.verdict, analysis.error_count, analysis.warning_count, analysis.info_count
+	);
+	Ok(())
+}
+
+/// Scans the mods like a regular build, with every optional check turned on and without
+/// stopping at broken archives, but only reports the findings
+fn cmd_analyze(data_path: &DataPath, options: PreloadOptions, json: bool) -> Result<ExitCode> {
+	let strict = options.strict;
+	let options = PreloadOptions {
+		keep_going: true,
+		verify_resources: true,
+		// Severities are kept as found, --strict only decides the exit code
+		strict: false,
+		..options
+	};
+	if !json {
+		println!("Analyzing mods in: {:?}", data_path.as_ref());
+	}
+	let analysis = patcher_preload::get_resource_handler(data_path, &options)?.analyze();
+	print_analysis(&analysis, json)?;
+	Ok(match analysis.verdict {
+		_ if !strict => ExitCode::Success,
+		Verdict::Pass => ExitCode::Success,
+		Verdict::Warn => ExitCode::AnalysisWarnings,
+		Verdict::Fail => ExitCode::AnalysisErrors,
+	})
+}
+
+fn cmd_count_mods(data_path: &DataPath, json: bool) -> Result<ExitCode> {
+	let count = patcher_preload::count_mods(data_path)?;
+	if json {
+		println!(
+			"{}",
+			serde_json::to_string_pretty(&count).context("Couldn't serialize the mod count")?
+		);
+		return Ok(ExitCode::Success);
+	}
+	println!("Counting mods in: {:?}", data_path.as_ref());
+	println!(
+		"  {} mod(s) registering about {} resource(s) (estimated from their manifests, \
+		resources registered by several mods are counted once per mod)",
+		count.mods, count.estimated_resources
+	);
+	if !count.unreadable.is_empty() {
+		println!(
+			"  {} archive(s) couldn't be read as zips: {}",
+			count.unreadable.len(),
+			count.unreadable.join(", ")
+		);
+	}
+	Ok(ExitCode::Success)
+}
+
+fn cmd_preload(
+	path: GamePathArgs,
+	options: PreloadOptions,
+	analyze: bool,
+	count_only: bool,
+	enable: bool,
+	disable: bool,
+	output_options: &OutputOptions,
+) -> Result<ExitCode> {
+	let mut config = resolve_game_path(path)?;
+
+	let data_path = config
+		.get_bb_data_path()
+		.context("Could not find data folder")?;
+
+	// Keep stdout clean for the zip itself when streaming it
+	let mut out: Box<dyn Write> = match options.writes_to_stdout() {
+		true => Box::new(std::io::stderr()),
+		false => Box::new(std::io::stdout()),
+	};
+	if disable {
+		config.set_preload_enabled(false)?;
+		writeln!(out, "Disabled preload generation")?;
+		if patcher_preload::remove_mod(&data_path)? {
+			writeln!(
+				out,
+				"  Removed ~mod_msu_launcher.zip from: {:?}",
+				data_path.as_ref()
+			)?;
+		}
+		return Ok(ExitCode::Success);
+	}
+	if enable {
+		config.set_preload_enabled(true)?;
+		writeln!(out, "Enabled preload generation")?;
+	}
+	if analyze {
+		return cmd_analyze(&data_path, options, output_options.json);
+	}
+	if count_only {
+		return cmd_count_mods(&data_path, output_options.json);
+	}
+	if !enable && !config.preload_enabled {
+		// Exiting with success would leave the reader of the stream with an empty zip
+		if options.writes_to_stdout() {
+			return Err(anyhow!("{}", PRELOAD_DISABLED_NOTE));
+		}
+		writeln!(out, "{}", PRELOAD_DISABLED_NOTE)?;
+		return Ok(ExitCode::Success);
+	}
+
+	writeln!(out, "Creating mod preload from: {:?}", data_path.as_ref())?;
+
+	let resources = gather_and_create_mod(&data_path, &options)?;
+	if let Some(stats_path) = &options.stats {
+		let stats = serde_json::to_string_pretty(&resources.stats())?;
+		std::fs::write(stats_path, stats + "\n")
+			.with_context(|| format!("Couldn't write stats to {:?}", stats_path))?;
+	}
+	if let Some(inventory_path) = &options.inventory {
+		std::fs::write(inventory_path, resources.inventory())
+			.with_context(|| format!("Couldn't write inventory to {:?}", inventory_path))?;
+	}
+	if let Some(report_path) = &options.report {
+		let report = resources.report(&options);
+		let contents = match report_path.extension() {
+			Some(ext) if ext.eq_ignore_ascii_case("json") => {
+				serde_json::to_string_pretty(&report)? + "\n"
+			}
+			_ => report.to_text(),
+		};
+		std::fs::write(report_path, contents)
+			.with_context(|| format!("Couldn't write report to {:?}", report_path))?;
+	}
+	if let Some(profile) = resources.profile() {
+		print_scan_profile(&mut out, profile)?;
+	}
+	print_preload_summary(&mut out, &resources, &options, output_options.verbose)?;
+
+	Ok(ExitCode::Success)
+}
+
+fn cmd_all(
+	path: GamePathArgs,
+	patch: PatchArgs,
+	running: RunningGameArgs,
+	options: PreloadOptions,
+	verbose: bool,
+) -> Result<ExitCode> {
+	let mut config = resolve_game_path(path)?;
+
+	// 4GB Patch
+	if let Some(exe_path) = config.get_bb_exe_path() {
+		running.wait_until_closed(&exe_path)?;
+		println!("Applying 4GB (LAA) patch to: {:?}", exe_path.as_ref());
+		match patch_exe(exe_path.as_ref(), &patch.options(&config)) {
+			Ok(result) => {
+				println!("  {}", result);
+				record_patched_hash(&mut config, &exe_path)?;
+			}
+			Err(e) => println!("  Warning: {}", e),
+		}
+	} else {
+		println!("Warning: Could not find BattleBrothers.exe, skipping 4GB patch");
+	}
+
+	// Preload
+	if !config.preload_enabled {
+		println!("\n{}", PRELOAD_DISABLED_NOTE);
+	} else if let Some(data_path) = config.get_bb_data_path() {
+		println!("\nCreating mod preload from: {:?}", data_path.as_ref());
+		let resources = gather_and_create_mod(&data_path, &options)?;
+		print_preload_summary(&mut std::io::stdout(), &resources, &options, verbose)?;
+	} else {
+		return Err(anyhow!("Could not find data folder"));
+	}
+
+	Ok(ExitCode::Success)
+}
+
+fn cmd_detect(
+	path: GamePathArgs,
+	explain: bool,
+	hash_cache: bool,
+	game_version_db: Option<PathBuf>,
+	report_unknown_hash: bool,
+	pe_offset: Option<u64>,
+) -> Result<ExitCode> {
 	let config = resolve_game_path(path)?;
 
 	let exe_path = config
 		.get_bb_exe_path()
 		.context("Could not find BattleBrothers.exe")?;
+	let game_version_db = match game_version_db {
+		Some(db_path) => GameVersionDb::with_file(&db_path)?,
+		None => GameVersionDb::embedded(),
+	};
 
 	println!("Detecting version of: {:?}", exe_path.as_ref());
+	patcher_laa::warn_forced_pe_offset(pe_offset);
 
-	let version = detect_version(exe_path.as_ref())?;
+	let hash = hash_cache::sha_hash_path_cached(exe_path.as_ref(), hash_cache)?;
+	let (version, trace) =
+		patcher_laa::detect_version_from_hash(exe_path.as_ref(), &hash, pe_offset)?;
+	if explain {
+		println!("  Explanation:");
+		for step in trace.iter() {
+			println!("    {}", step);
+		}
+	}
 	match version {
 		GameVersion::Steam => {
 			println!("  Version: Steam (has DRM)");
@@ -211,14 +1347,37 @@ fn cmd_detect(path: Option<PathBuf>) -> Result<()> {
 		}
 		GameVersion::Unknown => {
 			println!("  Version: Unknown");
-			println!("  This may be a new game version. Please report on GitHub.");
+			println!("  This may be a new game version.");
+			for line in patcher_laa::unknown_version_report(exe_path.as_ref(), &hash)?.lines() {
+				println!("  {}", line);
+			}
+			if report_unknown_hash {
+				let url = patcher_laa::unknown_version_issue_url(exe_path.as_ref(), &hash)?;
+				match process::open_in_browser(&url) {
+					Ok(()) => println!(
+						"  Opened a pre-filled GitHub issue in your browser, review and submit it there"
+					),
+					Err(e) => println!(
+						"  Couldn't open a browser ({:#}), open this URL to report it:\n  {}",
+						e, url
+					),
+				}
+			}
 		}
 	}
+	if let Some(game_version) = game_version_db.lookup(&hash) {
+		println!("  Game version: {}", game_version);
+	}
 
-	Ok(())
+	Ok(ExitCode::Success)
 }
 
-fn cmd_check(path: Option<PathBuf>) -> Result<()> {
+fn cmd_check(
+	path: GamePathArgs,
+	since_hash: Option<String>,
+	hash_cache: bool,
+	pe_offset: Option<u64>,
+) -> Result<ExitCode> {
 	let config = resolve_game_path(path)?;
 
 	let exe_path = config
@@ -226,43 +1385,810 @@ fn cmd_check(path: Option<PathBuf>) -> Result<()> {
 		.context("Could not find BattleBrothers.exe")?;
 
 	println!("Checking LAA status of: {:?}", exe_path.as_ref());
+	patcher_laa::warn_forced_pe_offset(pe_offset);
 
-	let is_patched = patcher_laa::is_laa(exe_path.as_ref())?;
+	let is_patched = patcher_laa::is_laa(exe_path.as_ref(), pe_offset)?;
 	if is_patched {
 		println!("  Status: PATCHED (Large Address Aware flag is set)");
 	} else {
 		println!("  Status: NOT PATCHED (needs 4GB patch)");
 	}
 
-	Ok(())
+	let (expected_hash, source) = match since_hash {
+		Some(hash) => (hash, "the given hash"),
+		None => match config.last_patched_hash.clone() {
+			Some(hash) => (hash, "the hash recorded when it was last patched"),
+			None => return Ok(ExitCode::Success),
+		},
+	};
+	let expected_hash = const_hex::decode(expected_hash.trim())
+		.with_context(|| format!("Invalid hash {:?}", expected_hash))?;
+	if hash_cache::sha_hash_path_cached(exe_path.as_ref(), hash_cache)? == expected_hash {
+		println!("  Hash: unchanged since {}", source);
+	} else {
+		println!("  Hash: CHANGED since {}", source);
+		return Ok(ExitCode::HashChanged);
+	}
+
+	Ok(ExitCode::Success)
+}
+
+#[derive(Serialize)]
+struct ExeProbe {
+	version: String,
+	sha256: String,
+	size: u64,
+	large_address_aware: bool,
+	characteristics: Vec<&'static str>,
+}
+
+#[derive(Serialize)]
+struct BackupProbe {
+	path: PathBuf,
+	version: String,
+	compressed: bool,
+}
+
+#[derive(Serialize)]
+struct PreloadProbe {
+	path: PathBuf,
+	version: Option<String>,
+	on_running_count: usize,
+	on_start_count: usize,
+}
+
+#[derive(Serialize, Default)]
+struct Probe {
+	game_path: Option<PathBuf>,
+	exe_path: Option<PathBuf>,
+	data_path: Option<PathBuf>,
+	exe: Option<ExeProbe>,
+	backups: Vec<BackupProbe>,
+	preload: Option<PreloadProbe>,
+	game_running: Option<bool>,
+	/// Why parts of the probe are missing
+	errors: Vec<String>,
+}
+
+fn probe_exe(exe_path: &Path) -> Result<ExeProbe> {
+	let (_, file_header) = patcher_laa::read_pe_headers(exe_path)?;
+	Ok(ExeProbe {
+		version: patcher_laa::detect_version(exe_path)?.to_string(),
+		sha256: const_hex::encode(sha_hash_path(exe_path)?),
+		size: std::fs::metadata(exe_path)?.len(),
+		large_address_aware: file_header.characteristics & IMAGE_FILE_LARGE_ADDRESS_AWARE != 0,
+		characteristics: file_header.characteristics_names(),
+	})
+}
+
+fn probe_preload(data_path: &DataPath) -> Result<Option<PreloadProbe>> {
+	let path = patcher_preload::preload_path(data_path);
+	if !path.exists() {
+		return Ok(None);
+	}
+	let preload = PreloadInfo::read(&path)?;
+	Ok(Some(PreloadProbe {
+		path,
+		version: preload.version,
+		on_running_count: preload.on_running.len(),
+		on_start_count: preload.on_start.len(),
+	}))
 }
 
-fn cmd_set_path(path: PathBuf) -> Result<()> {
+fn probe(path: GamePathArgs) -> Result<Probe> {
+	// Unlike resolve_game_path, a given path is used without being saved to the config
 	let mut config = Config::load_or_default();
+	match path.choose(&config)?.0 {
+		GamePathChoice::Given(p) => config.assumed_path = Some(config::game_dir_from_path(&p)),
+		GamePathChoice::Assumed(p) => config.assumed_path = Some(p),
+		GamePathChoice::Saved => {}
+	}
+	let mut probe = Probe {
+		game_path: config.game_path().map(Path::to_path_buf),
+		..Default::default()
+	};
+	let record = |result: Result<()>, errors: &mut Vec<String>| {
+		if let Err(e) = result {
+			errors.push(format!("{:#}", e));
+		}
+	};
+
+	match config.get_bb_exe_path() {
+		Some(exe_path) => {
+			let exe_path = exe_path.as_ref();
+			probe.exe_path = Some(exe_path.to_path_buf());
+			let result = probe_exe(exe_path).map(|exe| probe.exe = Some(exe));
+			record(result, &mut probe.errors);
+			let result = patcher_laa::find_backups(exe_path).map(|backups| {
+				probe.backups = backups
+					.into_iter()
+					.map(|backup| BackupProbe {
+						path: backup.path,
+						version: backup.version.to_string(),
+						compressed: backup.compressed,
+					})
+					.collect()
+			});
+			record(result, &mut probe.errors);
+		}
+		None => probe
+			.errors
+			.push("Could not find BattleBrothers.exe".to_owned()),
+	}
+	match config.get_bb_data_path() {
+		Some(data_path) => {
+			probe.data_path = Some(data_path.as_ref().to_path_buf());
+			let result = probe_preload(&data_path).map(|preload| probe.preload = preload);
+			record(result, &mut probe.errors);
+		}
+		None => probe.errors.push("Could not find data folder".to_owned()),
+	}
+	let result = process::is_game_running().map(|running| probe.game_running = Some(running));
+	record(result, &mut probe.errors);
+
+	Ok(probe)
+}
+
+fn cmd_probe(path: GamePathArgs, json: bool) -> Result<ExitCode> {
+	let probe = probe(path)?;
+	if json {
+		println!(
+			"{}",
+			serde_json::to_string_pretty(&probe).context("Couldn't serialize probe")?
+		);
+		return Ok(ExitCode::Success);
+	}
+
+	let show = |path: &Option<PathBuf>| match path {
+		Some(path) => format!("{:?}", path),
+		None => "not found".to_owned(),
+	};
+	println!("Game path: {}", show(&probe.game_path));
+	println!("Exe:       {}", show(&probe.exe_path));
+	println!("Data:      {}", show(&probe.data_path));
+	if let Some(exe) = &probe.exe {
+		println!("Version:   {}", exe.version);
+		println!("SHA-256:   {}", exe.sha256);
+		println!("Size:      {} bytes", exe.size);
+		println!(
+			"LAA:       {}",
+			if exe.large_address_aware {
+				"set"
+			} else {
+				"not set"
+			}
+		);
+		println!("Flags:     {}", exe.characteristics.join(" | "));
+	}
+	match probe.backups.is_empty() {
+		true => println!("Backups:   none"),
+		false => {
+			println!("Backups:");
+			for backup in probe.backups.iter() {
+				println!("  {:?} ({})", backup.path, backup.version);
+			}
+		}
+	}
+	match &probe.preload {
+		Some(preload) => println!(
+			"Preload:   {:?}, built by version {} with {} on_start and {} on_running resources",
+			preload.path,
+			preload.version.as_deref().unwrap_or("unknown"),
+			preload.on_start_count,
+			preload.on_running_count
+		),
+		None => println!("Preload:   none"),
+	}
+	match probe.game_running {
+		Some(running) => println!("Running:   {}", if running { "yes" } else { "no" }),
+		None => println!("Running:   unknown"),
+	}
+	for error in probe.errors.iter() {
+		println!("Warning: {}", error);
+	}
+
+	Ok(ExitCode::Success)
+}
+
+/// One part of the install checked by verify-install
+#[derive(Serialize)]
+struct InstallCheck {
+	name: &'static str,
+	verdict: Verdict,
+	detail: String,
+}
+
+impl InstallCheck {
+	fn new(name: &'static str, verdict: Verdict, detail: impl Into<String>) -> Self {
+		Self {
+			name,
+			verdict,
+			detail: detail.into(),
+		}
+	}
+}
+
+#[derive(Serialize)]
+struct InstallVerification {
+	verdict: Verdict,
+	checks: Vec<InstallCheck>,
+}
+
+fn verify_exe(config: &Config, exe_path: &ExePath) -> Result<InstallCheck> {
+	let hash = sha_hash_path(exe_path.as_ref())?;
+	let (version, _) = patcher_laa::detect_version_from_hash(exe_path.as_ref(), &hash, None)?;
+	if !patcher_laa::is_laa(exe_path.as_ref(), None)? {
+		return Ok(InstallCheck::new(
+			"exe",
+			Verdict::Fail,
+			format!(
+				"{} exe without the 4GB patch, run 'bb-patcher patch4gb'",
+				version
+			),
+		));
+	}
+	let game_version = GameVersionDb::embedded().lookup(&hash).map(str::to_owned);
+	let hash = const_hex::encode(&hash);
+	let patched_by_us = config.last_patched_hash.as_deref() == Some(hash.as_str());
+	Ok(match (game_version, patched_by_us) {
+		(Some(game_version), _) => InstallCheck::new(
+			"exe",
+			Verdict::Pass,
+			format!("4GB patched, game version {}", game_version),
+		),
+		(None, true) => InstallCheck::new(
+			"exe",
+			Verdict::Pass,
+			"4GB patched, unchanged since bb-patcher patched it",
+		),
+		(None, false) => InstallCheck::new(
+			"exe",
+			Verdict::Warn,
+			format!(
+				"4GB patched, but neither a known version nor patched by bb-patcher (SHA-256 {})",
+				hash
+			),
+		),
+	})
+}
+
+/// Checks the data folder, MSU, the mods and the preload, from a single scan of the mods
+fn verify_data(config: &Config, msu_id: &str, checks: &mut Vec<InstallCheck>) {
+	let Some(data_path) = config.get_bb_data_path() else {
+		checks.push(InstallCheck::new(
+			"data",
+			Verdict::Fail,
+			"Could not find data folder with data_001.dat",
+		));
+		return;
+	};
+	checks.push(InstallCheck::new(
+		"data",
+		Verdict::Pass,
+		format!("{:?}", data_path.as_ref()),
+	));
+
+	let options = PreloadOptions {
+		keep_going: true,
+		msu_id: Some(msu_id.to_owned()),
+		..Default::default()
+	};
+	let resources = match patcher_preload::get_resource_handler(&data_path, &options) {
+		Ok(resources) => resources,
+		Err(e) => {
+			checks.push(InstallCheck::new(
+				"mods",
+				Verdict::Fail,
+				format!("Couldn't scan the mods: {:#}", e),
+			));
+			return;
+		}
+	};
+	let msu = resources.mods().iter().find(|file_name| {
+		patcher_preload::mod_id_from_filename(file_name)
+			.0
+			.eq_ignore_ascii_case(msu_id)
+	});
+	checks.push(match msu {
+		Some(file_name) => InstallCheck::new(
+			"msu",
+			Verdict::Pass,
+			format!(
+				"MSU {} in {:?}",
+				patcher_preload::mod_id_from_filename(file_name)
+					.1
+					.unwrap_or("(unknown version)"),
+				file_name
+			),
+		),
+		None => InstallCheck::new(
+			"msu",
+			Verdict::Fail,
+			format!(
+				"MSU (mod id {:?}) is not installed, pass --msu-id if it's installed under a \
+				different id",
+				msu_id
+			),
+		),
+	});
+	let problem_count = resources.error_count() + resources.failures().len();
+	checks.push(match problem_count {
+		0 => InstallCheck::new(
+			"mods",
+			Verdict::Pass,
+			format!("{} mod(s) scanned cleanly", resources.mods().len()),
+		),
+		_ => InstallCheck::new(
+			"mods",
+			Verdict::Fail,
+			format!(
+				"{} problem(s) scanning the mods, run 'bb-patcher preload --analyze' for details",
+				problem_count
+			),
+		),
+	});
+
+	let preload_path = patcher_preload::preload_path(&data_path);
+	let preload = if !config.preload_enabled {
+		InstallCheck::new(
+			"preload",
+			Verdict::Warn,
+			"preload generation is disabled in the config",
+		)
+	} else if !preload_path.exists() {
+		InstallCheck::new(
+			"preload",
+			Verdict::Warn,
+			"no preload, run 'bb-patcher preload' to create it",
+		)
+	} else {
+		match patcher_preload::is_preload_stale(&data_path, &resources) {
+			Ok(false) => InstallCheck::new(
+				"preload",
+				Verdict::Pass,
+				format!(
+					"up to date with {} on_start and {} on_running resources",
+					resources.on_start_count(),
+					resources.on_running_count()
+				),
+			),
+			Ok(true) => InstallCheck::new(
+				"preload",
+				Verdict::Fail,
+				"doesn't match the installed mods, run 'bb-patcher preload' to refresh it",
+			),
+			Err(e) => InstallCheck::new(
+				"preload",
+				Verdict::Fail,
+				format!("Couldn't read {:?}: {:#}", preload_path, e),
+			),
+		}
+	};
+	checks.push(preload);
+}
+
+fn cmd_verify_install(path: GamePathArgs, msu_id: String, json: bool) -> Result<ExitCode> {
+	let config = resolve_game_path(path)?;
+
+	let mut checks = Vec::new();
+	checks.push(match config.get_bb_exe_path() {
+		Some(exe_path) => verify_exe(&config, &exe_path).unwrap_or_else(|e| {
+			InstallCheck::new(
+				"exe",
+				Verdict::Fail,
+				format!("Couldn't read {:?}: {:#}", exe_path.as_ref(), e),
+			)
+		}),
+		None => InstallCheck::new("exe", Verdict::Fail, "Could not find BattleBrothers.exe"),
+	});
+	verify_data(&config, &msu_id, &mut checks);
+	let verdict = [Verdict::Fail, Verdict::Warn]
+		.into_iter()
+		.find(|verdict| checks.iter().any(|check| check.verdict == *verdict))
+		.unwrap_or(Verdict::Pass);
+	let verification = InstallVerification { verdict, checks };
+
+	if json {
+		println!(
+			"{}",
+			serde_json::to_string_pretty(&verification)
+				.context("Couldn't serialize the verification")?
+		);
+	} else {
+		println!(
+			"Verifying {:?}",
+			config.game_path().unwrap_or(Path::new(""))
+		);
+		for check in verification.checks.iter() {
+			println!("  [{}] {}: {}", check.verdict, check.name, check.detail);
+		}
+		println!("  Verdict: {}", verification.verdict);
+	}
+
+	match verification.verdict {
+		Verdict::Fail => Ok(ExitCode::InstallProblems),
+		Verdict::Pass | Verdict::Warn => Ok(ExitCode::Success),
+	}
+}
+
+fn cmd_repair(
+	path: GamePathArgs,
+	patch: PatchArgs,
+	running: RunningGameArgs,
+	yes: bool,
+) -> Result<ExitCode> {
+	let mut config = resolve_game_path(path)?;
+
+	let exe_path = config
+		.get_bb_exe_path()
+		.context("Could not find BattleBrothers.exe")?;
+	patcher_laa::warn_forced_pe_offset(patch.pe_offset);
+	if patcher_laa::is_laa(exe_path.as_ref(), patch.pe_offset)? {
+		println!(
+			"{:?} is still patched, nothing to repair",
+			exe_path.as_ref()
+		);
+		return Ok(ExitCode::Success);
+	}
+	let last_patched_hash = config.last_patched_hash.clone().context(
+		"There is no record of this exe being patched, run 'bb-patcher patch4gb' to patch it",
+	)?;
+	let backups = patcher_laa::find_valid_backups(exe_path.as_ref())?;
+	if backups.is_empty() {
+		return Err(anyhow!(
+			"No valid backup found next to {:?}, run 'bb-patcher patch4gb' to patch it",
+			exe_path.as_ref()
+		));
+	}
+
+	let version = patcher_laa::detect_version(exe_path.as_ref())?;
+	println!(
+		"{:?} was patched before, but is now an unpatched {} exe",
+		exe_path.as_ref(),
+		version
+	);
+	println!("  Hash when last patched: {}", last_patched_hash);
+	println!(
+		"  Current hash:           {}",
+		const_hex::encode(sha_hash_path(exe_path.as_ref())?)
+	);
+	for backup in backups.iter() {
+		println!("  Backup: {:?} ({})", backup.path, backup.version);
+	}
+	if !yes && !confirm("Re-apply the 4GB patch?")? {
+		return Err(anyhow!(
+			"Repair cancelled, nothing was changed; pass --yes to repair without being asked"
+		));
+	}
+
+	running.wait_until_closed(&exe_path)?;
+	let result = patch_exe(exe_path.as_ref(), &patch.options(&config))?;
+	println!("Re-applied the 4GB patch: {}", result);
+	record_patched_hash(&mut config, &exe_path)?;
+	println!("Recorded the new patched hash in the config");
+
+	if !config.preload_enabled {
+		return Ok(ExitCode::Success);
+	}
+	let Some(data_path) = config.get_bb_data_path() else {
+		println!("Warning: Could not find data folder, skipping the preload");
+		return Ok(ExitCode::Success);
+	};
+	let options = PreloadOptions::default();
+	let resources = patcher_preload::get_resource_handler(&data_path, &options)?;
+	if !patcher_preload::is_preload_stale(&data_path, &resources)? {
+		println!("Preload is up to date");
+	} else if resources.error_count() > 0 || !resources.failures().is_empty() {
+		println!("Warning: The preload is stale, but the mods couldn't be scanned cleanly; run 'bb-patcher preload' for details");
+	} else {
+		patcher_preload::create_mod(&data_path, &resources, &options)?;
+		println!(
+			"Refreshed the stale preload with {} on_start and {} on_running resources",
+			resources.on_start_count(),
+			resources.on_running_count()
+		);
+	}
+
+	Ok(ExitCode::Success)
+}
+
+fn cmd_restore(path: GamePathArgs, backup: Option<PathBuf>, yes: bool) -> Result<ExitCode> {
+	let mut config = resolve_game_path(path)?;
+
+	let exe_path = config
+		.get_bb_exe_path()
+		.context("Could not find BattleBrothers.exe")?;
+	let backup = match backup {
+		Some(backup_path) => Backup::open(backup_path)?,
+		None => {
+			let mut backups = patcher_laa::find_valid_backups(exe_path.as_ref())?;
+			match backups.len() {
+				0 => {
+					return Err(anyhow!(
+						"No valid backup found next to {:?}",
+						exe_path.as_ref()
+					))
+				}
+				1 => backups.remove(0),
+				// The same exe backed up both plainly and compressed
+				_ if backups
+					.iter()
+					.map(Backup::read)
+					.collect::<Result<HashSet<_>>>()?
+					.len() == 1 =>
+				{
+					backups.remove(0)
+				}
+				_ => {
+					let names: Vec<_> = backups
+						.iter()
+						.map(|backup| format!("{:?}", backup.path))
+						.collect();
+					return Err(anyhow!(
+						"There are several valid backups, pick one with --backup: {}",
+						names.join(", ")
+					));
+				}
+			}
+		}
+	};
+	if !backup.is_valid() {
+		println!(
+			"Warning: {:?} is not a known unpatched exe ({})",
+			backup.path, backup.version
+		);
+	}
+	if !yes
+		&& !confirm(&format!(
+			"Overwrite {:?} with the {} exe from {:?}?",
+			exe_path.as_ref(),
+			backup.version,
+			backup.path
+		))? {
+		return Err(anyhow!(
+			"Restore cancelled, nothing was changed; pass --yes to restore without being asked"
+		));
+	}
+
+	backup.restore(exe_path.as_ref())?;
+	println!(
+		"Restored {:?} from {:?} ({})",
+		exe_path.as_ref(),
+		backup.path,
+		backup.version
+	);
+	// So that `repair` and `check` don't treat the unpatched exe as Steam having reverted it
+	config.clear_last_patched_hash()?;
+
+	Ok(ExitCode::Success)
+}
 
+fn cmd_uninstall(path: GamePathArgs, clear_config: bool, yes: bool) -> Result<ExitCode> {
+	let mut config = resolve_game_path(path)?;
+
+	let exe_path = config
+		.get_bb_exe_path()
+		.context("Could not find BattleBrothers.exe")?;
+	let data_path = config
+		.get_bb_data_path()
+		.context("Could not find data folder")?;
+	let mut backups = patcher_laa::find_backups(exe_path.as_ref())?;
+	backups.extend(patcher_laa::find_archived_backups(exe_path.as_ref())?);
+	// The oldest clean backup is the exe as it was before the tool first touched it. An exe
+	// patched with force_unknown_as only has unknown backups, the oldest unpatched one of
+	// those is its original then.
+	let oldest = |is_original: fn(&Backup) -> bool| {
+		backups
+			.iter()
+			.filter(|backup| is_original(backup))
+			.min_by_key(|backup| backup.modified())
+	};
+	let clean = oldest(Backup::is_valid)
+		.or_else(|| oldest(|backup| backup.version != GameVersion::AlreadyPatched));
+	// Without a backup to restore, the backups may be the only copies of the original exe
+	let delete_backups = clean.is_some();
+	let preload = patcher_preload::preload_path(&data_path);
+
+	println!(
+		"Uninstalling from {:?}:",
+		config.game_path().unwrap_or(Path::new(""))
+	);
+	match clean {
+		Some(clean) => println!(
+			"  Restore {:?} from {:?} ({})",
+			exe_path.as_ref(),
+			clean.path,
+			clean.version
+		),
+		None => println!(
+			"  Keep {:?} and its backups as they are, there is no unpatched backup of it",
+			exe_path.as_ref()
+		),
+	}
+	for backup in backups.iter().filter(|_| delete_backups) {
+		println!("  Delete backup {:?}", backup.path);
+	}
+	if preload.exists() {
+		println!("  Delete preload {:?}", preload);
+	}
+	if clear_config {
+		println!("  Delete config file {:?}", Config::file_path());
+	}
+	if !yes && !confirm("Uninstall everything listed above?")? {
+		return Err(anyhow!(
+			"Uninstall cancelled, nothing was changed; pass --yes to uninstall without being asked"
+		));
+	}
+
+	if let Some(clean) = clean {
+		clean.restore(exe_path.as_ref())?;
+		println!("Restored {:?} from {:?}", exe_path.as_ref(), clean.path);
+	}
+	for backup in backups.iter().filter(|_| delete_backups) {
+		std::fs::remove_file(&backup.path)
+			.with_context(|| format!("Couldn't remove backup {:?}", backup.path))?;
+		println!("Deleted backup {:?}", backup.path);
+	}
+	if patcher_preload::remove_mod(&data_path)? {
+		println!("Deleted preload {:?}", preload);
+	}
+	if clear_config {
+		if Config::remove_file()? {
+			println!("Deleted config file {:?}", Config::file_path());
+		}
+		hash_cache::remove().context("Couldn't remove the hash cache")?;
+	} else {
+		config.clear_last_patched_hash()?;
+	}
+
+	Ok(ExitCode::Success)
+}
+
+fn cmd_list_backups(path: GamePathArgs) -> Result<ExitCode> {
+	let config = resolve_game_path(path)?;
+
+	let exe_path = config
+		.get_bb_exe_path()
+		.context("Could not find BattleBrothers.exe")?;
+	let mut backups = patcher_laa::find_backups(exe_path.as_ref())?;
+	backups.extend(patcher_laa::find_archived_backups(exe_path.as_ref())?);
+	println!("Backups of {:?}:", exe_path.as_ref());
+	if backups.is_empty() {
+		println!("  None");
+	}
+	for backup in backups {
+		println!(
+			"  {:?}: {}, {} bytes{}",
+			backup.path,
+			backup.version,
+			std::fs::metadata(&backup.path)?.len(),
+			if backup.compressed {
+				" (compressed)"
+			} else {
+				""
+			}
+		);
+	}
+
+	Ok(ExitCode::Success)
+}
+
+/// Asks a yes/no question on the terminal, defaulting to no. Answers no without asking when
+/// stdin isn't a terminal, so scripts never hang on a prompt.
+fn confirm(question: &str) -> Result<bool> {
+	let stdin = std::io::stdin();
+	if !stdin.is_terminal() {
+		return Ok(false);
+	}
+	eprint!("{} [y/N] ", question);
+	std::io::stderr().flush()?;
+	let mut answer = String::new();
+	stdin.read_line(&mut answer)?;
+	Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+/// Points the config at the game folder given by `path`, which may be the exe or the game
+/// folder. When it's neither, a nearby game folder is offered instead. Returns the game folder.
+fn apply_game_path(config: &mut Config, path: &Path) -> Result<PathBuf> {
 	if path.is_file()
 		&& path
 			.file_name()
 			.map(|f| f == "BattleBrothers.exe")
 			.unwrap_or(false)
 	{
-		let bb_path = config.set_path_from_exe(&path)?;
-		println!("Game path set to: {:?}", bb_path);
-	} else if path.is_dir() {
-		config.set_path(&path)?;
-		println!("Game path set to: {:?}", path);
-	} else {
+		return config.set_path_from_exe(path);
+	}
+	if path.is_dir() && is_game_dir(path) {
+		config.set_path(path)?;
+		return Ok(path.to_path_buf());
+	}
+	if let Some(candidate) = complete_game_path(path) {
+		if confirm(&format!(
+			"{:?} is not a Battle Brothers folder, use {:?} instead?",
+			path, candidate
+		))? {
+			config.set_path(&candidate)?;
+			return Ok(candidate);
+		}
 		return Err(anyhow!(
-			"Invalid path: {:?}\nExpected path to BattleBrothers.exe or the game directory",
-			path
+			"{:?} is not a Battle Brothers folder, did you mean {:?}?",
+			path,
+			candidate
 		));
 	}
+	if path.is_dir() {
+		config.set_path(path)?;
+		return Ok(path.to_path_buf());
+	}
+	Err(anyhow!(
+		"Invalid path: {:?}\nExpected path to BattleBrothers.exe or the game directory",
+		path
+	))
+}
 
-	Ok(())
+fn cmd_set_path(path: PathBuf) -> Result<ExitCode> {
+	let mut config = Config::load_or_default();
+
+	let bb_path = apply_game_path(&mut config, &path)?;
+	println!("Game path set to: {:?}", bb_path);
+
+	Ok(ExitCode::Success)
 }
 
-fn cmd_config() -> Result<()> {
-	let config = Config::load_or_default();
+fn cmd_config(
+	migrate: bool,
+	force_unknown_as: Option<ForcedVersion>,
+	clear_force_unknown_as: bool,
+	require_clean_backup: Option<bool>,
+	backup_retention: Option<usize>,
+) -> Result<ExitCode> {
+	if migrate {
+		let changes = Config::migrate()?;
+		println!("Migrated configuration to the current format:");
+		if changes.is_empty() {
+			println!("  Already up to date");
+		}
+		for change in changes {
+			println!("  {}", change);
+		}
+		return Ok(ExitCode::Success);
+	}
+
+	let mut config = Config::load_or_default();
+	if let Some(forced) = force_unknown_as {
+		if config.bb_path.is_none() {
+			return Err(anyhow!(
+				"Game path not configured, run 'bb-patcher set-path <PATH>' first"
+			));
+		}
+		config.set_force_unknown_as(Some(forced))?;
+		println!(
+			"WARNING: An unknown BattleBrothers.exe in this install will now be patched as {}.\n\
+			If it isn't actually that kind of exe, the patched game may not start. Undo this with\n\
+			'bb-patcher config --clear-force-unknown-as'.\n",
+			forced.version()
+		);
+	} else if clear_force_unknown_as {
+		config.set_force_unknown_as(None)?;
+		println!("Unknown exes are no longer patched as another kind\n");
+	}
+	if let Some(required) = require_clean_backup {
+		config.set_require_clean_backup(required)?;
+		match required {
+			true => println!("Patching now requires a clean backup of the exe\n"),
+			false => println!("Patching no longer requires a clean backup of the exe\n"),
+		}
+	}
+	if let Some(retention) = backup_retention {
+		config.set_backup_retention(Some(retention).filter(|retention| *retention > 0))?;
+		match retention {
+			0 => println!("Patching now keeps all backups\n"),
+			_ => println!(
+				"Patching now keeps the newest {} backup(s) of each kind\n",
+				retention
+			),
+		}
+	}
 
 	println!("Current configuration:");
 	match &config.bb_path {
@@ -280,6 +2206,34 @@ fn cmd_config() -> Result<()> {
 			} else {
 				println!("  Data folder: NOT FOUND");
 			}
+
+			println!(
+				"  Preload generation: {}",
+				if config.preload_enabled {
+					"enabled"
+				} else {
+					"disabled"
+				}
+			);
+
+			if let Some(hash) = &config.last_patched_hash {
+				println!("  Last patched hash: {}", hash);
+			}
+
+			if let Some(forced) = config.force_unknown_as {
+				println!(
+					"  Unknown exe is patched as: {} (forced, see --clear-force-unknown-as)",
+					forced.version()
+				);
+			}
+
+			if config.require_clean_backup {
+				println!("  Patching requires a clean backup");
+			}
+
+			if let Some(retention) = config.backup_retention {
+				println!("  Backups kept of each kind: {}", retention);
+			}
 		}
 		None => {
 			println!("  Game path: Not configured");
@@ -287,30 +2241,634 @@ fn cmd_config() -> Result<()> {
 		}
 	}
 
-	Ok(())
+	Ok(ExitCode::Success)
+}
+
+fn cmd_examples() -> Result<ExitCode> {
+	for (i, (description, commands)) in EXAMPLES.iter().enumerate() {
+		if i > 0 {
+			println!();
+		}
+		println!("{}:", description);
+		for command in commands.iter() {
+			println!("  {}", command);
+		}
+	}
+
+	Ok(ExitCode::Success)
+}
+
+fn cmd_hash(
+	path: GamePathArgs,
+	file: Option<PathBuf>,
+	hash_algorithm: HashAlgorithm,
+) -> Result<ExitCode> {
+	let file = match file {
+		Some(file) => file,
+		None => resolve_game_path(path)?
+			.get_bb_exe_path()
+			.context("Could not find BattleBrothers.exe")?
+			.as_ref()
+			.to_path_buf(),
+	};
+
+	let hash = patcher_laa::hash_path(&file, hash_algorithm)
+		.with_context(|| format!("Couldn't hash {:?}", file))?;
+	println!("{} of {:?}:", hash_algorithm, file);
+	println!("  {}", const_hex::encode(hash));
+
+	Ok(ExitCode::Success)
+}
+
+#[derive(Serialize)]
+struct PeDump {
+	path: PathBuf,
+	size: u64,
+	sha256: String,
+	large_address_aware: bool,
+	dos_header: ImageDosHeader,
+	file_header: ImageFileHeader,
+}
+
+fn cmd_dump_pe(
+	exe: Option<PathBuf>,
+	path: GamePathArgs,
+	output: Option<PathBuf>,
+) -> Result<ExitCode> {
+	let exe = match exe {
+		Some(exe) => exe,
+		None => resolve_game_path(path)?
+			.get_bb_exe_path()
+			.context("Could not find BattleBrothers.exe")?
+			.as_ref()
+			.to_path_buf(),
+	};
+
+	let (dos_header, file_header) = patcher_laa::read_pe_headers(&exe)
+		.with_context(|| format!("Couldn't read PE headers of {:?}", exe))?;
+	let dump = PeDump {
+		size: std::fs::metadata(&exe)?.len(),
+		sha256: const_hex::encode(sha_hash_path(&exe)?),
+		large_address_aware: file_header.characteristics & IMAGE_FILE_LARGE_ADDRESS_AWARE != 0,
+		path: exe,
+		dos_header,
+		file_header,
+	};
+	let json = serde_json::to_string_pretty(&dump).context("Couldn't serialize PE headers")?;
+	match output {
+		Some(output) => {
+			std::fs::write(&output, json)
+				.with_context(|| format!("Couldn't write {:?}", output))?;
+			println!("Wrote PE headers of {:?} to {:?}", dump.path, output);
+		}
+		None => println!("{}", json),
+	}
+
+	Ok(ExitCode::Success)
 }
 
-fn main() {
+fn cmd_checksum(
+	exe: Option<PathBuf>,
+	path: GamePathArgs,
+	fix: bool,
+	running: RunningGameArgs,
+) -> Result<ExitCode> {
+	// The game's exe is tracked in the config, any other one is only backed up
+	let (mut config, exe, is_game_exe) = match exe {
+		Some(exe) => (Config::load_or_default(), exe, false),
+		None => {
+			let config = resolve_game_path(path)?;
+			let exe = config
+				.get_bb_exe_path()
+				.context("Could not find BattleBrothers.exe")?
+				.as_ref()
+				.to_path_buf();
+			(config, exe, true)
+		}
+	};
+
+	let checksum = match fix {
+		true => {
+			running.wait_until_closed(&ExePath::new(exe.clone()))?;
+			let options = PatchOptions {
+				backup_retention: config.backup_retention,
+				..Default::default()
+			};
+			patcher_laa::fix_pe_checksum(&exe, &options)
+		}
+		false => patcher_laa::pe_checksum(&exe),
+	}
+	.with_context(|| format!("Couldn't read the checksum of {:?}", exe))?;
+	println!("Checksum of {:?}:", exe);
+	println!("  Stored:   0x{:08X}", checksum.stored);
+	println!("  Computed: 0x{:08X}", checksum.computed);
+	if checksum.matches() {
+		println!("  The checksum is correct");
+	} else if fix {
+		println!("  Fixed: wrote 0x{:08X} to the exe", checksum.computed);
+		if is_game_exe {
+			record_patched_hash(&mut config, &ExePath::new(exe))?;
+		}
+	} else if checksum.stored == 0 {
+		println!("  No checksum is stored, Windows only checks it for drivers and system DLLs");
+	} else {
+		println!("  The checksum is wrong, --fix writes the computed one");
+		return Ok(ExitCode::ChecksumMismatch);
+	}
+
+	Ok(ExitCode::Success)
+}
+
+fn cmd_export_manifest(path: GamePathArgs, output: Option<PathBuf>) -> Result<ExitCode> {
+	let config = resolve_game_path(path)?;
+	let exe_path = config
+		.get_bb_exe_path()
+		.context("Could not find BattleBrothers.exe")?;
+	let data_path = config
+		.get_bb_data_path()
+		.context("Could not find data folder")?;
+
+	let manifest = InstallManifest::build(&exe_path, &data_path)?;
+	let json = serde_json::to_string_pretty(&manifest).context("Couldn't serialize manifest")?;
+	match output {
+		Some(output) => {
+			std::fs::write(&output, json + "\n")
+				.with_context(|| format!("Couldn't write {:?}", output))?;
+			println!(
+				"Wrote the manifest of {} mod(s) and the {} exe to {:?}",
+				manifest.mods.len(),
+				manifest.exe.version,
+				output
+			);
+		}
+		None => println!("{}", json),
+	}
+
+	Ok(ExitCode::Success)
+}
+
+fn cmd_import_manifest(path: GamePathArgs, manifest_path: PathBuf) -> Result<ExitCode> {
+	let config = resolve_game_path(path)?;
+	let exe_path = config
+		.get_bb_exe_path()
+		.context("Could not find BattleBrothers.exe")?;
+	let data_path = config
+		.get_bb_data_path()
+		.context("Could not find data folder")?;
+
+	let expected = InstallManifest::read(&manifest_path)?;
+	println!(
+		"Comparing {:?} against {:?}",
+		config.game_path().unwrap_or(Path::new("")),
+		manifest_path
+	);
+	let differences = InstallManifest::build(&exe_path, &data_path)?.differences(&expected);
+	if differences.is_empty() {
+		println!("  The install matches the manifest");
+	}
+	for difference in differences.iter() {
+		println!("  {}", difference);
+	}
+
+	match differences.is_empty() {
+		true => Ok(ExitCode::Success),
+		false => Ok(ExitCode::ManifestMismatch),
+	}
+}
+
+fn print_header_diff(
+	header_name: &str,
+	a: Vec<(&'static str, String)>,
+	b: Vec<(&'static str, String)>,
+) -> usize {
+	let differences: Vec<_> = a
+		.into_iter()
+		.zip(b)
+		.filter(|((_, a_value), (_, b_value))| a_value != b_value)
+		.map(|((name, a_value), (_, b_value))| (name, a_value, b_value))
+		.collect();
+	if differences.is_empty() {
+		println!("  {}: identical", header_name);
+	} else {
+		println!("  {}:", header_name);
+		for (name, a_value, b_value) in differences.iter() {
+			println!("    {}: {} -> {}", name, a_value, b_value);
+		}
+	}
+	differences.len()
+}
+
+fn cmd_compare(a: PathBuf, b: PathBuf) -> Result<ExitCode> {
+	let (a_dos, a_file) = patcher_laa::read_pe_headers(&a)
+		.with_context(|| format!("Couldn't read PE headers of {:?}", a))?;
+	let (b_dos, b_file) = patcher_laa::read_pe_headers(&b)
+		.with_context(|| format!("Couldn't read PE headers of {:?}", b))?;
+
+	println!("Comparing {:?} -> {:?}", a, b);
+	let difference_count = print_header_diff("DOS header", a_dos.fields(), b_dos.fields())
+		+ print_header_diff("File header", a_file.fields(), b_file.fields());
+
+	let a_laa = a_file.characteristics & IMAGE_FILE_LARGE_ADDRESS_AWARE != 0;
+	let b_laa = b_file.characteristics & IMAGE_FILE_LARGE_ADDRESS_AWARE != 0;
+	if a_laa != b_laa {
+		println!(
+			"  Large Address Aware flag: {} -> {}",
+			if a_laa { "set" } else { "not set" },
+			if b_laa { "set" } else { "not set" }
+		);
+	}
+	println!("  {} field(s) differ", difference_count);
+
+	Ok(ExitCode::Success)
+}
+
+#[derive(Serialize)]
+struct ConfigValue {
+	name: &'static str,
+	value: serde_json::Value,
+	source: String,
+}
+
+impl ConfigValue {
+	fn new(name: &'static str, value: impl Serialize, source: impl Into<String>) -> Self {
+		Self {
+			name,
+			value: serde_json::to_value(value).unwrap_or_default(),
+			source: source.into(),
+		}
+	}
+}
+
+/// The game path the command would use and where it comes from, chosen like
+/// `resolve_game_path` does but without validating or saving anything
+fn effective_game_path(
+	path: Option<&GamePathArgs>,
+	env_file: Option<&EnvFile>,
+	config: &Config,
+) -> Result<(Option<PathBuf>, String)> {
+	let Some(path) = path else {
+		return Ok((config.bb_path.clone(), saved_game_path_source(config)));
+	};
+	// --env-file isn't applied to the command yet
+	let path = GamePathArgs {
+		env_game_path: env_file.and_then(|env_file| env_file.game_path.clone()),
+		..path.clone()
+	};
+	Ok(match path.choose(config)? {
+		(GamePathChoice::Given(p), source) => (Some(config::game_dir_from_path(&p)), source),
+		(GamePathChoice::Assumed(p), source) => (Some(p), source),
+		(GamePathChoice::Saved, source) => (config.bb_path.clone(), source),
+	})
+}
+
+fn dump_config(
+	command: Option<&Commands>,
+	env_file: Option<&EnvFile>,
+	output_options: &OutputOptions,
+) -> Result<ExitCode> {
+	let config = Config::load_or_default();
+	let stored_keys = Config::stored_keys();
+	let from_config = |key: &str| match stored_keys.iter().any(|stored| stored == key) {
+		true => "config",
+		false => "default",
+	};
+	let flag_or_default = |set: bool, flag: &str| match set {
+		true => flag.to_owned(),
+		false => "default".to_owned(),
+	};
+
+	let (bb_path, bb_path_source) = effective_game_path(
+		command.and_then(Commands::game_path_args),
+		env_file,
+		&config,
+	)?;
+	let mut values = vec![
+		ConfigValue::new("config_file", Config::file_path(), "default"),
+		ConfigValue::new("game_path", &bb_path, bb_path_source),
+	];
+	let resolved = Config {
+		bb_path,
+		..config.clone()
+	};
+	values.push(ConfigValue::new(
+		"exe_path",
+		resolved
+			.get_bb_exe_path()
+			.map(|exe| exe.as_ref().to_path_buf()),
+		"game_path",
+	));
+	values.push(ConfigValue::new(
+		"data_path",
+		resolved
+			.get_bb_data_path()
+			.map(|data| data.as_ref().to_path_buf()),
+		"game_path",
+	));
+	values.push(ConfigValue::new(
+		"preload_enabled",
+		config.preload_enabled,
+		from_config("preload_enabled"),
+	));
+	values.push(match resolved.bb_path == config.bb_path {
+		true => ConfigValue::new(
+			"force_unknown_as",
+			config.force_unknown_as,
+			from_config("force_unknown_as"),
+		),
+		false => ConfigValue::new(
+			"force_unknown_as",
+			None::<ForcedVersion>,
+			"not applied to this game path",
+		),
+	});
+	let require_clean_backup_flag = command
+		.and_then(Commands::patch_args)
+		.is_some_and(|patch| patch.require_clean_backup);
+	let require_clean_backup_env = env_file.and_then(|env_file| env_file.require_clean_backup);
+	let (require_clean_backup, require_clean_backup_source) =
+		match (require_clean_backup_flag, require_clean_backup_env) {
+			(true, _) => (true, "--require-clean-backup"),
+			(false, Some(required)) => (required, "--env-file"),
+			(false, None) => (
+				config.require_clean_backup,
+				from_config("require_clean_backup"),
+			),
+		};
+	values.push(ConfigValue::new(
+		"require_clean_backup",
+		require_clean_backup,
+		require_clean_backup_source,
+	));
+	let backup_retention_flag = command
+		.and_then(Commands::patch_args)
+		.and_then(|patch| patch.backup_retention);
+	let backup_retention_env = env_file.and_then(|env_file| env_file.backup_retention);
+	values.push(ConfigValue::new(
+		"backup_retention",
+		backup_retention_flag
+			.or(backup_retention_env)
+			.or(config.backup_retention),
+		match (backup_retention_flag, backup_retention_env) {
+			(Some(_), _) => "--backup-retention",
+			(None, Some(_)) => "--env-file",
+			(None, None) => from_config("backup_retention"),
+		},
+	));
+	values.push(ConfigValue::new(
+		"last_patched_hash",
+		&config.last_patched_hash,
+		from_config("last_patched_hash"),
+	));
+	values.push(ConfigValue::new(
+		"json",
+		output_options.json,
+		flag_or_default(output_options.json, "--json"),
+	));
+	values.push(ConfigValue::new(
+		"verbose",
+		output_options.verbose,
+		flag_or_default(output_options.verbose, "--verbose"),
+	));
+	let color_source = if output_options.no_color {
+		"--no-color"
+	} else if std::env::var_os("NO_COLOR").is_some() {
+		"env NO_COLOR"
+	} else {
+		"default, on when stderr is a terminal"
+	};
+	values.push(ConfigValue::new(
+		"color",
+		output_options.use_color(),
+		color_source,
+	));
+
+	if output_options.json {
+		println!(
+			"{}",
+			serde_json::to_string_pretty(&values).context("Couldn't serialize the config")?
+		);
+	} else {
+		for value in values {
+			println!("{} = {}  ({})", value.name, value.value, value.source);
+		}
+	}
+
+	Ok(ExitCode::Success)
+}
+
+fn print_exit_codes() {
+	for exit_code in ExitCode::ALL {
+		println!("{}\t{}", exit_code.code(), exit_code.description());
+	}
+}
+
+fn main() -> std::process::ExitCode {
 	let cli = Cli::parse();
+	// Checked here rather than with args_conflicts_with_subcommands, which would also reject
+	// global options such as --json in front of the subcommand
+	if cli.print_exit_codes && cli.command.is_some() {
+		Cli::command()
+			.error(
+				clap::error::ErrorKind::ArgumentConflict,
+				"--print-exit-codes can't be used with a subcommand",
+			)
+			.exit();
+	}
+	let output_options = OutputOptions {
+		json: cli.json,
+		verbose: cli.verbose,
+		no_color: cli.no_color,
+	};
+	let env_file = match cli.env_file.as_deref().map(EnvFile::read).transpose() {
+		Ok(env_file) => env_file,
+		Err(err) => return finish(Err(err), &output_options),
+	};
+	if cli.dump_config {
+		return finish(
+			dump_config(cli.command.as_ref(), env_file.as_ref(), &output_options),
+			&output_options,
+		);
+	}
+	let Some(mut command) = cli.command else {
+		if !cli.print_exit_codes {
+			Cli::command()
+				.error(
+					clap::error::ErrorKind::MissingSubcommand,
+					"a subcommand is required, see 'bb-patcher --help'",
+				)
+				.exit();
+		}
+		print_exit_codes();
+		return ExitCode::Success.into();
+	};
+	if let Some(env_file) = &env_file {
+		command.apply_env_file(env_file);
+	}
+
+	let result = match cli.timeout {
+		Some(timeout) => {
+			let Some(result) = run_with_timeout(command, output_options, timeout) else {
+				output::print_error(
+					&anyhow!(
+						"Timed out after {} second(s), the command was stopped where it was",
+						timeout
+					),
+					&output_options,
+				);
+				return ExitCode::TimedOut.into();
+			};
+			result
+		}
+		None => run(command, &output_options),
+	};
+
+	finish(result, &output_options)
+}
 
-	let result = match cli.command {
+fn run(command: Commands, output_options: &OutputOptions) -> Result<ExitCode> {
+	match command {
 		Commands::Patch4gb {
 			path,
-			skip_steam_drm,
-		} => cmd_patch4gb(path, skip_steam_drm),
-		Commands::Preload { path } => cmd_preload(path),
+			patch,
+			running,
+			dry_run_diff,
+			output_hash,
+			no_op_ok,
+		} => cmd_patch4gb(path, patch, running, dry_run_diff, output_hash, no_op_ok),
+		Commands::Preload {
+			path,
+			build,
+			analyze,
+			count_only,
+			enable,
+			disable,
+		} => cmd_preload(
+			path,
+			build.options(),
+			analyze,
+			count_only,
+			enable,
+			disable,
+			output_options,
+		),
 		Commands::All {
 			path,
-			skip_steam_drm,
-		} => cmd_all(path, skip_steam_drm),
-		Commands::Detect { path } => cmd_detect(path),
-		Commands::Check { path } => cmd_check(path),
+			patch,
+			running,
+			keep_going,
+			description,
+			preserve_timestamps,
+		} => cmd_all(
+			path,
+			patch,
+			running,
+			PreloadOptions {
+				keep_going,
+				description,
+				preserve_timestamps,
+				..Default::default()
+			},
+			output_options.verbose,
+		),
+		Commands::Detect {
+			path,
+			explain,
+			hash_cache,
+			no_hash_cache,
+			game_version_db,
+			report_unknown_hash,
+			pe_offset,
+		} => cmd_detect(
+			path,
+			explain,
+			hash_cache && !no_hash_cache,
+			game_version_db,
+			report_unknown_hash,
+			pe_offset,
+		),
+		Commands::Check {
+			path,
+			since_hash,
+			hash_cache,
+			no_hash_cache,
+			pe_offset,
+		} => cmd_check(path, since_hash, hash_cache && !no_hash_cache, pe_offset),
+		Commands::Probe { path } => cmd_probe(path, output_options.json),
+		Commands::VerifyInstall { path, msu_id } => {
+			cmd_verify_install(path, msu_id, output_options.json)
+		}
+		Commands::Repair {
+			path,
+			patch,
+			running,
+			yes,
+		} => cmd_repair(path, patch, running, yes),
+		Commands::Restore { path, backup, yes } => cmd_restore(path, backup, yes),
+		Commands::Uninstall {
+			path,
+			clear_config,
+			yes,
+		} => cmd_uninstall(path, clear_config, yes),
+		Commands::ListBackups { path } => cmd_list_backups(path),
 		Commands::SetPath { path } => cmd_set_path(path),
-		Commands::Config => cmd_config(),
-	};
+		Commands::Config {
+			migrate,
+			force_unknown_as,
+			clear_force_unknown_as,
+			require_clean_backup,
+			backup_retention,
+		} => cmd_config(
+			migrate,
+			force_unknown_as,
+			clear_force_unknown_as,
+			require_clean_backup,
+			backup_retention,
+		),
+		Commands::Examples => cmd_examples(),
+		Commands::Hash {
+			path,
+			file,
+			hash_algorithm,
+		} => cmd_hash(path, file, hash_algorithm),
+		Commands::DumpPe { exe, path, output } => cmd_dump_pe(exe, path, output),
+		Commands::Checksum {
+			exe,
+			path,
+			fix,
+			running,
+		} => cmd_checksum(exe, path, fix, running),
+		Commands::ExportManifest { path, output } => cmd_export_manifest(path, output),
+		Commands::ImportManifest { path, manifest } => cmd_import_manifest(path, manifest),
+		Commands::Compare { a, b } => cmd_compare(a, b),
+	}
+}
 
-	if let Err(e) = result {
-		eprintln!("Error: {:#}", e);
-		std::process::exit(1);
+/// Runs the command on a worker thread and returns `None` if it didn't finish within `timeout`
+/// seconds. The worker is then abandoned and ends with the process, wherever it was.
+fn run_with_timeout(
+	command: Commands,
+	output_options: OutputOptions,
+	timeout: u64,
+) -> Option<Result<ExitCode>> {
+	let (sender, receiver) = std::sync::mpsc::channel();
+	std::thread::spawn(move || {
+		let _ = sender.send(run(command, &output_options));
+	});
+	match receiver.recv_timeout(Duration::from_secs(timeout)) {
+		Ok(result) => Some(result),
+		Err(std::sync::mpsc::RecvTimeoutError::Timeout) => None,
+		Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => {
+			Some(Err(anyhow!("The command panicked")))
+		}
+	}
+}
+
+fn finish(result: Result<ExitCode>, output_options: &OutputOptions) -> std::process::ExitCode {
+	match result {
+		Ok(exit_code) => exit_code.into(),
+		Err(e) => {
+			output::print_error(&e, output_options);
+			ExitCode::Error.into()
+		}
 	}
 }
@@ -0,0 +1,156 @@
+//! Portable description of an install, the exe and the installed mods, to compare one install
+//! against another, e.g. a friend's setup that works against one that doesn't.
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+use crate::config::{DataPath, ExePath};
+use crate::patcher_laa::{self, sha_hash_path, GameVersionDb};
+use crate::patcher_preload::{self, mod_id_from_filename, PreloadOptions};
+
+#[derive(Serialize, Deserialize)]
+pub struct InstallManifest {
+	pub patcher_version: String,
+	pub exe: ExeManifest,
+	pub mods: Vec<ModManifest>,
+	/// Archives in the data folder that weren't scanned, with the reason. Only known for the
+	/// install the manifest was just built from, so it isn't written to the manifest.
+	#[serde(skip)]
+	pub unscanned: Vec<(String, String)>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ExeManifest {
+	pub sha256: String,
+	/// Kind of exe as detected, e.g. `GOG` or `Already Patched`
+	pub version: String,
+	pub game_version: Option<String>,
+	pub large_address_aware: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ModManifest {
+	pub file_name: String,
+	pub id: String,
+	pub version: Option<String>,
+	pub sha256: String,
+}
+
+impl InstallManifest {
+	/// Describes the install from the same scan of the mods that building the preload does
+	pub fn build(exe_path: &ExePath, data_path: &DataPath) -> Result<Self> {
+		let hash = sha_hash_path(exe_path.as_ref())?;
+		let (version, _) = patcher_laa::detect_version_from_hash(exe_path.as_ref(), &hash, None)?;
+		let exe = ExeManifest {
+			sha256: const_hex::encode(&hash),
+			version: version.to_string(),
+			game_version: GameVersionDb::embedded().lookup(&hash).map(str::to_owned),
+			large_address_aware: patcher_laa::is_laa(exe_path.as_ref(), None)?,
+		};
+		let options = PreloadOptions {
+			keep_going: true,
+			..Default::default()
+		};
+		let resources = patcher_preload::get_resource_handler(data_path, &options)?;
+		let mut mods = Vec::new();
+		for file_name in resources.mods() {
+			let (id, version) = mod_id_from_filename(file_name);
+			mods.push(ModManifest {
+				file_name: file_name.clone(),
+				id: id.to_owned(),
+				version: version.map(str::to_owned),
+				sha256: const_hex::encode(sha_hash_path(&data_path.join(file_name))?),
+			});
+		}
+		let unscanned = resources
+			.failures()
+			.iter()
+			.map(|(file_name, err)| (file_name.clone(), format!("couldn't be scanned: {}", err)))
+			.chain(resources.skipped().iter().cloned())
+			.collect();
+		Ok(Self {
+			patcher_version: env!("CARGO_PKG_VERSION").to_owned(),
+			exe,
+			mods,
+			unscanned,
+		})
+	}
+
+	pub fn read(path: &Path) -> Result<Self> {
+		let contents = std::fs::read_to_string(path)
+			.with_context(|| format!("Couldn't read manifest {:?}", path))?;
+		serde_json::from_str(&contents).with_context(|| format!("Invalid manifest {:?}", path))
+	}
+
+	/// How this install differs from the `expected` one, one line per difference
+	pub fn differences(&self, expected: &InstallManifest) -> Vec<String> {
+		let mut differences = Vec::new();
+		if self.exe.sha256 != expected.exe.sha256 {
+			differences.push(format!(
+				"exe: {} ({}) instead of {} ({})",
+				self.exe.version, self.exe.sha256, expected.exe.version, expected.exe.sha256
+			));
+		}
+		if self.exe.large_address_aware != expected.exe.large_address_aware {
+			differences.push(format!(
+				"exe: 4GB patch {} here, {} in the manifest",
+				applied(self.exe.large_address_aware),
+				applied(expected.exe.large_address_aware)
+			));
+		}
+		let find = |mods: &'_ [ModManifest], id: &str| -> Option<usize> {
+			mods.iter().position(|m| m.id.eq_ignore_ascii_case(id))
+		};
+		for expected_mod in expected.mods.iter() {
+			let Some(i) = find(&self.mods, &expected_mod.id) else {
+				let unscanned = self.unscanned.iter().find(|(file_name, _)| {
+					mod_id_from_filename(file_name)
+						.0
+						.eq_ignore_ascii_case(&expected_mod.id)
+				});
+				differences.push(match unscanned {
+					Some((file_name, reason)) => format!(
+						"unscanned mod: {} is installed as {}, but was left out: {}",
+						expected_mod.id, file_name, reason
+					),
+					None => format!(
+						"missing mod: {} ({})",
+						expected_mod.id, expected_mod.file_name
+					),
+				});
+				continue;
+			};
+			let installed = &self.mods[i];
+			if installed.version != expected_mod.version {
+				differences.push(format!(
+					"version mismatch: {} is {} here, {} in the manifest",
+					installed.id,
+					installed.version.as_deref().unwrap_or("unversioned"),
+					expected_mod.version.as_deref().unwrap_or("unversioned")
+				));
+			} else if installed.sha256 != expected_mod.sha256 {
+				differences.push(format!(
+					"different file: {} has the same version but not the same contents",
+					installed.id
+				));
+			}
+		}
+		for installed in self.mods.iter() {
+			if find(&expected.mods, &installed.id).is_none() {
+				differences.push(format!(
+					"extra mod: {} ({})",
+					installed.id, installed.file_name
+				));
+			}
+		}
+		differences
+	}
+}
+
+fn applied(laa: bool) -> &'static str {
+	match laa {
+		true => "applied",
+		false => "not applied",
+	}
+}
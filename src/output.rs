@@ -0,0 +1,71 @@
+//! Formatting of the tool's output that depends on the global output flags.
+
+use std::io::IsTerminal;
+
+use serde::Serialize;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct OutputOptions {
+	pub json: bool,
+	pub verbose: bool,
+	pub no_color: bool,
+}
+
+impl OutputOptions {
+	/// Colors are only used on a terminal, and can be turned off with `--no-color` or `NO_COLOR`
+	pub fn use_color(&self) -> bool {
+		!self.no_color && std::env::var_os("NO_COLOR").is_none() && std::io::stderr().is_terminal()
+	}
+}
+
+#[derive(Serialize)]
+struct JsonError {
+	error: String,
+	causes: Vec<String>,
+}
+
+/// Multi-line messages are indented so they stay readable under their prefix
+fn indent_continuation_lines(message: &str, indent: &str) -> String {
+	message
+		.lines()
+		.collect::<Vec<_>>()
+		.join(&format!("\n{}", indent))
+}
+
+pub fn print_error(error: &anyhow::Error, options: &OutputOptions) {
+	if options.json {
+		let json_error = JsonError {
+			error: error.to_string(),
+			causes: error
+				.chain()
+				.skip(1)
+				.map(|cause| cause.to_string())
+				.collect(),
+		};
+		match serde_json::to_string(&json_error) {
+			Ok(json) => eprintln!("{}", json),
+			Err(_) => eprintln!("Error: {:#}", error),
+		}
+		return;
+	}
+
+	let prefix = match options.use_color() {
+		true => "\x1b[1;31mError:\x1b[0m",
+		false => "Error:",
+	};
+	if !options.verbose {
+		eprintln!("{} {:#}", prefix, error);
+		return;
+	}
+	eprintln!(
+		"{} {}",
+		prefix,
+		indent_continuation_lines(&error.to_string(), "       ")
+	);
+	for cause in error.chain().skip(1) {
+		eprintln!(
+			"  Caused by: {}",
+			indent_continuation_lines(&cause.to_string(), "             ")
+		);
+	}
+}
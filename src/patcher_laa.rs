@@ -1,17 +1,36 @@
+use crate::atomic_file::{self, AtomicFile};
+use crate::config::edit_distance;
+use crate::hash_cache;
 use crate::pe::{
 	ImageDosHeader, ImageFileHeader, IMAGE_DOS_SIGNATURE, IMAGE_FILE_LARGE_ADDRESS_AWARE,
-	IMAGE_NT_SIGNATURE,
+	IMAGE_NT_OPTIONAL_HDR32_MAGIC, IMAGE_NT_OPTIONAL_HDR64_MAGIC, IMAGE_NT_SIGNATURE,
+	IMAGE_SECTION_HEADER_SIZE,
 };
 use anyhow::{anyhow, Context, Result};
-use sha2::{Digest, Sha256};
-use std::collections::HashSet;
-use std::io::{Read, Seek, SeekFrom, Write};
+use digest::Digest;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::{HashMap, HashSet};
+use std::io::{Cursor, Read, Seek, SeekFrom, Write};
 use std::mem::size_of;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
 use std::{fs::File, path::Path};
 
 const GOG_HASH_STR: &str = include_str!("../hashes/gog.txt");
 const STEAM_HASH_STR: &str = include_str!("../hashes/steam.txt");
 const STEAMLESS_HASH_STR: &str = include_str!("../hashes/steamless.txt");
+const GAME_VERSIONS_STR: &str = include_str!("../hashes/versions.toml");
+
+/// Where new game versions are reported
+const NEW_ISSUE_URL: &str = "https://github.com/stream-enterer/MSU-Launcher/issues/new";
+
+/// Most hex digits an unknown hash may differ in from a known one, counting insertions and
+/// deletions, to be a near miss. Unrelated hashes differ in around 60 of their 64.
+const NEAR_MISS_MAX_DISTANCE: usize = 4;
+
+/// Number of leading hex digits of the hash in the title of a new version's issue
+const ISSUE_TITLE_HASH_LEN: usize = 8;
 
 fn get_hash_set_from_str(hash_str: &str) -> HashSet<Vec<u8>> {
 	hash_str
@@ -21,7 +40,7 @@ fn get_hash_set_from_str(hash_str: &str) -> HashSet<Vec<u8>> {
 		.collect()
 }
 
-fn read_and_check_pe_magic_number(file: &mut File, seek_back: bool) -> Result<()> {
+fn read_and_check_pe_magic_number<R: Read + Seek>(file: &mut R, seek_back: bool) -> Result<()> {
 	let mut pe_magic_number: [u8; 4] = [0; 4];
 	file.read_exact(&mut pe_magic_number)?;
 
@@ -37,7 +56,7 @@ fn read_and_check_pe_magic_number(file: &mut File, seek_back: bool) -> Result<()
 	Ok(())
 }
 
-fn seek_to_pe_header(file: &mut File) -> Result<()> {
+fn read_dos_header<R: Read + Seek>(file: &mut R) -> Result<ImageDosHeader> {
 	file.seek(SeekFrom::Start(0))?;
 	let mut dos_header_bytes = [0u8; size_of::<ImageDosHeader>()];
 	file.read_exact(&mut dos_header_bytes)?;
@@ -50,13 +69,23 @@ fn seek_to_pe_header(file: &mut File) -> Result<()> {
 	if e_magic != IMAGE_DOS_SIGNATURE {
 		return Err(anyhow!("Invalid DOS magic number : {:X}", e_magic));
 	}
+	Ok(dos_header)
+}
 
-	file.seek(SeekFrom::Start(dos_header.e_lfanew as u64))?;
-
+/// `pe_offset` forces the PE header to be looked for at that offset, instead of the one
+/// given by `e_lfanew` in the DOS header, which isn't read at all then
+fn seek_to_pe_header<R: Read + Seek>(file: &mut R, pe_offset: Option<u64>) -> Result<()> {
+	let Some(pe_offset) = pe_offset else {
+		let dos_header = read_dos_header(file)?;
+		file.seek(SeekFrom::Start(dos_header.e_lfanew as u64))?;
+		return read_and_check_pe_magic_number(file, true);
+	};
+	file.seek(SeekFrom::Start(pe_offset))?;
 	read_and_check_pe_magic_number(file, true)
+		.with_context(|| format!("No PE header at the forced offset {:#x}", pe_offset))
 }
 
-fn read_image_file_header(file: &mut File) -> Result<ImageFileHeader> {
+fn read_image_file_header<R: Read + Seek>(file: &mut R) -> Result<ImageFileHeader> {
 	read_and_check_pe_magic_number(file, false)?;
 	let mut header_bytes = [0u8; size_of::<ImageFileHeader>()];
 	file.read_exact(&mut header_bytes)?;
@@ -67,12 +96,19 @@ fn read_image_file_header(file: &mut File) -> Result<ImageFileHeader> {
 	Ok(file_header)
 }
 
-fn write_image_file_header(file: &mut File, header: &ImageFileHeader) -> Result<()> {
-	if file.metadata()?.permissions().readonly() {
-		return Err(anyhow!(
-			"Couldn't write IMAGE_FILE_HEADER: File is readonly"
-		));
-	}
+/// Reads the DOS and file headers of a PE file without modifying it
+pub fn read_pe_headers(path: &Path) -> Result<(ImageDosHeader, ImageFileHeader)> {
+	let mut file = File::open(path)?;
+	let dos_header = read_dos_header(&mut file)?;
+	seek_to_pe_header(&mut file, None)?;
+	let file_header = read_image_file_header(&mut file)?;
+	Ok((dos_header, file_header))
+}
+
+fn write_image_file_header<F: Read + Write + Seek>(
+	file: &mut F,
+	header: &ImageFileHeader,
+) -> Result<()> {
 	read_and_check_pe_magic_number(file, false)?;
 
 	// Safety: ImageFileHeader is repr(C, packed) and contains only primitive types
@@ -87,46 +123,562 @@ fn write_image_file_header(file: &mut File, header: &ImageFileHeader) -> Result<
 	Ok(())
 }
 
-fn make_laa(path: &Path) -> Result<()> {
-	let mut file = File::options().read(true).write(true).open(path)?;
-	seek_to_pe_header(&mut file)?;
-	let mut file_header = read_image_file_header(&mut file)?;
+fn set_laa_flag<F: Read + Write + Seek>(file: &mut F, pe_offset: Option<u64>) -> Result<()> {
+	seek_to_pe_header(file, pe_offset)?;
+	let mut file_header = read_image_file_header(file)?;
 	file_header.characteristics |= IMAGE_FILE_LARGE_ADDRESS_AWARE;
-	seek_to_pe_header(&mut file)?;
-	write_image_file_header(&mut file, &file_header)?;
-	Ok(())
+	seek_to_pe_header(file, pe_offset)?;
+	write_image_file_header(file, &file_header)
+}
+
+fn make_laa(path: &Path, pe_offset: Option<u64>) -> Result<()> {
+	hash_cache::invalidate(path);
+	let mut file = File::options().read(true).write(true).open(path)?;
+	if file.metadata()?.permissions().readonly() {
+		return Err(anyhow!(
+			"Couldn't write IMAGE_FILE_HEADER: File is readonly"
+		));
+	}
+	set_laa_flag(&mut file, pe_offset)
+}
+
+/// A byte the 4GB patch would change, `field` is the `IMAGE_FILE_HEADER` field it is part of
+pub struct ByteChange {
+	pub offset: u64,
+	pub old: u8,
+	pub new: u8,
+	pub field: &'static str,
+}
+
+/// Applies the 4GB patch to a copy of the exe in memory and compares it to the file on disk,
+/// without modifying it
+pub fn laa_diff(path: &Path, pe_offset: Option<u64>) -> Result<Vec<ByteChange>> {
+	let original = std::fs::read(path)?;
+	let mut patched = Cursor::new(original.clone());
+	seek_to_pe_header(&mut patched, pe_offset)?;
+	let header_offset = patched.stream_position()? + size_of::<u32>() as u64;
+	set_laa_flag(&mut patched, pe_offset)?;
+	Ok(original
+		.iter()
+		.zip(patched.get_ref())
+		.enumerate()
+		.filter(|(_, (old, new))| old != new)
+		.map(|(offset, (old, new))| {
+			let offset = offset as u64;
+			ByteChange {
+				offset,
+				old: *old,
+				new: *new,
+				field: offset
+					.checked_sub(header_offset)
+					.and_then(|relative| ImageFileHeader::field_at(relative as usize))
+					.unwrap_or("unknown"),
+			}
+		})
+		.collect())
 }
 
-pub fn is_laa(path: &Path) -> Result<bool> {
+/// Checks that the headers and sections of a PE file lie within the file, so that a truncated
+/// or corrupt exe is caught before it's patched. Returns the number of sections.
+fn verify_pe(path: &Path, pe_offset: Option<u64>) -> Result<u16> {
 	let mut file = File::open(path)?;
-	seek_to_pe_header(&mut file)?;
+	let len = file.metadata()?.len();
+	seek_to_pe_header(&mut file, pe_offset)?;
 	let file_header = read_image_file_header(&mut file)?;
+	let optional_header_offset = file.stream_position()?;
+	let mut magic = [0u8; 2];
+	file.read_exact(&mut magic)
+		.context("The optional header is missing")?;
+	if !matches!(
+		u16::from_le_bytes(magic),
+		IMAGE_NT_OPTIONAL_HDR32_MAGIC | IMAGE_NT_OPTIONAL_HDR64_MAGIC
+	) {
+		return Err(anyhow!(
+			"Invalid optional header magic number: {:02X?}",
+			magic
+		));
+	}
+	let section_count = file_header.number_of_sections;
+	if section_count == 0 {
+		return Err(anyhow!("The exe has no sections"));
+	}
+	let section_table_offset =
+		optional_header_offset + u64::from(file_header.size_of_optional_header);
+	let section_table_end =
+		section_table_offset + u64::from(section_count) * IMAGE_SECTION_HEADER_SIZE;
+	if section_table_end > len {
+		return Err(anyhow!(
+			"The section table ends at {:#x}, past the end of the file at {:#x}",
+			section_table_end,
+			len
+		));
+	}
+	file.seek(SeekFrom::Start(section_table_offset))?;
+	for _ in 0..section_count {
+		let mut section = [0u8; IMAGE_SECTION_HEADER_SIZE as usize];
+		file.read_exact(&mut section)?;
+		let field = |offset: usize| {
+			u64::from(u32::from_le_bytes(
+				section[offset..offset + 4].try_into().unwrap(),
+			))
+		};
+		let (size_of_raw_data, pointer_to_raw_data) = (field(16), field(20));
+		if pointer_to_raw_data + size_of_raw_data > len {
+			return Err(anyhow!(
+				"Section {:?} ends at {:#x}, past the end of the file at {:#x}",
+				String::from_utf8_lossy(&section[..8]).trim_end_matches('\0'),
+				pointer_to_raw_data + size_of_raw_data,
+				len
+			));
+		}
+	}
+	Ok(section_count)
+}
+
+/// Offset of `CheckSum` in the optional header, the same for PE32 and PE32+
+const OPTIONAL_HEADER_CHECKSUM_OFFSET: u64 = 64;
+
+/// The `CheckSum` stored in the optional header and the one computed over the file
+pub struct PeChecksum {
+	pub stored: u32,
+	pub computed: u32,
+	offset: u64,
+}
+
+impl PeChecksum {
+	pub fn matches(&self) -> bool {
+		self.stored == self.computed
+	}
+}
+
+/// The checksum of `imagehlp`'s `CheckSumMappedFile`: the file's 16-bit words summed with the
+/// carries folded back in, with the stored checksum counted as zero, plus the file's length
+fn compute_pe_checksum(bytes: &[u8], checksum_offset: usize) -> u32 {
+	let mut sum: u64 = 0;
+	for (i, word) in bytes.chunks(2).enumerate() {
+		if (checksum_offset..checksum_offset + 4).contains(&(i * 2)) {
+			continue;
+		}
+		let word = match word {
+			[low, high] => u16::from_le_bytes([*low, *high]),
+			[low] => u16::from(*low),
+			_ => unreachable!(),
+		};
+		sum += u64::from(word);
+		sum = (sum & 0xFFFF) + (sum >> 16);
+	}
+	sum = (sum & 0xFFFF) + (sum >> 16);
+	(sum as u32).wrapping_add(bytes.len() as u32)
+}
+
+fn read_pe_checksum(bytes: &[u8]) -> Result<PeChecksum> {
+	let mut file = Cursor::new(bytes);
+	seek_to_pe_header(&mut file, None)?;
+	read_image_file_header(&mut file)?;
+	let offset = file.stream_position()? + OPTIONAL_HEADER_CHECKSUM_OFFSET;
+	file.seek(SeekFrom::Start(offset))?;
+	let mut stored = [0u8; 4];
+	file.read_exact(&mut stored)
+		.context("The optional header is too short to hold a checksum")?;
+	Ok(PeChecksum {
+		stored: u32::from_le_bytes(stored),
+		computed: compute_pe_checksum(bytes, offset as usize),
+		offset,
+	})
+}
+
+/// Reads the stored checksum of a PE file and computes the correct one, without modifying it
+pub fn pe_checksum(path: &Path) -> Result<PeChecksum> {
+	read_pe_checksum(&std::fs::read(path)?)
+}
+
+/// Writes the correct checksum into the optional header of a PE file after backing it up like
+/// `patch_exe` does, returns the checksums from before it was fixed
+pub fn fix_pe_checksum(path: &Path, options: &PatchOptions) -> Result<PeChecksum> {
+	let checksum = pe_checksum(path)?;
+	if checksum.matches() {
+		return Ok(checksum);
+	}
+	if std::fs::metadata(path)?.permissions().readonly() {
+		return Err(anyhow!("Couldn't write the checksum: File is readonly"));
+	}
+	make_backup(path, CHECKSUM_BACKUP_EXTENSION, options)?;
+	hash_cache::invalidate(path);
+	let mut file = File::options().read(true).write(true).open(path)?;
+	file.seek(SeekFrom::Start(checksum.offset))?;
+	file.write_all(&checksum.computed.to_le_bytes())
+		.context("Couldn't write the checksum")?;
+	Ok(checksum)
+}
+
+fn is_laa_reader<R: Read + Seek>(file: &mut R, pe_offset: Option<u64>) -> Result<bool> {
+	seek_to_pe_header(file, pe_offset)?;
+	let file_header = read_image_file_header(file)?;
 	Ok(file_header.characteristics & IMAGE_FILE_LARGE_ADDRESS_AWARE != 0)
 }
 
-fn sha_hash_path(path: &Path) -> Result<Vec<u8>> {
+/// `pe_offset` is the offset forced by --pe-offset, if any
+pub fn is_laa(path: &Path, pe_offset: Option<u64>) -> Result<bool> {
+	is_laa_reader(&mut File::open(path)?, pe_offset)
+}
+
+/// Warns that --pe-offset bypasses the DOS header, if it was given
+pub fn warn_forced_pe_offset(pe_offset: Option<u64>) {
+	if let Some(pe_offset) = pe_offset {
+		println!(
+			"  Warning: reading the PE header at offset {:#x} as forced by --pe-offset, \
+			bypassing the DOS header and its safety checks",
+			pe_offset
+		);
+	}
+}
+
+/// Digests available for hashing files, version detection itself always uses SHA-256
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum HashAlgorithm {
+	#[default]
+	Sha256,
+	Sha1,
+	Md5,
+}
+
+impl std::fmt::Display for HashAlgorithm {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			HashAlgorithm::Sha256 => write!(f, "SHA-256"),
+			HashAlgorithm::Sha1 => write!(f, "SHA-1"),
+			HashAlgorithm::Md5 => write!(f, "MD5"),
+		}
+	}
+}
+
+fn digest_path<D: Digest + Write>(path: &Path) -> Result<Vec<u8>> {
 	let mut file = File::open(path)?;
-	let mut hasher = Sha256::new();
+	let mut hasher = D::new();
 	std::io::copy(&mut file, &mut hasher)?;
 	Ok(hasher.finalize().to_vec())
 }
 
-fn make_backup(path: &Path, backup_extension: &str) -> Result<()> {
-	let backup_path = format!(
-		"{}.{}",
-		path.to_str()
-			.with_context(|| format!("Couldn't parse file path {:?}", path))?,
-		backup_extension
+pub fn hash_path(path: &Path, algorithm: HashAlgorithm) -> Result<Vec<u8>> {
+	match algorithm {
+		HashAlgorithm::Sha256 => digest_path::<Sha256>(path),
+		HashAlgorithm::Sha1 => digest_path::<sha1::Sha1>(path),
+		HashAlgorithm::Md5 => digest_path::<md5::Md5>(path),
+	}
+}
+
+pub fn sha_hash_path(path: &Path) -> Result<Vec<u8>> {
+	hash_path(path, HashAlgorithm::Sha256)
+}
+
+/// Extensions of the backups `patch_exe` and `fix_pe_checksum` make, appended to the exe's file name
+pub const BACKUP_EXTENSIONS: [&str; 4] = [
+	"steam_backup",
+	"steamless_backup",
+	"gog_backup",
+	CHECKSUM_BACKUP_EXTENSION,
+];
+/// `fix_pe_checksum` may change any exe, so its backups aren't named after the exe's version
+const CHECKSUM_BACKUP_EXTENSION: &str = "checksum_backup";
+/// Appended to the backup's name when it's stored compressed
+const COMPRESSED_BACKUP_EXTENSION: &str = "bak.zip";
+
+/// How `patch_exe` patches the exe
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PatchOptions {
+	/// Patch the Steam version even though its DRM is intact
+	pub skip_steam_drm: bool,
+	/// Kind an unknown exe is patched as, instead of refusing to patch it
+	pub force_unknown_as: Option<ForcedVersion>,
+	/// Store the backup as a zip containing the exe instead of a plain copy
+	pub backup_compress: bool,
+	/// Offset of the PE header, for exes where it can't be found through the DOS header
+	pub pe_offset: Option<u64>,
+	/// Refuse to patch unless a backup of a known unpatched version exists or was just made
+	pub require_clean_backup: bool,
+	/// How many backups of each kind to keep, including older ones that were moved aside
+	pub backup_retention: Option<usize>,
+	/// Read the backup back after making it and refuse to patch unless it matches the exe
+	pub strict_backup_verify: bool,
+}
+
+fn backup_path(path: &Path, backup_extension: &str, compressed: bool) -> Result<PathBuf> {
+	let path = path
+		.to_str()
+		.with_context(|| format!("Couldn't parse file path {:?}", path))?;
+	Ok(PathBuf::from(match compressed {
+		true => format!(
+			"{}.{}.{}",
+			path, backup_extension, COMPRESSED_BACKUP_EXTENSION
+		),
+		false => format!("{}.{}", path, backup_extension),
+	}))
+}
+
+fn make_compressed_backup(path: &Path, backup_path: &Path) -> Result<()> {
+	let mut zip = zip::ZipWriter::new(AtomicFile::create(backup_path)?);
+	let file_name = path
+		.file_name()
+		.context("Couldn't get exe file name")?
+		.to_string_lossy();
+	zip.start_file(
+		file_name,
+		zip::write::SimpleFileOptions::default()
+			.compression_method(zip::CompressionMethod::Deflated),
+	)?;
+	std::io::copy(&mut File::open(path)?, &mut zip)?;
+	zip.finish()?.commit()?;
+
+	let size = std::fs::metadata(path)?.len();
+	let compressed_size = std::fs::metadata(backup_path)?.len();
+	println!(
+		"  Compressed backup {:?}: {} -> {} bytes, saved {}%",
+		backup_path,
+		size,
+		compressed_size,
+		(size.saturating_sub(compressed_size) * 100)
+			.checked_div(size)
+			.unwrap_or(0)
+	);
+	Ok(())
+}
+
+/// `YYYYMMDD-HHMMSS` in UTC
+fn format_timestamp(time: SystemTime) -> String {
+	let secs = time
+		.duration_since(UNIX_EPOCH)
+		.map(|duration| duration.as_secs())
+		.unwrap_or_default();
+	let (days, secs_of_day) = (secs / 86400, secs % 86400);
+	// Civil date from days since 1970-01-01, see http://howardhinnant.github.io/date_algorithms.html
+	let z = days as i64 + 719468;
+	let era = z.div_euclid(146097);
+	let day_of_era = z.rem_euclid(146097);
+	let year_of_era =
+		(day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+	let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+	let mp = (5 * day_of_year + 2) / 153;
+	let day = day_of_year - (153 * mp + 2) / 5 + 1;
+	let month = if mp < 10 { mp + 3 } else { mp - 9 };
+	let year = year_of_era + era * 400 + i64::from(month <= 2);
+	format!(
+		"{:04}{:02}{:02}-{:02}{:02}{:02}",
+		year,
+		month,
+		day,
+		secs_of_day / 3600,
+		secs_of_day / 60 % 60,
+		secs_of_day % 60
+	)
+}
+
+/// Moves an existing backup that differs from the exe aside, named after when it was made, so
+/// that making a new backup never loses an older one
+fn archive_previous_backup(path: &Path, backup_extension: &str, compressed: bool) -> Result<()> {
+	let current = backup_path(path, backup_extension, compressed)?;
+	if !current.is_file() || Backup::open(current.clone())?.read()? == std::fs::read(path)? {
+		return Ok(());
+	}
+	let timestamp = format_timestamp(std::fs::metadata(&current)?.modified()?);
+	let mut archived = backup_path(
+		path,
+		&format!("{}.{}", backup_extension, timestamp),
+		compressed,
+	)?;
+	for n in 2.. {
+		if !archived.exists() {
+			break;
+		}
+		archived = backup_path(
+			path,
+			&format!("{}.{}-{}", backup_extension, timestamp, n),
+			compressed,
+		)?;
+	}
+	std::fs::rename(&current, &archived)
+		.with_context(|| format!("Couldn't move the previous backup {:?} aside", current))?;
+	println!("  Kept the previous backup as {:?}", archived);
+	Ok(())
+}
+
+/// Backups that `archive_previous_backup` moved aside, with their timestamp, newest first
+fn archived_backups(exe_path: &Path, backup_extension: &str) -> Result<Vec<(String, Backup)>> {
+	let exe_name = exe_path
+		.file_name()
+		.context("Couldn't get exe file name")?
+		.to_string_lossy();
+	let prefix = format!("{}.{}.", exe_name, backup_extension);
+	let compressed_suffix = format!(".{}", COMPRESSED_BACKUP_EXTENSION);
+	let dir = exe_path.parent().unwrap_or(Path::new("."));
+	let mut backups = Vec::new();
+	for entry in std::fs::read_dir(dir)? {
+		let entry = entry?;
+		let file_name = entry.file_name().to_string_lossy().into_owned();
+		let Some(rest) = file_name.strip_prefix(&prefix) else {
+			continue;
+		};
+		let timestamp = rest.strip_suffix(&compressed_suffix).unwrap_or(rest);
+		if timestamp.starts_with(|c: char| c.is_ascii_digit())
+			&& timestamp.chars().all(|c| c.is_ascii_digit() || c == '-')
+		{
+			backups.push((timestamp.to_owned(), Backup::open(entry.path())?));
+		}
+	}
+	backups.sort_by(|(a, _), (b, _)| b.cmp(a));
+	Ok(backups)
+}
+
+/// Backups of every kind that were moved aside by newer ones, newest first per kind
+pub fn find_archived_backups(exe_path: &Path) -> Result<Vec<Backup>> {
+	let mut backups = Vec::new();
+	for backup_extension in BACKUP_EXTENSIONS {
+		backups.extend(
+			archived_backups(exe_path, backup_extension)?
+				.into_iter()
+				.map(|(_, backup)| backup),
+		);
+	}
+	Ok(backups)
+}
+
+/// Deletes all but the newest `retention` backups of one kind, plain and compressed, by
+/// modification time. `just_made` is always kept, as is the oldest clean backup, the true
+/// original, even if they're older than that.
+fn prune_backups(
+	exe_path: &Path,
+	backup_extension: &str,
+	retention: usize,
+	just_made: &Path,
+) -> Result<()> {
+	let mut backups = Vec::new();
+	for compressed in [false, true] {
+		let current = backup_path(exe_path, backup_extension, compressed)?;
+		if current.is_file() {
+			backups.push(Backup::open(current)?);
+		}
+	}
+	backups.extend(
+		archived_backups(exe_path, backup_extension)?
+			.into_iter()
+			.map(|(_, backup)| backup),
 	);
-	std::fs::copy(path, backup_path).with_context(move || {
+	// Newest first, the backup that was just made goes first among equal times
+	backups.sort_by_key(|backup| {
+		(
+			std::cmp::Reverse(backup.modified()),
+			backup.path != just_made,
+		)
+	});
+	let oldest_clean = backups
+		.iter()
+		.filter(|backup| backup.is_valid())
+		.min_by_key(|backup| backup.modified())
+		.map(|backup| backup.path.clone());
+	for (i, backup) in backups.iter().enumerate() {
+		if i < retention || backup.path == just_made || Some(&backup.path) == oldest_clean.as_ref()
+		{
+			continue;
+		}
+		std::fs::remove_file(&backup.path)
+			.with_context(|| format!("Couldn't remove old backup {:?}", backup.path))?;
+		println!(
+			"  Removed old backup {:?} ({}), keeping the newest {}",
+			backup.path, backup.version, retention
+		);
+	}
+	Ok(())
+}
+
+/// With `backup_retention`, older backups of the same kind beyond that many are deleted
+/// afterwards. With `strict_backup_verify`, the backup is read back and compared to the exe.
+fn make_backup(path: &Path, backup_extension: &str, options: &PatchOptions) -> Result<()> {
+	let compress = options.backup_compress;
+	archive_previous_backup(path, backup_extension, compress)?;
+	let backup_path = backup_path(path, backup_extension, compress)?;
+	let result = match compress {
+		true => make_compressed_backup(path, &backup_path),
+		false => atomic_file::copy(path, &backup_path),
+	};
+	result.with_context(move || {
 		format!(
 			"Failed to create backup of file {:?} with extension {}",
 			path, backup_extension
 		)
 	})?;
+	if options.strict_backup_verify {
+		verify_backup(path, &backup_path)?;
+	}
+	if let Some(retention) = options.backup_retention {
+		prune_backups(path, backup_extension, retention, &backup_path)?;
+	}
+	Ok(())
+}
+
+/// Re-reads a backup that was just made and checks that it has the same hash as the exe, so
+/// that the exe is only modified if a backup of it is known to be readable
+fn verify_backup(path: &Path, backup_path: &Path) -> Result<()> {
+	let expected = sha_hash_path(path)?;
+	let actual = Backup::open(backup_path.to_path_buf())
+		.and_then(|backup| backup.read())
+		.map(|contents| Sha256::digest(contents).to_vec())
+		.with_context(|| {
+			format!(
+				"Refusing to patch: couldn't read back the backup {:?}",
+				backup_path
+			)
+		})?;
+	if actual != expected {
+		return Err(anyhow!(
+			"Refusing to patch: the backup {:?} doesn't match the exe (SHA-256 {} instead of {}), \
+			it may have been written to a full or failing disk",
+			backup_path,
+			const_hex::encode(actual),
+			const_hex::encode(expected)
+		));
+	}
+	println!("  Verified the backup {:?}", backup_path);
 	Ok(())
 }
 
+/// Makes sure a backup of a known unpatched version exists, so that the exe can always be
+/// restored to a clean state. An existing clean backup is kept rather than overwritten, and a
+/// backup that turns out not to be clean is deleted again.
+fn make_clean_backup(path: &Path, backup_extension: &str, options: &PatchOptions) -> Result<()> {
+	if let Some(clean) = find_valid_backups(path)?.into_iter().next() {
+		println!(
+			"  Keeping the clean {} backup {:?}",
+			clean.version, clean.path
+		);
+		return Ok(());
+	}
+	make_backup(path, backup_extension, options)?;
+	let backup = Backup::open(backup_path(
+		path,
+		backup_extension,
+		options.backup_compress,
+	)?)?;
+	if !backup.is_valid() {
+		std::fs::remove_file(&backup.path)
+			.with_context(|| format!("Couldn't remove the backup {:?}", backup.path))?;
+		return Err(anyhow!(
+			"Refusing to patch: the backup {:?} is not a known unpatched version, so the exe \
+			couldn't be restored to a clean state, and was deleted again. Patching without a \
+			clean backup requires turning off --require-clean-backup (and require_clean_backup \
+			in the config).",
+			backup.path
+		));
+	}
+	Ok(())
+}
+
+fn backup_and_patch(exe_path: &Path, backup_extension: &str, options: &PatchOptions) -> Result<()> {
+	match options.require_clean_backup {
+		true => make_clean_backup(exe_path, backup_extension, options)?,
+		false => make_backup(exe_path, backup_extension, options)?,
+	}
+	make_laa(exe_path, options.pe_offset).context("Failed to apply 4GB Patch")
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum GameVersion {
 	Steam,
@@ -148,57 +700,406 @@ impl std::fmt::Display for GameVersion {
 	}
 }
 
+/// A backup of the exe made by `patch_exe`
+pub struct Backup {
+	pub path: PathBuf,
+	pub version: GameVersion,
+	pub compressed: bool,
+}
+
+impl Backup {
+	pub fn open(path: PathBuf) -> Result<Self> {
+		let compressed = path
+			.to_string_lossy()
+			.ends_with(&format!(".{}", COMPRESSED_BACKUP_EXTENSION));
+		let mut backup = Self {
+			path,
+			version: GameVersion::Unknown,
+			compressed,
+		};
+		let contents = backup.read()?;
+		let hash = Sha256::digest(&contents);
+		backup.version =
+			classify_hash(&hash, || is_laa_reader(&mut Cursor::new(&contents), None))?.0;
+		Ok(backup)
+	}
+
+	/// The backed up exe, decompressed if needed
+	pub fn read(&self) -> Result<Vec<u8>> {
+		if !self.compressed {
+			return std::fs::read(&self.path)
+				.with_context(|| format!("Couldn't read backup {:?}", self.path));
+		}
+		let mut zip = zip::ZipArchive::new(File::open(&self.path)?)
+			.with_context(|| format!("Couldn't read compressed backup {:?}", self.path))?;
+		let mut file = zip.by_index(0)?;
+		let mut contents = Vec::with_capacity(file.size() as usize);
+		file.read_to_end(&mut contents)?;
+		Ok(contents)
+	}
+
+	pub fn is_valid(&self) -> bool {
+		!matches!(
+			self.version,
+			GameVersion::AlreadyPatched | GameVersion::Unknown
+		)
+	}
+
+	/// When the backup file was last modified, `None` if that can't be read
+	pub fn modified(&self) -> Option<SystemTime> {
+		std::fs::metadata(&self.path)
+			.and_then(|metadata| metadata.modified())
+			.ok()
+	}
+
+	/// Overwrites the exe with the backed up one
+	pub fn restore(&self, exe_path: &Path) -> Result<()> {
+		hash_cache::invalidate(exe_path);
+		atomic_file::write(exe_path, self.read()?)
+			.with_context(|| format!("Couldn't restore {:?} from {:?}", exe_path, self.path))
+	}
+}
+
+/// Backups of the exe that exist, plain and compressed, with the version detected for each
+pub fn find_backups(exe_path: &Path) -> Result<Vec<Backup>> {
+	let mut backups = Vec::new();
+	for backup_extension in BACKUP_EXTENSIONS {
+		for compressed in [false, true] {
+			let backup_path = backup_path(exe_path, backup_extension, compressed)?;
+			if backup_path.is_file() {
+				backups.push(Backup::open(backup_path)?);
+			}
+		}
+	}
+	Ok(backups)
+}
+
+/// Backups of the exe that are a known unpatched version
+pub fn find_valid_backups(exe_path: &Path) -> Result<Vec<Backup>> {
+	let mut backups = find_backups(exe_path)?;
+	backups.retain(Backup::is_valid);
+	Ok(backups)
+}
+
+/// Kind of exe an unknown exe can be treated as, for installs with a modified exe
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ForcedVersion {
+	Steam,
+	Steamless,
+	Gog,
+}
+
+impl ForcedVersion {
+	pub fn version(self) -> GameVersion {
+		match self {
+			ForcedVersion::Steam => GameVersion::Steam,
+			ForcedVersion::Steamless => GameVersion::Steamless,
+			ForcedVersion::Gog => GameVersion::Gog,
+		}
+	}
+}
+
 pub fn detect_version(exe_path: &Path) -> Result<GameVersion> {
-	let hash = sha_hash_path(exe_path)?;
-	if get_hash_set_from_str(STEAM_HASH_STR).contains(&hash) {
-		Ok(GameVersion::Steam)
-	} else if get_hash_set_from_str(STEAMLESS_HASH_STR).contains(&hash) {
-		Ok(GameVersion::Steamless)
-	} else if get_hash_set_from_str(GOG_HASH_STR).contains(&hash) {
-		Ok(GameVersion::Gog)
-	} else if is_laa(exe_path)? {
-		Ok(GameVersion::AlreadyPatched)
+	detect_version_explained(exe_path).map(|(version, _)| version)
+}
+
+/// Same as `detect_version`, but also returns a human readable trace of each
+/// check that was made on the way to the result.
+pub fn detect_version_explained(exe_path: &Path) -> Result<(GameVersion, Vec<String>)> {
+	detect_version_from_hash(exe_path, &sha_hash_path(exe_path)?, None)
+}
+
+/// Same as `detect_version_explained`, for an exe whose hash is already known and whose PE
+/// header may be at an offset forced by --pe-offset
+pub fn detect_version_from_hash(
+	exe_path: &Path,
+	hash: &[u8],
+	pe_offset: Option<u64>,
+) -> Result<(GameVersion, Vec<String>)> {
+	classify_hash(hash, || is_laa(exe_path, pe_offset))
+}
+
+/// Looks the hash up in the known hash sets, falling back to whether the LAA bit is set
+fn classify_hash(
+	hash: &[u8],
+	is_laa: impl FnOnce() -> Result<bool>,
+) -> Result<(GameVersion, Vec<String>)> {
+	let mut trace = vec![format!("computed hash {}", const_hex::encode(hash))];
+	let hash_sets = [
+		(STEAM_HASH_STR, "steam", GameVersion::Steam),
+		(STEAMLESS_HASH_STR, "steamless", GameVersion::Steamless),
+		(GOG_HASH_STR, "gog", GameVersion::Gog),
+	];
+	for (hash_str, set_name, version) in hash_sets {
+		if get_hash_set_from_str(hash_str).contains(hash) {
+			trace.push(format!("matched {} set -> {}", set_name, version));
+			return Ok((version, trace));
+		}
+		trace.push(format!("not in {} set", set_name));
+	}
+	if is_laa()? {
+		trace.push(format!(
+			"matched no set; LAA bit is set -> {}",
+			GameVersion::AlreadyPatched
+		));
+		Ok((GameVersion::AlreadyPatched, trace))
+	} else {
+		trace.push(format!(
+			"matched no set; LAA bit is not set -> {}",
+			GameVersion::Unknown
+		));
+		Ok((GameVersion::Unknown, trace))
+	}
+}
+
+/// Maps exe hashes to the Battle Brothers version they belong to, such as `1.5.1.12`
+pub struct GameVersionDb {
+	versions: HashMap<Vec<u8>, String>,
+}
+
+impl GameVersionDb {
+	fn parse(source: &str, name: &str) -> Result<HashMap<Vec<u8>, String>> {
+		let table: HashMap<String, String> = toml::from_str(source)
+			.with_context(|| format!("Couldn't parse game version database {}", name))?;
+		table
+			.into_iter()
+			.map(|(hash, version)| {
+				let decoded = const_hex::decode(&hash)
+					.ok()
+					.filter(|decoded| decoded.len() == Sha256::output_size())
+					.ok_or_else(|| {
+						anyhow!(
+							"{} maps {:?}, which is not a SHA-256 hash, to a game version",
+							name,
+							hash
+						)
+					})?;
+				Ok((decoded, version))
+			})
+			.collect()
+	}
+
+	/// The versions shipped with the patcher, from `hashes/versions.toml`
+	pub fn embedded() -> Self {
+		Self {
+			versions: Self::parse(GAME_VERSIONS_STR, "hashes/versions.toml").unwrap(),
+		}
+	}
+
+	/// The embedded versions, with the ones in `path` added on top
+	pub fn with_file(path: &Path) -> Result<Self> {
+		let source = std::fs::read_to_string(path)
+			.with_context(|| format!("Couldn't read game version database {:?}", path))?;
+		let mut db = Self::embedded();
+		db.versions
+			.extend(Self::parse(&source, &format!("{:?}", path))?);
+		Ok(db)
+	}
+
+	pub fn lookup(&self, hash: &[u8]) -> Option<&str> {
+		self.versions.get(hash).map(String::as_str)
+	}
+}
+
+/// Finds the known hash closest to `hash` by edit distance over their hex digits, if it is close
+/// enough to be a likely near miss (such as a mistyped hash) rather than an unrelated one
+fn find_near_miss_hash(hash: &[u8]) -> Option<(GameVersion, usize)> {
+	let hash = const_hex::encode(hash);
+	[
+		(STEAM_HASH_STR, GameVersion::Steam),
+		(STEAMLESS_HASH_STR, GameVersion::Steamless),
+		(GOG_HASH_STR, GameVersion::Gog),
+	]
+	.into_iter()
+	.flat_map(|(hash_str, version)| {
+		hash_str
+			.lines()
+			.filter(|line| !line.is_empty())
+			.map(move |line| (version, line))
+	})
+	.map(|(version, known)| (version, edit_distance(&hash, &known.to_ascii_lowercase())))
+	.filter(|(_, distance)| (1..=NEAR_MISS_MAX_DISTANCE).contains(distance))
+	.min_by_key(|(_, distance)| *distance)
+}
+
+/// Builds the message shown for an unknown exe, ready to be pasted into a GitHub issue
+pub fn unknown_version_report(exe_path: &Path, hash: &[u8]) -> Result<String> {
+	let size = std::fs::metadata(exe_path)?.len();
+	let mut report = String::new();
+	if let Some((version, distance)) = find_near_miss_hash(hash) {
+		report.push_str(&format!(
+			"Note: this hash is only {} hex digit(s) away from a known {} hash.\n\
+			Did the file get partially modified, or was the hash copied incorrectly?\n",
+			distance, version
+		));
+	}
+	report.push_str(&format!(
+		"If this is a new version, please report it on GitHub with the block below:\n\
+		---- Battle Brothers version report ----\n\
+		SHA-256: {}\n\
+		Size: {} bytes\n\
+		----------------------------------------",
+		const_hex::encode(hash),
+		size
+	));
+	Ok(report)
+}
+
+fn percent_encode(s: &str) -> String {
+	s.bytes()
+		.map(|b| match b {
+			b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+				(b as char).to_string()
+			}
+			_ => format!("%{:02X}", b),
+		})
+		.collect()
+}
+
+/// Guesses where the exe is from by its path, as a hint for whoever triages the report
+fn guess_platform(exe_path: &Path) -> String {
+	let path = exe_path.to_string_lossy().to_lowercase();
+	let store = if path.contains("steamapps") {
+		"Steam"
+	} else if path.contains("gog") {
+		"GOG"
 	} else {
-		Ok(GameVersion::Unknown)
+		"unknown store"
+	};
+	format!("{} on {}", store, std::env::consts::OS)
+}
+
+/// Link to a new GitHub issue, pre-filled with the version report of an unknown exe. Opening
+/// it only fills in the form, the issue is submitted by whoever opened it.
+pub fn unknown_version_issue_url(exe_path: &Path, hash: &[u8]) -> Result<String> {
+	let body = format!(
+		"---- Battle Brothers version report ----\n\
+		SHA-256: {}\n\
+		Size: {} bytes\n\
+		Platform: {}\n\
+		Patcher version: {}\n\
+		----------------------------------------\n",
+		const_hex::encode(hash),
+		std::fs::metadata(exe_path)?.len(),
+		guess_platform(exe_path),
+		env!("CARGO_PKG_VERSION")
+	);
+	Ok(format!(
+		"{}?title={}&body={}",
+		NEW_ISSUE_URL,
+		percent_encode(&format!(
+			"Unknown Battle Brothers exe {}",
+			&const_hex::encode(hash)[..ISSUE_TITLE_HASH_LEN]
+		)),
+		percent_encode(&body)
+	))
+}
+
+pub struct PatchOutcome {
+	pub message: String,
+	/// Whether the exe was already patched, so that nothing changed
+	pub already_patched: bool,
+}
+
+impl PatchOutcome {
+	fn patched(message: &str) -> Self {
+		Self {
+			message: message.to_owned(),
+			already_patched: false,
+		}
 	}
 }
 
-pub fn patch_exe(exe_path: &Path, skip_steam_drm: bool) -> Result<String> {
-	let version = detect_version(exe_path)?;
+impl std::fmt::Display for PatchOutcome {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "{}", self.message)
+	}
+}
+
+/// The version the exe is patched as, or the reason it must not be patched. `force_unknown_as` is the kind an unknown exe is patched as, instead of
+/// refusing to patch it.
+fn patchable_version(exe_path: &Path, hash: &[u8], options: &PatchOptions) -> Result<GameVersion> {
+	let version = classify_hash(hash, || {
+		is_laa_reader(&mut File::open(exe_path)?, options.pe_offset)
+	})?
+	.0;
+	let forced = matches!(
+		(version, options.force_unknown_as),
+		(GameVersion::Unknown, Some(_))
+	);
+	let version = match (version, options.force_unknown_as) {
+		(GameVersion::Unknown, Some(forced)) => {
+			println!(
+				"  Unknown exe, treating it as {} as set by force_unknown_as in the config",
+				forced.version()
+			);
+			forced.version()
+		}
+		(version, _) => version,
+	};
+	match version {
+		GameVersion::Steam if !options.skip_steam_drm => Err(anyhow!(
+			"Steam version detected. Steam DRM removal requires running Steamless.CLI.exe on Windows.\n\
+			Options:\n\
+			1. Run Steamless manually on Windows first, then use this tool\n\
+			2. Use --skip-steam-drm to patch anyway (may not work correctly)\n\
+			3. Use the GOG version which doesn't have DRM"
+		)),
+		GameVersion::Unknown => Err(anyhow!(
+			"Unknown version of Battle Brothers.\n{}",
+			unknown_version_report(exe_path, hash)?
+		)),
+		GameVersion::Steamless => {
+			let section_count = verify_pe(exe_path, options.pe_offset).context(
+				"The exe isn't a valid PE, Steamless may have produced a truncated or corrupt \
+				file; restore the original exe and run Steamless again",
+			)?;
+			println!(
+				"  Steamless output is a valid PE with {} section(s), {}",
+				section_count,
+				match forced {
+					true => "patching it as forced by force_unknown_as",
+					false => "detected as the known Steamless version",
+				}
+			);
+			Ok(version)
+		}
+		version => Ok(version),
+	}
+}
+
+/// Refuses the exes `patch_exe` refuses, without changing anything
+pub fn check_patchable(exe_path: &Path, options: &PatchOptions) -> Result<()> {
+	patchable_version(exe_path, &sha_hash_path(exe_path)?, options)?;
+	Ok(())
+}
+
+pub fn patch_exe(exe_path: &Path, options: &PatchOptions) -> Result<PatchOutcome> {
+	warn_forced_pe_offset(options.pe_offset);
+	let hash = sha_hash_path(exe_path)?;
+	let version = patchable_version(exe_path, &hash, options)?;
 	match version {
 		GameVersion::Steam => {
-			if skip_steam_drm {
-				println!("  Steam version detected, but skipping DRM removal as requested");
-				println!("  Note: The 4GB patch may not work correctly without DRM removal");
-				make_backup(exe_path, "steam_backup")?;
-				make_laa(exe_path).context("Failed to apply 4GB Patch")?;
-				Ok("Patched Steam Version (DRM intact - may not work correctly)".to_string())
-			} else {
-				Err(anyhow!(
-					"Steam version detected. Steam DRM removal requires running Steamless.CLI.exe on Windows.\n\
-					Options:\n\
-					1. Run Steamless manually on Windows first, then use this tool\n\
-					2. Use --skip-steam-drm to patch anyway (may not work correctly)\n\
-					3. Use the GOG version which doesn't have DRM"
-				))
-			}
+			println!("  Steam version detected, but skipping DRM removal as requested");
+			println!("  Note: The 4GB patch may not work correctly without DRM removal");
+			backup_and_patch(exe_path, "steam_backup", options)?;
+			Ok(PatchOutcome::patched(
+				"Patched Steam Version (DRM intact - may not work correctly)",
+			))
 		}
 		GameVersion::Steamless => {
-			make_backup(exe_path, "steamless_backup")?;
-			make_laa(exe_path).context("Failed to apply 4GB Patch")?;
-			Ok("Patched Steamless Version".to_string())
+			backup_and_patch(exe_path, "steamless_backup", options)?;
+			Ok(PatchOutcome::patched("Patched Steamless Version"))
 		}
 		GameVersion::Gog => {
-			make_backup(exe_path, "gog_backup")?;
-			make_laa(exe_path).context("Failed to apply 4GB Patch")?;
-			Ok("Patched GOG Version".to_string())
+			backup_and_patch(exe_path, "gog_backup", options)?;
+			Ok(PatchOutcome::patched("Patched GOG Version"))
 		}
-		GameVersion::AlreadyPatched => Ok("Already patched".to_string()),
-		GameVersion::Unknown => Err(anyhow!(
-			"Unknown version of Battle Brothers.\n\
-            Hash: {}\n\
-            If this is a new version, please report it on GitHub.",
-			const_hex::encode(sha_hash_path(exe_path)?)
-		)),
+		GameVersion::AlreadyPatched => Ok(PatchOutcome {
+			message: "Already patched".to_owned(),
+			already_patched: true,
+		}),
+		GameVersion::Unknown => unreachable!("unknown exes are refused by patchable_version"),
 	}
 }
@@ -1,16 +1,24 @@
-use anyhow::{anyhow, Result};
-use std::collections::HashSet;
-use std::io::Write;
+use anyhow::{anyhow, Context, Result};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::io::{Cursor, IsTerminal, Seek, Write};
+use std::path::PathBuf;
+use std::time::{Duration, Instant, SystemTime};
 use std::{fs::File, io::Read, path::Path};
 use zip::ZipArchive;
-use zip::{write::SimpleFileOptions, CompressionMethod};
+use zip::{write::SimpleFileOptions, CompressionMethod, DateTime};
 
+use crate::atomic_file::AtomicFile;
 use crate::config::DataPath;
 
 const TABBED_NEWLINE: &str = "\n\t\t\t";
 
 const ON_RUNNING_PATH: &str = "preload/on_running.txt";
 const ON_START_PATH: &str = "preload/on_start.txt";
+/// Which archive registered each resource, one `resource<TAB>archive` pair per line. Only
+/// written into generated preloads, so that a later merge can tell where its entries came from.
+const SOURCES_PATH: &str = "preload/sources.txt";
 
 const MOD_ID: &str = "mod_msu_launcher";
 const ZIP_NAME: &str = "~mod_msu_launcher.zip";
@@ -18,10 +26,212 @@ const MOD_NAME: &str = "MSU Launcher";
 const MOD_NAMESPACE: &str = "MSULauncher";
 const MOD_STRING: &str = include_str!("../squirrel/mod_msu_launcher.nut");
 const VERSION: &str = env!("CARGO_PKG_VERSION");
+/// Mods known to be broken, see `check_denylist`
+const DENYLIST_STR: &str = include_str!("../denylist.txt");
+
+/// Mod id of the Modding Standards & Utilities framework, which the preload is meant to be used with
+pub const DEFAULT_MSU_ID: &str = "mod_msu";
+
+/// Archive formats that modders sometimes distribute mods in, but which the game can't load
+const UNSUPPORTED_ARCHIVE_MAGICS: [(&str, &[u8]); 2] =
+	[("7z", b"7z\xBC\xAF\x27\x1C"), ("RAR", b"Rar!\x1A\x07")];
+
+/// Resources the game reads as UTF-8 text without a BOM
+const TEXT_RESOURCE_EXTENSIONS: [&str; 7] = ["txt", "csv", "json", "xml", "html", "css", "js"];
+
+/// Top level folders of the game's data, resources are expected to start with one of these
+const GAME_DATA_FOLDERS: [&str; 7] = [
+	"brushes", "gfx", "music", "preload", "scripts", "sounds", "ui",
+];
+
+/// Names Windows reserves for devices, also with any extension, e.g. `nul.txt`
+const WINDOWS_RESERVED_NAMES: [&str; 22] = [
+	"CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+	"COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+const WINDOWS_INVALID_CHARS: [char; 8] = ['<', '>', ':', '"', '\\', '|', '?', '*'];
+
+/// Base game archives whose files the game can't start without when a mod replaces them by accident
+const CRITICAL_BASE_ARCHIVES: [&str; 1] = ["data_001.dat"];
+
+/// Resource paths longer or deeper than this can hit path length limits under WINE and on some
+/// filesystems when the game reads them
+const MAX_RESOURCE_PATH_LEN: usize = 200;
+const MAX_RESOURCE_PATH_DEPTH: usize = 12;
+
+/// How the resources a mod registers are ordered in the preload
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum IntraModSort {
+	/// All resources sorted by name, regardless of the mod registering them
+	#[default]
+	Name,
+	/// Grouped by the first mod registering them, in the order its manifest lists them
+	Declared,
+}
+
+#[derive(Default)]
+pub struct PreloadOptions {
+	/// Record mods that fail to scan and continue instead of aborting
+	pub keep_going: bool,
+	/// Description embedded in the generated mod, an auto-generated one is used if not set
+	pub description: Option<String>,
+	/// Use the modification times of the source manifests for the generated entries instead of
+	/// a fixed timestamp, at the cost of the preload no longer being byte-for-byte reproducible
+	pub preserve_timestamps: bool,
+	/// Check that every registered resource exists in the mod archive registering it
+	pub verify_resources: bool,
+	/// Treat warnings as errors, which prevents the preload from being created
+	pub strict: bool,
+	/// Where to write the preload instead of the data folder, `-` writes it to stdout
+	pub output: Option<PathBuf>,
+	/// Strip up to this many wrapper folders in front of a known game data folder from resource paths
+	pub flatten: Option<usize>,
+	/// Existing preload whose resources are kept, with the scanned resources added on top
+	pub merge: Option<PathBuf>,
+	/// When merging, drop the resources of the existing preload whose source archives are no
+	/// longer in the data folder
+	pub prune: bool,
+	/// Where to write metrics about the scan as JSON
+	pub stats: Option<PathBuf>,
+	/// Mod id MSU is installed under, `DEFAULT_MSU_ID` if not set
+	pub msu_id: Option<String>,
+	/// Where to write the list of resources each mod registered
+	pub inventory: Option<PathBuf>,
+	/// Write the contents of the preload into this folder as plain files, instead of the zip
+	/// unless `output` is also set
+	pub extract_to: Option<PathBuf>,
+	/// File forcing resources into a phase, one `<resource> on_start|on_running` per line
+	pub phase_override: Option<PathBuf>,
+	/// Treat mods overriding files of a critical base game archive as errors
+	pub base_game_check: bool,
+	/// Mods that are meant to override base game files, exempt from `base_game_check`
+	pub allow_override: Vec<String>,
+	/// Scan the mods without writing the preload anywhere
+	pub dry_run: bool,
+	/// Where to write a report of the build, as JSON if the file name ends in `.json`
+	pub report: Option<PathBuf>,
+	/// Embed the ids of the mods registering each resource in the generated mod, so that in-game
+	/// diagnostics can name the mod a broken resource came from
+	pub tag_sources: bool,
+	/// File of known-bad mods, one `<mod_id> <reason>` per line, checked on top of the embedded list
+	pub denylist: Option<PathBuf>,
+	/// Order of the resources of each mod
+	pub intra_mod_sort: IntraModSort,
+	/// Abort before scanning if the data folder has more mod archives than this, as it's likely
+	/// the wrong folder
+	pub max_mods: Option<usize>,
+	/// When merging, only scan the mod archives modified after this, keeping the resources of
+	/// the others from the merged preload
+	pub since: Option<SystemTime>,
+	/// Time the scan of each archive and keep the size of every registered file
+	pub profile_resources: bool,
+}
+
+impl PreloadOptions {
+	pub fn writes_to_stdout(&self) -> bool {
+		self.output.as_deref() == Some(Path::new("-"))
+	}
+
+	/// Human readable name of where the preload is written
+	pub fn output_name(&self) -> String {
+		let zip_name = match &self.output {
+			_ if self.writes_to_stdout() => "stdout".to_owned(),
+			Some(path) => format!("{:?}", path),
+			None => ZIP_NAME.to_owned(),
+		};
+		match (&self.extract_to, &self.output) {
+			(Some(dir), None) => format!("{:?}", dir),
+			(Some(dir), Some(_)) => format!("{} and {:?}", zip_name, dir),
+			(None, _) => zip_name,
+		}
+	}
+
+	pub fn msu_id(&self) -> &str {
+		self.msu_id.as_deref().unwrap_or(DEFAULT_MSU_ID)
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+	Info,
+	Warning,
+	Error,
+}
+
+#[derive(Clone, Serialize)]
+pub struct Finding {
+	pub severity: Severity,
+	pub message: String,
+}
+
+impl Finding {
+	pub fn error(message: String) -> Self {
+		Self {
+			severity: Severity::Error,
+			message,
+		}
+	}
+
+	pub fn info(message: String) -> Self {
+		Self {
+			severity: Severity::Info,
+			message,
+		}
+	}
+
+	pub fn warning(message: String) -> Self {
+		Self {
+			severity: Severity::Warning,
+			message,
+		}
+	}
+}
+
+impl std::fmt::Display for Finding {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self.severity {
+			Severity::Info => write!(f, "Note: {}", self.message),
+			Severity::Warning => write!(f, "Warning: {}", self.message),
+			Severity::Error => write!(f, "Error: {}", self.message),
+		}
+	}
+}
 
 pub struct ResourceGatherer {
 	pub on_running: HashSet<String>,
 	pub on_start: HashSet<String>,
+	pub mods: Vec<String>,
+	/// Archive file names registering each resource
+	pub sources: HashMap<String, BTreeSet<String>>,
+	pub findings: Vec<Finding>,
+	pub failures: Vec<(String, String)>,
+	/// Archives left out of the scan on purpose, with the reason
+	pub skipped: Vec<(String, String)>,
+	pub on_running_modified: Option<DateTime>,
+	pub on_start_modified: Option<DateTime>,
+	/// Uncompressed and compressed size of the files making up the registered resources
+	pub resource_size: u64,
+	pub resource_compressed_size: u64,
+	/// Files in the base game archives, with the archive they are in
+	pub base_files: HashMap<String, String>,
+	/// Archives in the data folder that are symlinks, with their target
+	pub symlinks: Vec<(String, PathBuf)>,
+	/// With `IntraModSort::Declared`, the position of the mod that registered each resource first
+	/// and of the resource in its manifest
+	pub declared_order: Option<HashMap<String, (usize, usize)>>,
+	/// With `profile_resources`, where the scan spent its time
+	pub profile: Option<ScanProfile>,
+}
+
+/// How long the scan took and what it spent it on, to find the mods slowing it down
+#[derive(Default)]
+pub struct ScanProfile {
+	pub total: Duration,
+	/// Each scanned archive with how long scanning it took
+	pub archives: Vec<(String, Duration)>,
+	/// Each registered file with its uncompressed size and the archive it's in
+	pub resources: Vec<(String, u64, String)>,
 }
 
 impl ResourceGatherer {
@@ -29,6 +239,19 @@ impl ResourceGatherer {
 		Self {
 			on_running: HashSet::new(),
 			on_start: HashSet::new(),
+			mods: Vec::new(),
+			sources: HashMap::new(),
+			findings: Vec::new(),
+			failures: Vec::new(),
+			skipped: Vec::new(),
+			on_running_modified: None,
+			on_start_modified: None,
+			resource_size: 0,
+			resource_compressed_size: 0,
+			base_files: HashMap::new(),
+			symlinks: Vec::new(),
+			declared_order: None,
+			profile: None,
 		}
 	}
 }
@@ -42,17 +265,196 @@ impl Default for ResourceGatherer {
 pub struct ResourceHandler {
 	on_running: Vec<String>,
 	on_start: Vec<String>,
+	mods: Vec<String>,
+	sources: Vec<(String, String)>,
+	findings: Vec<Finding>,
+	failures: Vec<(String, String)>,
+	skipped: Vec<(String, String)>,
+	on_running_modified: Option<DateTime>,
+	on_start_modified: Option<DateTime>,
+	resource_size: u64,
+	resource_compressed_size: u64,
+	symlinks: Vec<(String, PathBuf)>,
+	profile: Option<ScanProfile>,
+}
+
+/// Metrics about a scan, meant to be tracked by tools rather than read by people
+#[derive(Serialize)]
+pub struct PreloadStats {
+	pub patcher_version: &'static str,
+	pub mod_count: usize,
+	pub resources_by_phase: BTreeMap<&'static str, usize>,
+	/// Keyed by lowercase file extension, `folder` for resources without one
+	pub resources_by_type: BTreeMap<String, usize>,
+	pub total_size: u64,
+	pub compressed_size: u64,
+	/// Resources registered in both phases
+	pub conflict_count: usize,
+	/// Resources registered by more than one archive
+	pub duplicate_count: usize,
+}
+
+/// Everything that went into a build, or would have for a dry run
+#[derive(Serialize)]
+pub struct PreloadReport<'a> {
+	pub dry_run: bool,
+	pub patcher_version: &'static str,
+	pub output: String,
+	pub mods: &'a [String],
+	pub on_start: &'a [String],
+	pub on_running: &'a [String],
+	pub findings: &'a [Finding],
+	pub failures: Vec<ScanFailure<'a>>,
+}
+
+#[derive(Serialize)]
+pub struct ScanFailure<'a> {
+	pub archive: &'a str,
+	pub reason: &'a str,
+}
+
+impl PreloadReport<'_> {
+	pub fn to_text(&self) -> String {
+		let mut s = String::new();
+		if self.dry_run {
+			s.push_str("DRY RUN: nothing was written, this is what the preload would contain\n");
+		}
+		s.push_str(&format!(
+			"{} {} preload report\n",
+			MOD_NAME, self.patcher_version
+		));
+		s.push_str(&format!("Output: {}\n", self.output));
+		let mut section = |title: &str, lines: &mut dyn Iterator<Item = String>| {
+			let lines: Vec<String> = lines.collect();
+			s.push_str(&format!("\n{} ({}):\n", title, lines.len()));
+			for line in lines {
+				s.push_str(&format!("  {}\n", line));
+			}
+		};
+		section("Mods", &mut self.mods.iter().cloned());
+		section("on_start", &mut self.on_start.iter().cloned());
+		section("on_running", &mut self.on_running.iter().cloned());
+		section(
+			"Findings",
+			&mut self.findings.iter().map(Finding::to_string),
+		);
+		section(
+			"Failed to scan",
+			&mut self
+				.failures
+				.iter()
+				.map(|failure| format!("{}: {}", failure.archive, failure.reason)),
+		);
+		s
+	}
+}
+
+/// Overall result of an analysis, from the most severe finding
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Verdict {
+	Pass,
+	Warn,
+	Fail,
+}
+
+impl std::fmt::Display for Verdict {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Verdict::Pass => write!(f, "PASS"),
+			Verdict::Warn => write!(f, "WARN"),
+			Verdict::Fail => write!(f, "FAIL"),
+		}
+	}
+}
+
+/// Every finding of a scan in one list, most severe first, as reported by `preload --analyze`
+#[derive(Serialize)]
+pub struct Analysis {
+	pub verdict: Verdict,
+	pub mod_count: usize,
+	pub error_count: usize,
+	pub warning_count: usize,
+	pub info_count: usize,
+	pub findings: Vec<Finding>,
+}
+
+/// Where the generated preload sits among the scanned mods in the game's load order
+pub struct LoadOrderPosition<'a> {
+	pub position: usize,
+	pub total: usize,
+	pub after: Option<&'a str>,
+	pub before: Option<&'a str>,
+}
+
+fn ordinal(n: usize) -> String {
+	let suffix = match (n % 10, n % 100) {
+		(_, 11..=13) => "th",
+		(1, _) => "st",
+		(2, _) => "nd",
+		(3, _) => "rd",
+		_ => "th",
+	};
+	format!("{}{}", n, suffix)
+}
+
+impl std::fmt::Display for LoadOrderPosition<'_> {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "loads {} of {}", ordinal(self.position), self.total)?;
+		if let Some(after) = self.after {
+			write!(f, ", after {}", after)?;
+		}
+		if let Some(before) = self.before {
+			write!(f, ", before {}", before)?;
+		}
+		Ok(())
+	}
 }
 
 impl From<ResourceGatherer> for ResourceHandler {
 	fn from(value: ResourceGatherer) -> Self {
-		let mut on_running: Vec<_> = value.on_running.into_iter().collect();
-		on_running.sort();
-		let mut on_start: Vec<_> = value.on_start.into_iter().collect();
-		on_start.sort();
+		// Resources without a declared position, such as merged ones, go last
+		let sort = |resources: HashSet<String>| {
+			let mut resources: Vec<_> = resources.into_iter().collect();
+			match &value.declared_order {
+				Some(order) => resources.sort_by_cached_key(|resource| {
+					(
+						order.get(resource).copied().unwrap_or((usize::MAX, 0)),
+						resource.clone(),
+					)
+				}),
+				None => resources.sort(),
+			}
+			resources
+		};
+		let on_running = sort(value.on_running);
+		let on_start = sort(value.on_start);
+		let mut mods = value.mods;
+		mods.sort();
+		let mut sources: Vec<_> = value
+			.sources
+			.into_iter()
+			.flat_map(|(resource, archives)| {
+				archives
+					.into_iter()
+					.map(move |archive| (resource.clone(), archive))
+			})
+			.collect();
+		sources.sort();
 		Self {
 			on_running,
 			on_start,
+			mods,
+			sources,
+			findings: value.findings,
+			failures: value.failures,
+			skipped: value.skipped,
+			on_running_modified: value.on_running_modified,
+			on_start_modified: value.on_start_modified,
+			resource_size: value.resource_size,
+			resource_compressed_size: value.resource_compressed_size,
+			symlinks: value.symlinks,
+			profile: value.profile,
 		}
 	}
 }
@@ -98,6 +500,42 @@ impl ResourceHandler {
 		ResourceHandler::make_raw_strings(&self.on_start)
 	}
 
+	/// Squirrel table of each resource to the ids of the mods registering it, such as
+	/// `{ ["gfx/foo.png"] = ["mod_foo"] }`
+	pub fn get_sources_quoted(&self) -> String {
+		let mut by_resource: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+		for (resource, archive) in self.sources.iter() {
+			let mod_id = match is_mod_archive(Path::new(archive)) {
+				true => mod_id_from_filename(archive).0,
+				false => archive.as_str(),
+			};
+			by_resource.entry(resource).or_default().push(mod_id);
+		}
+		let mut s = "{".to_owned();
+		if !by_resource.is_empty() {
+			for (resource, mod_ids) in by_resource {
+				let mod_ids: Vec<String> = mod_ids.into_iter().map(quote_squirrel_string).collect();
+				s.push_str(&format!(
+					"{}[{}] = [{}],",
+					TABBED_NEWLINE,
+					quote_squirrel_string(resource),
+					mod_ids.join(", ")
+				));
+			}
+			s.push_str(&TABBED_NEWLINE[0..TABBED_NEWLINE.len() - 1]);
+		}
+		s.push('}');
+		s
+	}
+
+	pub fn get_sources_raw(&self) -> String {
+		let mut s = String::new();
+		for (resource, archive) in self.sources.iter() {
+			s.push_str(&format!("{}\t{}\n", resource, archive));
+		}
+		s
+	}
+
 	pub fn on_running_count(&self) -> usize {
 		self.on_running.len()
 	}
@@ -105,6 +543,239 @@ impl ResourceHandler {
 	pub fn on_start_count(&self) -> usize {
 		self.on_start.len()
 	}
+
+	pub fn mod_count(&self) -> usize {
+		self.mods.len()
+	}
+
+	/// File names of the scanned mod archives, sorted
+	pub fn mods(&self) -> &[String] {
+		&self.mods
+	}
+
+	pub fn findings(&self) -> &[Finding] {
+		&self.findings
+	}
+
+	pub fn error_count(&self) -> usize {
+		self.findings
+			.iter()
+			.filter(|finding| finding.severity == Severity::Error)
+			.count()
+	}
+
+	/// Archives that were scanned through a symlink, with the symlink's target
+	pub fn symlinks(&self) -> &[(String, PathBuf)] {
+		&self.symlinks
+	}
+
+	/// Where the scan spent its time, when running with `profile_resources`
+	pub fn profile(&self) -> Option<&ScanProfile> {
+		self.profile.as_ref()
+	}
+
+	/// Archives that couldn't be scanned, with the reason, when running with `keep_going`
+	pub fn failures(&self) -> &[(String, String)] {
+		&self.failures
+	}
+
+	/// Archives left out of the scan on purpose, such as duplicates, with the reason
+	pub fn skipped(&self) -> &[(String, String)] {
+		&self.skipped
+	}
+
+	/// Every archive with the resources it registered and their phase, sorted so that inventories
+	/// of different builds can be diffed
+	pub fn inventory(&self) -> String {
+		let mut by_archive: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+		for (resource, archive) in self.sources.iter() {
+			by_archive.entry(archive).or_default().push(resource);
+		}
+		let mut s = String::new();
+		for (archive, resources) in by_archive {
+			s.push_str(&format!("{}\n", archive));
+			for resource in resources {
+				let phase = match self.on_start.binary_search_by(|r| r.as_str().cmp(resource)) {
+					Ok(_) => "on_start",
+					Err(_) => "on_running",
+				};
+				s.push_str(&format!("\t{}\t{}\n", phase, resource));
+			}
+		}
+		s
+	}
+
+	pub fn stats(&self) -> PreloadStats {
+		let mut resources_by_type = BTreeMap::new();
+		for resource in self.on_running.iter().chain(self.on_start.iter()) {
+			let resource_type = Path::new(resource)
+				.extension()
+				.map(|ext| ext.to_string_lossy().to_lowercase())
+				.unwrap_or_else(|| "folder".to_owned());
+			*resources_by_type.entry(resource_type).or_insert(0) += 1;
+		}
+		let conflict_count = self
+			.on_running
+			.iter()
+			.filter(|resource| self.on_start.binary_search(resource).is_ok())
+			.count();
+		let mut per_resource: HashMap<&str, usize> = HashMap::new();
+		for (resource, _) in self.sources.iter() {
+			*per_resource.entry(resource.as_str()).or_insert(0) += 1;
+		}
+		PreloadStats {
+			patcher_version: VERSION,
+			mod_count: self.mod_count(),
+			resources_by_phase: BTreeMap::from([
+				("on_running", self.on_running_count()),
+				("on_start", self.on_start_count()),
+			]),
+			resources_by_type,
+			total_size: self.resource_size,
+			compressed_size: self.resource_compressed_size,
+			conflict_count,
+			duplicate_count: per_resource.values().filter(|count| **count > 1).count(),
+		}
+	}
+
+	pub fn report(&self, options: &PreloadOptions) -> PreloadReport<'_> {
+		PreloadReport {
+			dry_run: options.dry_run,
+			patcher_version: VERSION,
+			output: options.output_name(),
+			mods: &self.mods,
+			on_start: &self.on_start,
+			on_running: &self.on_running,
+			findings: &self.findings,
+			failures: self
+				.failures
+				.iter()
+				.map(|(archive, reason)| ScanFailure { archive, reason })
+				.collect(),
+		}
+	}
+
+	/// Collects the findings of the scan, the archives that failed to scan and the conflicts and
+	/// duplicates between mods into one list
+	pub fn analyze(&self) -> Analysis {
+		let mut findings = self.findings.clone();
+		for (name, reason) in self.failures.iter() {
+			findings.push(Finding::error(format!(
+				"{:?} failed to scan, its resources would be missing from the preload: {}",
+				name, reason
+			)));
+		}
+		let in_both_phases: Vec<&str> = self
+			.on_running
+			.iter()
+			.filter(|resource| self.on_start.binary_search(resource).is_ok())
+			.map(String::as_str)
+			.collect();
+		if !in_both_phases.is_empty() {
+			findings.push(Finding::warning(format!(
+				"{} resource(s) are registered both on_start and on_running: {}",
+				in_both_phases.len(),
+				in_both_phases.join(", ")
+			)));
+		}
+		let mut by_resource: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+		for (resource, archive) in self.sources.iter() {
+			by_resource.entry(resource).or_default().push(archive);
+		}
+		let duplicates: Vec<String> = by_resource
+			.into_iter()
+			.filter(|(_, archives)| archives.len() > 1)
+			.map(|(resource, archives)| format!("{} ({})", resource, archives.join(", ")))
+			.collect();
+		if !duplicates.is_empty() {
+			findings.push(Finding::info(format!(
+				"{} resource(s) are registered by more than one mod: {}",
+				duplicates.len(),
+				duplicates.join(", ")
+			)));
+		}
+		findings.sort_by_key(|finding| std::cmp::Reverse(finding.severity));
+		let count = |severity| {
+			findings
+				.iter()
+				.filter(|finding| finding.severity == severity)
+				.count()
+		};
+		let (error_count, warning_count, info_count) = (
+			count(Severity::Error),
+			count(Severity::Warning),
+			count(Severity::Info),
+		);
+		Analysis {
+			verdict: match (error_count, warning_count) {
+				(0, 0) => Verdict::Pass,
+				(0, _) => Verdict::Warn,
+				_ => Verdict::Fail,
+			},
+			mod_count: self.mod_count(),
+			error_count,
+			warning_count,
+			info_count,
+			findings,
+		}
+	}
+
+	/// The game loads the archives in its data folder in alphabetical order of their file names,
+	/// which is why the preload's name starts with a `~`
+	pub fn load_order_position(&self) -> LoadOrderPosition<'_> {
+		let index = self.mods.partition_point(|name| name.as_str() < ZIP_NAME);
+		LoadOrderPosition {
+			position: index + 1,
+			total: self.mods.len() + 1,
+			after: index.checked_sub(1).map(|i| self.mods[i].as_str()),
+			before: self.mods.get(index).map(|name| name.as_str()),
+		}
+	}
+}
+
+/// Contents of a previously generated preload
+pub struct PreloadInfo {
+	pub version: Option<String>,
+	pub on_running: Vec<String>,
+	pub on_start: Vec<String>,
+	/// Archives each resource came from, empty for preloads built before this was recorded
+	pub sources: HashMap<String, BTreeSet<String>>,
+}
+
+impl PreloadInfo {
+	pub fn read(path: &Path) -> Result<Self> {
+		let file = File::open(path).with_context(|| format!("Couldn't open preload {:?}", path))?;
+		let mut zip_file =
+			ZipArchive::new(file).with_context(|| format!("Couldn't read preload {:?}", path))?;
+		let mod_string = read_file_in_zip(&mut zip_file, &preload_script_path())?;
+		if mod_string.is_empty() {
+			return Err(anyhow!(
+				"{:?} is not a preload generated by {}",
+				path,
+				MOD_NAME
+			));
+		}
+		let version = mod_string
+			.lines()
+			.find_map(|line| line.trim().strip_prefix("Version = "))
+			.map(|version| version.trim_end_matches(',').trim_matches('"').to_owned());
+		let lines = |contents: String| contents.lines().map(str::to_owned).collect();
+		let mut sources: HashMap<String, BTreeSet<String>> = HashMap::new();
+		for line in read_file_in_zip(&mut zip_file, SOURCES_PATH)?.lines() {
+			if let Some((resource, archive)) = line.split_once('\t') {
+				sources
+					.entry(resource.to_owned())
+					.or_default()
+					.insert(archive.to_owned());
+			}
+		}
+		Ok(Self {
+			version,
+			on_running: lines(read_file_in_zip(&mut zip_file, ON_RUNNING_PATH)?),
+			on_start: lines(read_file_in_zip(&mut zip_file, ON_START_PATH)?),
+			sources,
+		})
+	}
 }
 
 fn read_file_in_zip(zip_file: &mut ZipArchive<File>, name: &str) -> Result<String> {
@@ -118,66 +789,1327 @@ fn read_file_in_zip(zip_file: &mut ZipArchive<File>, name: &str) -> Result<Strin
 	Ok(contents)
 }
 
-pub fn gather_resources_for_mod(gatherer: &mut ResourceGatherer, mod_path: &Path) -> Result<()> {
+/// Base game archives are `.dat` files, mods are `.zip` files
+fn is_mod_archive(path: &Path) -> bool {
+	path.extension()
+		.map(|ext| ext.eq_ignore_ascii_case("zip"))
+		.unwrap_or(false)
+}
+
+/// Splits a mod archive's file name into the mod id and version, following the usual
+/// `mod_id_1.2.3.zip` naming, e.g. `mod_msu_1.6.0.zip` is `mod_msu` version `1.6.0`
+pub fn mod_id_from_filename(file_name: &str) -> (&str, Option<&str>) {
+	let stem = file_name
+		.len()
+		.checked_sub(".zip".len())
+		.filter(|end| file_name[*end..].eq_ignore_ascii_case(".zip"))
+		.map_or(file_name, |end| &file_name[..end]);
+	match stem.rsplit_once(['_', '-']) {
+		Some((id, version))
+			if !id.is_empty()
+				&& version
+					.trim_start_matches(['v', 'V'])
+					.starts_with(|c: char| c.is_ascii_digit())
+				&& version
+					.trim_start_matches(['v', 'V'])
+					.chars()
+					.all(|c| c.is_ascii_digit() || c == '.') =>
+		{
+			(id, Some(version))
+		}
+		_ => (stem, None),
+	}
+}
+
+/// Notes the installed MSU version and warns if it's missing
+fn check_msu(gatherer: &mut ResourceGatherer, msu_id: &str) {
+	let msu = gatherer
+		.mods
+		.iter()
+		.map(|file_name| (file_name, mod_id_from_filename(file_name)))
+		.find(|(_, (id, _))| id.eq_ignore_ascii_case(msu_id));
+	let finding = match msu {
+		Some((file_name, (_, version))) => Finding::info(format!(
+			"found MSU {} in {:?}, the preload loads {} it",
+			version.unwrap_or("(unknown version)"),
+			file_name,
+			if file_name.as_str() < ZIP_NAME {
+				"after"
+			} else {
+				"before"
+			}
+		)),
+		// Only a note, as mods that don't need MSU get by fine without it and --strict
+		// shouldn't refuse to build for them
+		None => Finding::info(format!(
+			"MSU (mod id {:?}) is not installed, the preload is only used by mods built on it; \
+			pass --msu-id if it's installed under a different id",
+			msu_id
+		)),
+	};
+	gatherer.findings.push(finding);
+}
+
+/// Parses `<mod_id> <reason>` lines, skipping blank lines and `#` comments
+fn parse_denylist(contents: &str) -> Vec<(&str, &str)> {
+	contents
+		.lines()
+		.map(str::trim)
+		.filter(|line| !line.is_empty() && !line.starts_with('#'))
+		.map(|line| match line.split_once(char::is_whitespace) {
+			Some((id, reason)) => (id, reason.trim()),
+			None => (line, ""),
+		})
+		.collect()
+}
+
+/// Warns about installed mods that are on the embedded denylist or the one given with `denylist`
+fn check_denylist(gatherer: &mut ResourceGatherer, options: &PreloadOptions) -> Result<()> {
+	let extra = match &options.denylist {
+		Some(path) => std::fs::read_to_string(path)
+			.with_context(|| format!("Couldn't read denylist {:?}", path))?,
+		None => String::new(),
+	};
+	let denylist: Vec<_> = parse_denylist(DENYLIST_STR)
+		.into_iter()
+		.chain(parse_denylist(&extra))
+		.collect();
+	let mut findings = Vec::new();
+	for file_name in gatherer.mods.iter() {
+		let (id, _) = mod_id_from_filename(file_name);
+		let Some((_, reason)) = denylist
+			.iter()
+			.rev()
+			.find(|(denied, _)| denied.eq_ignore_ascii_case(id))
+		else {
+			continue;
+		};
+		let message = format!(
+			"{:?} is a known-bad mod{}",
+			file_name,
+			match reason.is_empty() {
+				true => String::new(),
+				false => format!(": {}", reason),
+			}
+		);
+		findings.push(match options.strict {
+			true => Finding::error(message),
+			false => Finding::info(message),
+		});
+	}
+	gatherer.findings.extend(findings);
+	Ok(())
+}
+
+fn modified_time_in_zip(zip_file: &mut ZipArchive<File>, name: &str) -> Option<DateTime> {
+	zip_file
+		.by_name(name)
+		.ok()
+		.and_then(|file| file.last_modified())
+}
+
+fn detect_unsupported_archive(path: &Path) -> Result<Option<&'static str>> {
+	let mut magic = Vec::with_capacity(8);
+	File::open(path)?.take(8).read_to_end(&mut magic)?;
+	Ok(UNSUPPORTED_ARCHIVE_MAGICS
+		.iter()
+		.find(|(_, signature)| magic.starts_with(signature))
+		.map(|(kind, _)| *kind))
+}
+
+/// A resource is present if the archive has a file with its exact name, or a folder of that name
+fn find_dangling_resources<'a>(
+	zip_file: &ZipArchive<File>,
+	resources: impl Iterator<Item = &'a str>,
+) -> Vec<&'a str> {
+	resources
+		.filter(|resource| {
+			let folder = format!("{}/", resource.trim_end_matches('/'));
+			zip_file.index_for_name(resource).is_none()
+				&& !zip_file.file_names().any(|name| name.starts_with(&folder))
+		})
+		.collect()
+}
+
+fn is_text_resource(resource: &str) -> bool {
+	Path::new(resource)
+		.extension()
+		.map(|ext| {
+			TEXT_RESOURCE_EXTENSIONS
+				.iter()
+				.any(|text_ext| ext.eq_ignore_ascii_case(text_ext))
+		})
+		.unwrap_or(false)
+}
+
+fn detect_encoding_issue(contents: &[u8]) -> Option<&'static str> {
+	if contents.starts_with(b"\xEF\xBB\xBF") {
+		Some("UTF-8 with BOM")
+	} else if contents.starts_with(b"\xFF\xFE") || contents.starts_with(b"\xFE\xFF") {
+		Some("UTF-16")
+	} else if std::str::from_utf8(contents).is_err() {
+		Some("not valid UTF-8")
+	} else {
+		None
+	}
+}
+
+/// Text resources the game won't read correctly because of their encoding
+fn find_badly_encoded_resources<'a>(
+	zip_file: &mut ZipArchive<File>,
+	resources: impl Iterator<Item = &'a str>,
+) -> Result<Vec<String>> {
+	let mut badly_encoded = Vec::new();
+	for resource in resources.filter(|resource| is_text_resource(resource)) {
+		let mut file = match zip_file.by_name(resource) {
+			Err(zip::result::ZipError::FileNotFound) => continue,
+			Err(e) => return Err(anyhow!(e)),
+			Ok(file) => file,
+		};
+		let mut contents = Vec::with_capacity(file.size() as usize);
+		file.read_to_end(&mut contents)?;
+		if let Some(issue) = detect_encoding_issue(&contents) {
+			badly_encoded.push(format!("{} ({})", resource, issue));
+		}
+	}
+	Ok(badly_encoded)
+}
+
+/// Why a resource path can't be created or read on Windows, such as a folder or file named
+/// after a reserved device like `CON` or `COM1`, characters Windows doesn't allow in names, or
+/// names ending in a dot or space
+fn windows_name_issue(resource: &str) -> Option<String> {
+	for component in resource.trim_end_matches('/').split('/') {
+		if let Some(c) = component
+			.chars()
+			.find(|c| c.is_control() || WINDOWS_INVALID_CHARS.contains(c))
+		{
+			return Some(format!("{:?} is not allowed in names", c));
+		}
+		let stem = component.split('.').next().unwrap_or(component).trim_end();
+		if WINDOWS_RESERVED_NAMES
+			.iter()
+			.any(|reserved| stem.eq_ignore_ascii_case(reserved))
+		{
+			return Some(format!("{} is a reserved device name", stem.to_uppercase()));
+		}
+		if component.ends_with(['.', ' ']) && component != "." && component != ".." {
+			return Some("names can't end in a dot or space".to_owned());
+		}
+	}
+	None
+}
+
+/// Line of the first character outside of strings and comments that isn't ASCII, which the
+/// game's Squirrel compiler rejects
+fn find_non_ascii_code(script: &str) -> Option<usize> {
+	let mut chars = script.chars().peekable();
+	let mut line = 1;
+	while let Some(c) = chars.next() {
+		match c {
+			'\n' => line += 1,
+			'#' => while chars.next_if(|c| *c != '\n').is_some() {},
+			'/' if chars.next_if_eq(&'/').is_some() => {
+				while chars.next_if(|c| *c != '\n').is_some() {}
+			}
+			'/' if chars.next_if_eq(&'*').is_some() => {
+				while let Some(c) = chars.next() {
+					match c {
+						'\n' => line += 1,
+						'*' if chars.next_if_eq(&'/').is_some() => break,
+						_ => {}
+					}
+				}
+			}
+			// Verbatim strings have no escapes and end at the first unpaired quote
+			'@' if chars.next_if_eq(&'"').is_some() => {
+				while let Some(c) = chars.next() {
+					match c {
+						'\n' => line += 1,
+						'"' if chars.next_if_eq(&'"').is_none() => break,
+						_ => {}
+					}
+				}
+			}
+			'"' | '\'' => {
+				while let Some(next) = chars.next() {
+					match next {
+						'\\' => {
+							chars.next();
+						}
+						'\n' => {
+							line += 1;
+							break;
+						}
+						_ if next == c => break,
+						_ => {}
+					}
+				}
+			}
+			_ if !c.is_ascii() => return Some(line),
+			_ => {}
+		}
+	}
+	None
+}
+
+fn detect_script_issue(contents: &[u8]) -> Option<String> {
+	if let Some(issue) = detect_encoding_issue(contents) {
+		return Some(issue.to_owned());
+	}
+	let script = std::str::from_utf8(contents).ok()?;
+	if script
+		.match_indices('\r')
+		.any(|(i, _)| !script[i + 1..].starts_with('\n'))
+	{
+		return Some("CR-only line endings, as saved by classic Mac editors".to_owned());
+	}
+	find_non_ascii_code(script).map(|line| {
+		format!(
+			"non-ASCII character outside of strings and comments on line {}",
+			line
+		)
+	})
+}
+
+/// Squirrel scripts in the archive that the game can fail to compile because of their encoding,
+/// line endings or characters, whether or not they're registered as resources
+fn find_broken_scripts(zip_file: &mut ZipArchive<File>) -> Result<Vec<String>> {
+	let mut broken = Vec::new();
+	for i in 0..zip_file.len() {
+		let mut file = zip_file.by_index(i)?;
+		if !file.is_file() || !file.name().to_ascii_lowercase().ends_with(".nut") {
+			continue;
+		}
+		let mut contents = Vec::with_capacity(file.size() as usize);
+		file.read_to_end(&mut contents)?;
+		if let Some(issue) = detect_script_issue(&contents) {
+			broken.push(format!("{} ({})", file.name(), issue));
+		}
+	}
+	Ok(broken)
+}
+
+fn preload_script_path() -> String {
+	format!("scripts/!mods_preload/{}.nut", MOD_ID)
+}
+
+/// Files in a mod archive that belong to a generated preload rather than a regular mod, such as
+/// a bundled copy of our preload or a whole `~mod_*.zip` nested inside the archive
+fn find_bundled_preload_content(zip_file: &ZipArchive<File>) -> Vec<&str> {
+	let preload_script = preload_script_path();
+	zip_file
+		.file_names()
+		.filter(|name| {
+			let file_name = name.rsplit('/').next().unwrap_or(name);
+			*name == preload_script
+				|| *name == SOURCES_PATH
+				|| (file_name.starts_with("~mod_") && is_mod_archive(Path::new(file_name)))
+		})
+		.collect()
+}
+
+/// Whether the archive is a preload generated by an earlier run rather than a mod that happens
+/// to bundle our preload script: it holds only the files of a preload, and reads as one
+fn is_generated_preload(mod_path: &Path, zip_file: &ZipArchive<File>) -> bool {
+	let preload_files = [
+		preload_script_path(),
+		ON_RUNNING_PATH.to_owned(),
+		ON_START_PATH.to_owned(),
+		SOURCES_PATH.to_owned(),
+	];
+	zip_file
+		.file_names()
+		.all(|name| preload_files.iter().any(|file| file == name))
+		&& PreloadInfo::read(mod_path).is_ok()
+}
+
+fn is_game_data_folder(folder: &str) -> bool {
+	GAME_DATA_FOLDERS
+		.iter()
+		.any(|known| folder.eq_ignore_ascii_case(known))
+}
+
+/// Strips wrapper folders in front of a known game data folder, e.g. `my_mod/gfx/icon.png`
+/// becomes `gfx/icon.png`. Paths that don't match this pattern are left untouched.
+fn flatten_resource(resource: &str, max_depth: usize) -> Option<&str> {
+	let components: Vec<&str> = resource.split('/').collect();
+	if is_game_data_folder(components[0]) {
+		return None;
+	}
+	let depth = components
+		.iter()
+		.take(max_depth + 1)
+		.position(|component| is_game_data_folder(component))
+		.filter(|depth| *depth < components.len() - 1)?;
+	let stripped_len: usize = components[..depth].iter().map(|c| c.len() + 1).sum();
+	Some(&resource[stripped_len..])
+}
+
+pub fn gather_resources_for_mod(
+	gatherer: &mut ResourceGatherer,
+	mod_path: &Path,
+	options: &PreloadOptions,
+) -> Result<()> {
 	let file = std::fs::File::open(mod_path)?;
 	let mut zip_file = match zip::ZipArchive::new(file) {
-		Err(zip::result::ZipError::InvalidArchive(_)) => return Ok(()),
+		Err(zip::result::ZipError::InvalidArchive(_)) => {
+			let file_name = mod_path.file_name().unwrap_or(mod_path.as_os_str());
+			let reason = match detect_unsupported_archive(mod_path)? {
+				Some(kind) => {
+					gatherer.findings.push(Finding::warning(format!(
+						"{:?} is a {} archive, which the game can't load; re-pack it as a .zip to install it",
+						file_name, kind
+					)));
+					format!("a {} archive, which the game can't load", kind)
+				}
+				None => "not a valid zip archive".to_owned(),
+			};
+			gatherer
+				.skipped
+				.push((file_name.to_string_lossy().into_owned(), reason));
+			return Ok(());
+		}
 		Err(e) => return Err(anyhow!(e)),
 		Ok(zip) => zip,
 	};
-	for line in read_file_in_zip(&mut zip_file, ON_RUNNING_PATH)?.lines() {
-		gatherer.on_running.insert(line.to_owned());
+	let archive_name = mod_path
+		.file_name()
+		.unwrap_or_default()
+		.to_string_lossy()
+		.into_owned();
+	// Our own output is skipped by name, but a renamed or copied one would otherwise register
+	// the resources of an earlier build again on every run
+	if is_generated_preload(mod_path, &zip_file) {
+		gatherer.findings.push(Finding::warning(format!(
+			"{:?} is a preload generated by an earlier run, which was skipped; delete it, the \
+			preload is always written to {}",
+			archive_name, ZIP_NAME
+		)));
+		gatherer.skipped.push((
+			archive_name,
+			"a copy of a preload generated by an earlier run".to_owned(),
+		));
+		return Ok(());
+	}
+	if is_mod_archive(mod_path) {
+		gatherer.mods.push(archive_name.clone());
+	} else {
+		for name in zip_file.file_names() {
+			gatherer
+				.base_files
+				.insert(name.to_owned(), archive_name.clone());
+		}
+	}
+	let bundled_preload = find_bundled_preload_content(&zip_file);
+	if !bundled_preload.is_empty() {
+		gatherer.findings.push(Finding::warning(format!(
+			"{:?} ships its own preload content ({}), which registers resources a second time \
+			next to the generated preload; remove it from the mod",
+			mod_path.file_name().unwrap_or(mod_path.as_os_str()),
+			bundled_preload.join(", ")
+		)));
+	}
+	let on_running = read_file_in_zip(&mut zip_file, ON_RUNNING_PATH)?;
+	let on_start = read_file_in_zip(&mut zip_file, ON_START_PATH)?;
+	// Only what --verify-resources finds is an error with --strict, other warnings never stop
+	// the preload from being created
+	let verify_findings_start = gatherer.findings.len();
+	if options.verify_resources {
+		let dangling =
+			find_dangling_resources(&zip_file, on_running.lines().chain(on_start.lines()));
+		if !dangling.is_empty() {
+			gatherer.findings.push(Finding::warning(format!(
+				"{:?} registers {} resource(s) that are missing from its archive: {}",
+				mod_path.file_name().unwrap_or(mod_path.as_os_str()),
+				dangling.len(),
+				dangling.join(", ")
+			)));
+		}
+		let too_long: Vec<_> = on_running
+			.lines()
+			.chain(on_start.lines())
+			.filter(|resource| {
+				resource.len() > MAX_RESOURCE_PATH_LEN
+					|| resource.split('/').count() > MAX_RESOURCE_PATH_DEPTH
+			})
+			.collect();
+		if !too_long.is_empty() {
+			gatherer.findings.push(Finding::warning(format!(
+				"{:?} registers resource(s) longer than {} characters or nested deeper than {} \
+				folders, which can fail to load under WINE or on some filesystems: {}",
+				mod_path.file_name().unwrap_or(mod_path.as_os_str()),
+				MAX_RESOURCE_PATH_LEN,
+				MAX_RESOURCE_PATH_DEPTH,
+				too_long.join(", ")
+			)));
+		}
+		let invalid_on_windows: Vec<_> = on_running
+			.lines()
+			.chain(on_start.lines())
+			.filter_map(|resource| {
+				windows_name_issue(resource).map(|issue| format!("{} ({})", resource, issue))
+			})
+			.collect();
+		if !invalid_on_windows.is_empty() {
+			gatherer.findings.push(Finding::warning(format!(
+				"{:?} registers resource(s) whose names are invalid on Windows, which the game \
+				can't read there or under WINE: {}",
+				mod_path.file_name().unwrap_or(mod_path.as_os_str()),
+				invalid_on_windows.join(", ")
+			)));
+		}
+		let badly_encoded = find_badly_encoded_resources(
+			&mut zip_file,
+			on_running.lines().chain(on_start.lines()),
+		)?;
+		if !badly_encoded.is_empty() {
+			gatherer.findings.push(Finding::warning(format!(
+				"{:?} has text resource(s) with an unexpected encoding: {}; \
+				re-save them as UTF-8 without a BOM so the game can read them",
+				mod_path.file_name().unwrap_or(mod_path.as_os_str()),
+				badly_encoded.join(", ")
+			)));
+		}
+		let broken_scripts = find_broken_scripts(&mut zip_file)?;
+		if !broken_scripts.is_empty() {
+			gatherer.findings.push(Finding::warning(format!(
+				"{:?} has script(s) the game may fail to compile: {}; re-save them as UTF-8 \
+				without a BOM with Windows or Unix line endings",
+				mod_path.file_name().unwrap_or(mod_path.as_os_str()),
+				broken_scripts.join(", ")
+			)));
+		}
+	}
+	if options.strict {
+		for finding in &mut gatherer.findings[verify_findings_start..] {
+			finding.severity = Severity::Error;
+		}
+	}
+	let mut remapped = Vec::new();
+	let mut normalize = |resource: &str| match options
+		.flatten
+		.and_then(|max_depth| flatten_resource(resource, max_depth))
+	{
+		Some(flattened) => {
+			remapped.push(format!("{} -> {}", resource, flattened));
+			flattened.to_owned()
+		}
+		None => resource.to_owned(),
+	};
+	if gatherer.declared_order.is_some()
+		&& !(on_start.lines().is_sorted() && on_running.lines().is_sorted())
+	{
+		gatherer.findings.push(Finding::info(format!(
+			"{:?} registers its resources in an explicit order, which is kept",
+			mod_path.file_name().unwrap_or(mod_path.as_os_str())
+		)));
+	}
+	let mod_index = gatherer.mods.len();
+	for (i, line) in on_running.lines().enumerate() {
+		let resource = normalize(line);
+		if let Some(order) = &mut gatherer.declared_order {
+			order.entry(resource.clone()).or_insert((mod_index, i));
+		}
+		gatherer
+			.sources
+			.entry(resource.clone())
+			.or_default()
+			.insert(archive_name.clone());
+		gatherer.on_running.insert(resource);
+	}
+	for (i, line) in on_start.lines().enumerate() {
+		let resource = normalize(line);
+		if let Some(order) = &mut gatherer.declared_order {
+			order.entry(resource.clone()).or_insert((mod_index, i));
+		}
+		gatherer
+			.sources
+			.entry(resource.clone())
+			.or_default()
+			.insert(archive_name.clone());
+		gatherer.on_start.insert(resource);
+	}
+	if !remapped.is_empty() {
+		gatherer.findings.push(Finding::info(format!(
+			"flattened {} resource path(s) of {:?}: {}",
+			remapped.len(),
+			mod_path.file_name().unwrap_or(mod_path.as_os_str()),
+			remapped.join(", ")
+		)));
+	}
+	let registered: HashSet<&str> = on_running
+		.lines()
+		.chain(on_start.lines())
+		.map(|resource| resource.trim_end_matches('/'))
+		.collect();
+	for i in 0..zip_file.len() {
+		let file = zip_file.by_index_raw(i)?;
+		let name = file.name();
+		let is_registered = registered.contains(name)
+			|| name
+				.match_indices('/')
+				.any(|(end, _)| registered.contains(&name[..end]));
+		if is_registered && file.is_file() {
+			gatherer.resource_size += file.size();
+			gatherer.resource_compressed_size += file.compressed_size();
+			if let Some(profile) = gatherer.profile.as_mut() {
+				profile
+					.resources
+					.push((name.to_owned(), file.size(), archive_name.clone()));
+			}
+		}
+	}
+	gatherer.on_running_modified = gatherer
+		.on_running_modified
+		.max(modified_time_in_zip(&mut zip_file, ON_RUNNING_PATH));
+	gatherer.on_start_modified = gatherer
+		.on_start_modified
+		.max(modified_time_in_zip(&mut zip_file, ON_START_PATH));
+	Ok(())
+}
+
+/// Notes the base game files each mod overrides. With `base_game_check`, overriding files of
+/// a critical base game archive is an error unless the mod is allowed to.
+fn check_base_game_overrides(gatherer: &mut ResourceGatherer, options: &PreloadOptions) {
+	let mut overrides: BTreeMap<&str, Vec<(&str, &str)>> = BTreeMap::new();
+	for (resource, archives) in gatherer.sources.iter() {
+		let Some(base_archive) = gatherer.base_files.get(resource) else {
+			continue;
+		};
+		for archive in archives
+			.iter()
+			.filter(|archive| is_mod_archive(Path::new(archive)))
+		{
+			overrides
+				.entry(archive)
+				.or_default()
+				.push((resource, base_archive));
+		}
+	}
+	let mut findings = Vec::new();
+	for (archive, mut resources) in overrides {
+		resources.sort();
+		let allowed = options
+			.allow_override
+			.iter()
+			.any(|allowed| allowed.eq_ignore_ascii_case(archive));
+		let describe = |resources: &[(&str, &str)]| {
+			resources
+				.iter()
+				.map(|(resource, base_archive)| format!("{} ({})", resource, base_archive))
+				.collect::<Vec<_>>()
+				.join(", ")
+		};
+		let (critical, other): (Vec<_>, Vec<_>) = resources
+			.iter()
+			.partition(|(_, base_archive)| CRITICAL_BASE_ARCHIVES.contains(base_archive));
+		if critical.is_empty() || !options.base_game_check || allowed {
+			findings.push(Finding::info(format!(
+				"{:?} overrides {} base game file(s): {}",
+				archive,
+				resources.len(),
+				describe(&resources)
+			)));
+			continue;
+		}
+		findings.push(Finding {
+			severity: Severity::Error,
+			message: format!(
+				"{:?} overrides {} file(s) of critical base game archives, which can crash \
+				the game on load: {}; pass --allow-override {} if this is intended",
+				archive,
+				critical.len(),
+				describe(&critical),
+				archive
+			),
+		});
+		if !other.is_empty() {
+			findings.push(Finding::info(format!(
+				"{:?} overrides {} base game file(s): {}",
+				archive,
+				other.len(),
+				describe(&other)
+			)));
+		}
+	}
+	gatherer.findings.extend(findings);
+}
+
+/// Resources whose paths only differ in case, which collide for the game on WINE/Linux even though
+/// they are distinct files in the archives. Each conflict lists every spelling with its sources.
+fn find_case_conflicts(sources: &HashMap<String, BTreeSet<String>>) -> Vec<String> {
+	let mut by_lowercase: BTreeMap<String, Vec<&String>> = BTreeMap::new();
+	for resource in sources.keys() {
+		by_lowercase
+			.entry(resource.to_lowercase())
+			.or_default()
+			.push(resource);
+	}
+	by_lowercase
+		.into_values()
+		.filter(|spellings| spellings.len() > 1)
+		.map(|mut spellings| {
+			spellings.sort();
+			spellings
+				.iter()
+				.map(|resource| {
+					let archives: Vec<_> = sources[*resource].iter().map(String::as_str).collect();
+					format!("{} ({})", resource, archives.join(", "))
+				})
+				.collect::<Vec<_>>()
+				.join(" vs ")
+		})
+		.collect()
+}
+
+/// Adds the resources of an existing preload that the scanned mods don't already register.
+/// Where the phases disagree the scanned phase wins. With `prune`, resources whose recorded
+/// source archives are all gone from the data folder are dropped instead.
+fn merge_preload(
+	gatherer: &mut ResourceGatherer,
+	base_path: &Path,
+	data_path: &DataPath,
+	prune: bool,
+) -> Result<()> {
+	let mut base = PreloadInfo::read(base_path)?;
+	let mut added = 0;
+	let mut overlapping = 0;
+	let mut conflicts = Vec::new();
+	let mut pruned = Vec::new();
+	let mut without_source = 0;
+	let sources = &mut gatherer.sources;
+	let mut merge_phase = |resources: Vec<String>,
+	                       same_phase: &mut HashSet<String>,
+	                       other_phase: &HashSet<String>| {
+		for resource in resources {
+			if same_phase.contains(&resource) {
+				overlapping += 1;
+			} else if other_phase.contains(&resource) {
+				conflicts.push(resource);
+			} else {
+				let archives = base.sources.remove(&resource).unwrap_or_default();
+				if prune {
+					if archives.is_empty() {
+						without_source += 1;
+					} else if archives
+						.iter()
+						.all(|archive| !data_path.join(archive).exists())
+					{
+						pruned.push(format!(
+							"{} ({})",
+							resource,
+							archives.into_iter().collect::<Vec<_>>().join(", ")
+						));
+						continue;
+					}
+				}
+				if !archives.is_empty() {
+					sources.insert(resource.clone(), archives);
+				}
+				same_phase.insert(resource);
+				added += 1;
+			}
+		}
+	};
+	merge_phase(
+		base.on_running,
+		&mut gatherer.on_running,
+		&gatherer.on_start,
+	);
+	merge_phase(base.on_start, &mut gatherer.on_start, &gatherer.on_running);
+
+	gatherer.findings.push(Finding::info(format!(
+		"merged {:?} (built by version {}): added {} resource(s), {} were already registered by the scanned mods",
+		base_path,
+		base.version.as_deref().unwrap_or("unknown"),
+		added,
+		overlapping
+	)));
+	if !conflicts.is_empty() {
+		gatherer.findings.push(Finding::warning(format!(
+			"{} resource(s) are registered in a different phase by {:?} than by the scanned mods, \
+			keeping the scanned phase: {}",
+			conflicts.len(),
+			base_path,
+			conflicts.join(", ")
+		)));
+	}
+	if !pruned.is_empty() {
+		gatherer.findings.push(Finding::info(format!(
+			"pruned {} resource(s) of {:?} whose source archives are no longer installed: {}",
+			pruned.len(),
+			base_path,
+			pruned.join(", ")
+		)));
+	}
+	if without_source > 0 {
+		gatherer.findings.push(Finding::warning(format!(
+			"{} resource(s) of {:?} have no recorded source archive and were kept; \
+			regenerate it without --merge to record the sources",
+			without_source, base_path
+		)));
+	}
+	Ok(())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Phase {
+	OnStart,
+	OnRunning,
+}
+
+impl std::fmt::Display for Phase {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Phase::OnStart => write!(f, "on_start"),
+			Phase::OnRunning => write!(f, "on_running"),
+		}
+	}
+}
+
+/// Reads a phase override file. Empty lines and lines starting with `#` are skipped.
+fn read_phase_overrides(path: &Path) -> Result<Vec<(String, Phase)>> {
+	let contents = std::fs::read_to_string(path)
+		.with_context(|| format!("Couldn't read phase override file {:?}", path))?;
+	let mut overrides = Vec::new();
+	for (i, line) in contents.lines().enumerate() {
+		let line = line.trim();
+		if line.is_empty() || line.starts_with('#') {
+			continue;
+		}
+		let parsed = line
+			.rsplit_once(char::is_whitespace)
+			.and_then(|(resource, phase)| {
+				let phase = match phase {
+					"on_start" => Phase::OnStart,
+					"on_running" => Phase::OnRunning,
+					_ => return None,
+				};
+				Some((resource.trim_end().to_owned(), phase))
+			});
+		match parsed {
+			Some(entry) => overrides.push(entry),
+			None => {
+				return Err(anyhow!(
+					"{:?} line {}: expected `<resource> on_start` or `<resource> on_running`, got {:?}",
+					path,
+					i + 1,
+					line
+				))
+			}
+		}
+	}
+	Ok(overrides)
+}
+
+/// Moves resources to the phase the override file forces them into
+fn apply_phase_overrides(gatherer: &mut ResourceGatherer, path: &Path) -> Result<()> {
+	let mut overridden = Vec::new();
+	let mut unregistered = Vec::new();
+	for (resource, phase) in read_phase_overrides(path)? {
+		let (from, to) = match phase {
+			Phase::OnStart => (&mut gatherer.on_running, &mut gatherer.on_start),
+			Phase::OnRunning => (&mut gatherer.on_start, &mut gatherer.on_running),
+		};
+		if from.remove(&resource) {
+			overridden.push(format!("{} -> {}", resource, phase));
+			to.insert(resource);
+		} else if !to.contains(&resource) {
+			unregistered.push(resource);
+		}
 	}
-	for line in read_file_in_zip(&mut zip_file, ON_START_PATH)?.lines() {
-		gatherer.on_start.insert(line.to_owned());
+	if !overridden.is_empty() {
+		gatherer.findings.push(Finding::info(format!(
+			"overrode the phase of {} resource(s) as listed in {:?}: {}",
+			overridden.len(),
+			path,
+			overridden.join(", ")
+		)));
+	}
+	if !unregistered.is_empty() {
+		gatherer.findings.push(Finding::warning(format!(
+			"{:?} overrides the phase of {} resource(s) no mod registers: {}",
+			path,
+			unregistered.len(),
+			unregistered.join(", ")
+		)));
 	}
 	Ok(())
 }
 
-pub fn get_resource_handler(data_path: &DataPath) -> Result<ResourceHandler> {
+/// Files whose modification time can't be read count as modified
+fn is_modified_after(path: &Path, since: SystemTime) -> bool {
+	std::fs::metadata(path)
+		.and_then(|metadata| metadata.modified())
+		.map_or(true, |modified| modified > since)
+}
+
+/// Mod archives that are byte-identical to another one under a different name, such as
+/// `mod_foo (1).zip` next to `mod_foo.zip`, mapped to the one that is kept. Of each set of
+/// identical archives the one with the shortest name is kept. Only archives that share their
+/// size with another one are hashed, so most are never read.
+fn find_duplicate_archives(archives: &[(String, PathBuf)]) -> Result<BTreeMap<String, String>> {
+	let mut by_size: HashMap<u64, Vec<(&str, &Path)>> = HashMap::new();
+	for (file_name, path) in archives.iter().filter(|(_, path)| is_mod_archive(path)) {
+		let size = std::fs::metadata(path)
+			.with_context(|| format!("Couldn't read the size of {:?}", file_name))?
+			.len();
+		by_size
+			.entry(size)
+			.or_default()
+			.push((file_name, path.as_path()));
+	}
+	let mut by_hash: HashMap<Vec<u8>, Vec<&str>> = HashMap::new();
+	for (file_name, path) in by_size
+		.into_values()
+		.filter(|same_size| same_size.len() > 1)
+		.flatten()
+	{
+		let mut hasher = Sha256::new();
+		std::io::copy(&mut File::open(path)?, &mut hasher)
+			.with_context(|| format!("Couldn't hash {:?}", file_name))?;
+		by_hash
+			.entry(hasher.finalize().to_vec())
+			.or_default()
+			.push(file_name);
+	}
+	let mut duplicates = BTreeMap::new();
+	for mut names in by_hash.into_values().filter(|names| names.len() > 1) {
+		names.sort_by_key(|name| (name.len(), *name));
+		for duplicate in &names[1..] {
+			duplicates.insert((*duplicate).to_owned(), names[0].to_owned());
+		}
+	}
+	Ok(duplicates)
+}
+
+pub fn get_resource_handler(
+	data_path: &DataPath,
+	options: &PreloadOptions,
+) -> Result<ResourceHandler> {
+	let scan_start = Instant::now();
 	let entries: Result<Vec<_>, _> = std::fs::read_dir(data_path)?.collect();
-	let entries = entries?;
+	let mut archives = Vec::new();
 	let mut gatherer = ResourceGatherer::new();
-	for e in entries.into_iter() {
-		if let Ok(file_type) = e.file_type() {
-			if file_type.is_dir() || e.file_name().to_string_lossy().ends_with(ZIP_NAME) {
-				continue;
+	if options.intra_mod_sort == IntraModSort::Declared {
+		gatherer.declared_order = Some(HashMap::new());
+	}
+	if options.profile_resources {
+		gatherer.profile = Some(ScanProfile::default());
+	}
+	let mut broken_symlinks = Vec::new();
+	for e in entries? {
+		let Ok(file_type) = e.file_type() else {
+			continue;
+		};
+		let file_name = e.file_name().to_string_lossy().into_owned();
+		if file_name.ends_with(ZIP_NAME) {
+			continue;
+		}
+		// Symlinks are followed, e.g. to mods kept in a central folder outside the data folder
+		if file_type.is_symlink() {
+			let target = std::fs::read_link(e.path()).unwrap_or_default();
+			match std::fs::metadata(e.path()) {
+				Err(_) => {
+					broken_symlinks.push(format!("{} -> {:?}", file_name, target));
+					continue;
+				}
+				Ok(metadata) if metadata.is_dir() => continue,
+				Ok(_) => gatherer.symlinks.push((file_name.clone(), target)),
 			}
-			gather_resources_for_mod(&mut gatherer, &e.path())?;
+		} else if file_type.is_dir() {
+			continue;
 		}
+		archives.push((file_name, e.path()));
+	}
+	let mod_count = archives
+		.iter()
+		.filter(|(_, path)| is_mod_archive(path))
+		.count();
+	if let Some(max_mods) = options.max_mods.filter(|max_mods| mod_count > *max_mods) {
+		return Err(anyhow!(
+			"Found {} mod archives in {:?}, more than the limit of {} set by --max-mods; \
+			check that this is the game's data folder",
+			mod_count,
+			data_path.as_ref(),
+			max_mods
+		));
+	}
+	archives.sort();
+	gatherer.symlinks.sort();
+	let mut merge = options.merge.as_deref();
+	if let (Some(since), Some(base_path)) = (options.since, merge) {
+		match PreloadInfo::read(base_path) {
+			Err(err) => {
+				gatherer.findings.push(Finding::warning(format!(
+					"can't scan incrementally, as {:#}; scanned all mods instead",
+					err
+				)));
+				merge = None;
+			}
+			Ok(_) => {
+				let (rescanned, unchanged): (Vec<_>, Vec<_>) = archives
+					.into_iter()
+					.partition(|(_, path)| !is_mod_archive(path) || is_modified_after(path, since));
+				let rescanned_mods: Vec<_> = rescanned
+					.iter()
+					.filter(|(_, path)| is_mod_archive(path))
+					.map(|(file_name, _)| file_name.as_str())
+					.collect();
+				gatherer.findings.push(Finding::info(format!(
+					"incremental scan: rescanned {} mod(s) modified since --since{}{}, the \
+					resources of the other {} are kept from {:?}",
+					rescanned_mods.len(),
+					if rescanned_mods.is_empty() { "" } else { ": " },
+					rescanned_mods.join(", "),
+					unchanged.len(),
+					base_path
+				)));
+				// Still installed, only their resources come from the merged preload
+				gatherer
+					.mods
+					.extend(unchanged.into_iter().map(|(file_name, _)| file_name));
+				archives = rescanned;
+			}
+		}
+	}
+	broken_symlinks.sort();
+	if !broken_symlinks.is_empty() {
+		gatherer.findings.push(Finding::warning(format!(
+			"skipped {} symlink(s) in the data folder whose target doesn't exist: {}",
+			broken_symlinks.len(),
+			broken_symlinks.join(", ")
+		)));
+	}
+	let duplicates = find_duplicate_archives(&archives)?;
+	for (duplicate, kept) in duplicates.iter() {
+		gatherer.findings.push(Finding::warning(format!(
+			"{:?} is byte-identical to {:?}, likely the same mod downloaded twice; only {:?} \
+			is scanned, delete the other one",
+			duplicate, kept, kept
+		)));
+		gatherer
+			.skipped
+			.push((duplicate.clone(), format!("byte-identical to {:?}", kept)));
+	}
+	for (file_name, path) in archives {
+		if duplicates.contains_key(&file_name) {
+			continue;
+		}
+		let archive_start = Instant::now();
+		let result = gather_resources_for_mod(&mut gatherer, &path, options);
+		if let Some(profile) = gatherer.profile.as_mut() {
+			profile
+				.archives
+				.push((file_name.clone(), archive_start.elapsed()));
+		}
+		if let Err(err) = result {
+			if !options.keep_going {
+				return Err(err.context(format!("Couldn't scan {:?}", file_name)));
+			}
+			gatherer.failures.push((file_name, format!("{:#}", err)));
+		}
+	}
+	check_msu(&mut gatherer, options.msu_id());
+	check_denylist(&mut gatherer, options)?;
+	check_base_game_overrides(&mut gatherer, options);
+	for conflict in find_case_conflicts(&gatherer.sources) {
+		gatherer.findings.push(Finding::warning(format!(
+			"resources only differ in case and collide on case-insensitive lookups: {}",
+			conflict
+		)));
+	}
+	if let Some(base_path) = merge {
+		merge_preload(&mut gatherer, base_path, data_path, options.prune)?;
+	}
+	if let Some(override_path) = &options.phase_override {
+		apply_phase_overrides(&mut gatherer, override_path)?;
+	}
+	if let Some(profile) = gatherer.profile.as_mut() {
+		profile.total = scan_start.elapsed();
 	}
 	Ok(gatherer.into())
 }
 
-fn get_mod_string(resource_handler: &ResourceHandler) -> String {
+fn quote_squirrel_string(s: &str) -> String {
+	let mut quoted = String::with_capacity(s.len() + 2);
+	quoted.push('"');
+	for c in s.chars() {
+		match c {
+			'"' => quoted.push_str("\\\""),
+			'\\' => quoted.push_str("\\\\"),
+			'\n' => quoted.push_str("\\n"),
+			'\r' => quoted.push_str("\\r"),
+			'\t' => quoted.push_str("\\t"),
+			c => quoted.push(c),
+		}
+	}
+	quoted.push('"');
+	quoted
+}
+
+fn default_description(resource_handler: &ResourceHandler) -> String {
+	format!(
+		"Generated by {} {} from {} mods",
+		MOD_NAME,
+		VERSION,
+		resource_handler.mod_count()
+	)
+}
+
+fn get_mod_string(
+	resource_handler: &ResourceHandler,
+	description: &str,
+	tag_sources: bool,
+) -> String {
 	let mod_string = MOD_STRING.to_owned();
+	let sources = match tag_sources {
+		true => resource_handler.get_sources_quoted(),
+		false => "{}".to_owned(),
+	};
+	let mod_string = mod_string.replace("$Sources$", &sources);
 	let mod_string = mod_string.replace("$OnRunning$", &resource_handler.get_on_running_quoted());
 	let mod_string = mod_string.replace("$OnStart$", &resource_handler.get_on_start_quoted());
 	let mod_string = mod_string.replace("$Version$", &format!("\"{}\"", VERSION));
 	let mod_string = mod_string.replace("$Name$", &format!("\"{}\"", MOD_NAME));
 	let mod_string = mod_string.replace("$ID$", &format!("\"{}\"", MOD_ID));
+	let mod_string = mod_string.replace("$Description$", &quote_squirrel_string(description));
 
 	mod_string.replace("$NameSpace$", MOD_NAMESPACE)
 }
 
-pub fn create_mod(data_path: &DataPath, resources: &ResourceHandler) -> Result<()> {
-	let mut zip = zip::ZipWriter::new(std::fs::File::create(data_path.join(ZIP_NAME))?);
-	let options = SimpleFileOptions::default().compression_method(CompressionMethod::Stored);
-	let mod_string = get_mod_string(resources);
-	zip.start_file(format!("scripts/!mods_preload/{}.nut", MOD_ID), options)?;
-	zip.write_all(mod_string.as_bytes())?;
+pub fn create_mod(
+	data_path: &DataPath,
+	resources: &ResourceHandler,
+	options: &PreloadOptions,
+) -> Result<()> {
+	if let Some(dir) = &options.extract_to {
+		assemble_mod(&mut DirectorySink { root: dir }, resources, options)
+			.with_context(|| format!("Couldn't extract the preload to {:?}", dir))?;
+		if options.output.is_none() {
+			return Ok(());
+		}
+	}
+	match &options.output {
+		_ if options.writes_to_stdout() => {
+			let mut stdout = std::io::stdout().lock();
+			if stdout.is_terminal() {
+				return Err(anyhow!(
+					"Refusing to write the preload zip to a terminal, redirect stdout to a file or pipe"
+				));
+			}
+			let mut buffer = Cursor::new(Vec::new());
+			write_mod(&mut buffer, resources, options)?;
+			stdout.write_all(buffer.get_ref())?;
+			stdout.flush()?;
+			Ok(())
+		}
+		Some(path) => write_mod_file(path, resources, options),
+		None => write_mod_file(&preload_path(data_path), resources, options),
+	}
+}
+
+fn write_mod_file(
+	path: &Path,
+	resources: &ResourceHandler,
+	options: &PreloadOptions,
+) -> Result<()> {
+	let mut file = AtomicFile::create(path)?;
+	write_mod(&mut file, resources, options)?;
+	file.commit()
+}
+
+/// Where the files making up the preload are written to
+trait PreloadSink {
+	fn add_file(&mut self, name: &str, modified: Option<DateTime>, contents: &[u8]) -> Result<()>;
+}
+
+struct ZipSink<W: Write + Seek> {
+	zip: zip::ZipWriter<W>,
+	preserve_timestamps: bool,
+}
 
-	zip.start_file(ON_RUNNING_PATH, options)?;
-	zip.write_all(resources.get_on_running_raw().as_bytes())?;
-	zip.start_file(ON_START_PATH, options)?;
-	zip.write_all(resources.get_on_start_raw().as_bytes())?;
+impl<W: Write + Seek> PreloadSink for ZipSink<W> {
+	fn add_file(&mut self, name: &str, modified: Option<DateTime>, contents: &[u8]) -> Result<()> {
+		// Stored uncompressed, the game reads these files on every start and they're small
+		// enough that compressing them saves next to nothing
+		let options = SimpleFileOptions::default().compression_method(CompressionMethod::Stored);
+		let options = match (self.preserve_timestamps, modified) {
+			(true, modified) => options.last_modified_time(modified.unwrap_or_default()),
+			(false, _) => options,
+		};
+		self.zip.start_file(name, options)?;
+		self.zip.write_all(contents)?;
+		Ok(())
+	}
+}
+
+/// Writes the files into a folder as they would be laid out in the zip, timestamps are not kept
+struct DirectorySink<'a> {
+	root: &'a Path,
+}
+
+impl PreloadSink for DirectorySink<'_> {
+	fn add_file(&mut self, name: &str, _modified: Option<DateTime>, contents: &[u8]) -> Result<()> {
+		let path = self.root.join(name);
+		if let Some(parent) = path.parent() {
+			std::fs::create_dir_all(parent)
+				.with_context(|| format!("Couldn't create {:?}", parent))?;
+		}
+		std::fs::write(&path, contents).with_context(|| format!("Couldn't write {:?}", path))
+	}
+}
+
+fn assemble_mod(
+	sink: &mut impl PreloadSink,
+	resources: &ResourceHandler,
+	options: &PreloadOptions,
+) -> Result<()> {
+	let description = options
+		.description
+		.clone()
+		.unwrap_or_else(|| default_description(resources));
+	let mod_string = get_mod_string(resources, &description, options.tag_sources);
+	sink.add_file(
+		&preload_script_path(),
+		resources
+			.on_running_modified
+			.max(resources.on_start_modified),
+		mod_string.as_bytes(),
+	)?;
+	sink.add_file(
+		ON_RUNNING_PATH,
+		resources.on_running_modified,
+		resources.get_on_running_raw().as_bytes(),
+	)?;
+	sink.add_file(
+		ON_START_PATH,
+		resources.on_start_modified,
+		resources.get_on_start_raw().as_bytes(),
+	)?;
+	sink.add_file(SOURCES_PATH, None, resources.get_sources_raw().as_bytes())
+}
 
-	zip.finish()?;
+fn write_mod<W: Write + Seek>(
+	writer: W,
+	resources: &ResourceHandler,
+	options: &PreloadOptions,
+) -> Result<()> {
+	let mut sink = ZipSink {
+		zip: zip::ZipWriter::new(writer),
+		preserve_timestamps: options.preserve_timestamps,
+	};
+	assemble_mod(&mut sink, resources, options)?;
+	sink.zip.finish()?;
 	Ok(())
 }
 
-pub fn gather_and_create_mod(data_path: &DataPath) -> Result<ResourceHandler> {
-	let resources = get_resource_handler(data_path)?;
-	create_mod(data_path, &resources)?;
+/// Quick estimate of what building the preload would process, see `count_mods`
+#[derive(Serialize)]
+pub struct ModCount {
+	pub mods: usize,
+	/// Lines in the mods' manifests. Resources registered by several mods are counted once per
+	/// mod, so the preload may end up with fewer.
+	pub estimated_resources: usize,
+	/// Archives that aren't readable zips, which a full scan would report
+	pub unreadable: Vec<String>,
+}
+
+/// Counts the mod archives in the data folder and the resources their manifests register,
+/// reading only each archive's file list and manifests. Much faster than a full scan, but
+/// runs none of its checks and doesn't deduplicate resources.
+pub fn count_mods(data_path: &DataPath) -> Result<ModCount> {
+	let mut count = ModCount {
+		mods: 0,
+		estimated_resources: 0,
+		unreadable: Vec::new(),
+	};
+	for e in std::fs::read_dir(data_path)? {
+		let e = e?;
+		let file_name = e.file_name().to_string_lossy().into_owned();
+		// Follows symlinks like a full scan, broken ones are skipped
+		let is_file = std::fs::metadata(e.path()).is_ok_and(|metadata| metadata.is_file());
+		if file_name.ends_with(ZIP_NAME) || !is_file || !is_mod_archive(&e.path()) {
+			continue;
+		}
+		let mut zip_file = match File::open(e.path())
+			.map_err(anyhow::Error::from)
+			.and_then(|file| Ok(ZipArchive::new(file)?))
+		{
+			Ok(zip_file) => zip_file,
+			Err(_) => {
+				count.unreadable.push(file_name);
+				continue;
+			}
+		};
+		count.mods += 1;
+		for manifest in [ON_RUNNING_PATH, ON_START_PATH] {
+			count.estimated_resources += read_file_in_zip(&mut zip_file, manifest)?
+				.lines()
+				.filter(|line| !line.is_empty())
+				.count();
+		}
+	}
+	count.unreadable.sort();
+	Ok(count)
+}
+
+/// Where the preload is written by default
+pub fn preload_path(data_path: &DataPath) -> PathBuf {
+	data_path.join(ZIP_NAME)
+}
+
+/// Whether the preload in the data folder is missing or out of date with the mods `resources`
+/// was scanned from, because mods were added or removed since it was generated. Compares the
+/// archives the preload records as sources rather than the resources themselves, which
+/// --flatten, --registration-phase-override and --merge change, so it holds whatever options the
+/// preload was built with. Preloads without sources fall back to comparing the resources.
+pub fn is_preload_stale(data_path: &DataPath, resources: &ResourceHandler) -> Result<bool> {
+	let zip_path = preload_path(data_path);
+	if !zip_path.exists() {
+		return Ok(true);
+	}
+	let mut preload = PreloadInfo::read(&zip_path)?;
+	if !preload.sources.is_empty() {
+		let preload_archives: HashSet<&str> = preload
+			.sources
+			.values()
+			.flatten()
+			.map(String::as_str)
+			.collect();
+		let added = resources
+			.sources
+			.iter()
+			.any(|(_, archive)| !preload_archives.contains(archive.as_str()));
+		let removed = preload_archives
+			.iter()
+			.any(|archive| !data_path.join(archive).exists());
+		return Ok(added || removed);
+	}
+	preload.on_running.sort();
+	preload.on_start.sort();
+	Ok(preload.on_running != resources.on_running || preload.on_start != resources.on_start)
+}
+
+/// Deletes a previously generated preload from the data folder, returns whether there was one
+pub fn remove_mod(data_path: &DataPath) -> Result<bool> {
+	let zip_path = preload_path(data_path);
+	if !zip_path.exists() {
+		return Ok(false);
+	}
+	std::fs::remove_file(&zip_path).with_context(|| format!("Couldn't remove {:?}", zip_path))?;
+	Ok(true)
+}
+
+/// The preload isn't created for a dry run or if the scan produced any errors, check `ResourceHandler::error_count`
+pub fn gather_and_create_mod(
+	data_path: &DataPath,
+	options: &PreloadOptions,
+) -> Result<ResourceHandler> {
+	let resources = get_resource_handler(data_path, options)?;
+	if resources.error_count() == 0 && !options.dry_run {
+		create_mod(data_path, &resources, options)?;
+	}
 	Ok(resources)
 }
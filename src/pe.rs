@@ -1,9 +1,11 @@
 //! Cross-platform PE (Portable Executable) header structures.
 //! These are defined manually to avoid Windows-only dependencies.
 
+use serde::Serialize;
+
 /// DOS Header - 64 bytes at the start of every PE file
 #[repr(C, packed)]
-#[derive(Debug, Clone, Copy, Default)]
+#[derive(Debug, Clone, Copy, Default, Serialize)]
 pub struct ImageDosHeader {
 	pub e_magic: u16,      // Magic number (MZ = 0x5A4D)
 	pub e_cblp: u16,       // Bytes on last page of file
@@ -28,7 +30,7 @@ pub struct ImageDosHeader {
 
 /// File Header - part of the PE header
 #[repr(C, packed)]
-#[derive(Debug, Clone, Copy, Default)]
+#[derive(Debug, Clone, Copy, Default, Serialize)]
 pub struct ImageFileHeader {
 	pub machine: u16,                 // Target machine type
 	pub number_of_sections: u16,      // Number of sections
@@ -43,3 +45,107 @@ pub struct ImageFileHeader {
 pub const IMAGE_DOS_SIGNATURE: u16 = 0x5A4D; // MZ
 pub const IMAGE_NT_SIGNATURE: u32 = 0x00004550; // PE\0\0
 pub const IMAGE_FILE_LARGE_ADDRESS_AWARE: u16 = 0x0020;
+pub const IMAGE_NT_OPTIONAL_HDR32_MAGIC: u16 = 0x010B; // PE32
+pub const IMAGE_NT_OPTIONAL_HDR64_MAGIC: u16 = 0x020B; // PE32+
+pub const IMAGE_SECTION_HEADER_SIZE: u64 = 40;
+
+/// Names of the flags in `ImageFileHeader::characteristics`
+const IMAGE_FILE_CHARACTERISTICS: [(u16, &str); 15] = [
+	(0x0001, "RELOCS_STRIPPED"),
+	(0x0002, "EXECUTABLE_IMAGE"),
+	(0x0004, "LINE_NUMS_STRIPPED"),
+	(0x0008, "LOCAL_SYMS_STRIPPED"),
+	(0x0010, "AGGRESSIVE_WS_TRIM"),
+	(IMAGE_FILE_LARGE_ADDRESS_AWARE, "LARGE_ADDRESS_AWARE"),
+	(0x0080, "BYTES_REVERSED_LO"),
+	(0x0100, "32BIT_MACHINE"),
+	(0x0200, "DEBUG_STRIPPED"),
+	(0x0400, "REMOVABLE_RUN_FROM_SWAP"),
+	(0x0800, "NET_RUN_FROM_SWAP"),
+	(0x1000, "SYSTEM"),
+	(0x2000, "DLL"),
+	(0x4000, "UP_SYSTEM_ONLY"),
+	(0x8000, "BYTES_REVERSED_HI"),
+];
+
+/// Names and sizes of the fields of `ImageFileHeader`, in declaration order
+const IMAGE_FILE_HEADER_LAYOUT: [(&str, usize); 7] = [
+	("machine", 2),
+	("number_of_sections", 2),
+	("time_date_stamp", 4),
+	("pointer_to_symbol_table", 4),
+	("number_of_symbols", 4),
+	("size_of_optional_header", 2),
+	("characteristics", 2),
+];
+
+fn hex_field(
+	name: &'static str,
+	value: impl std::fmt::UpperHex,
+	width: usize,
+) -> (&'static str, String) {
+	(name, format!("0x{:0width$X}", value, width = width))
+}
+
+impl ImageDosHeader {
+	/// Each field's name and value, in declaration order
+	pub fn fields(&self) -> Vec<(&'static str, String)> {
+		let e_res = self.e_res;
+		let e_res2 = self.e_res2;
+		vec![
+			hex_field("e_magic", self.e_magic, 4),
+			hex_field("e_cblp", self.e_cblp, 4),
+			hex_field("e_cp", self.e_cp, 4),
+			hex_field("e_crlc", self.e_crlc, 4),
+			hex_field("e_cparhdr", self.e_cparhdr, 4),
+			hex_field("e_minalloc", self.e_minalloc, 4),
+			hex_field("e_maxalloc", self.e_maxalloc, 4),
+			hex_field("e_ss", self.e_ss, 4),
+			hex_field("e_sp", self.e_sp, 4),
+			hex_field("e_csum", self.e_csum, 4),
+			hex_field("e_ip", self.e_ip, 4),
+			hex_field("e_cs", self.e_cs, 4),
+			hex_field("e_lfarlc", self.e_lfarlc, 4),
+			hex_field("e_ovno", self.e_ovno, 4),
+			("e_res", format!("{:04X?}", e_res)),
+			hex_field("e_oemid", self.e_oemid, 4),
+			hex_field("e_oeminfo", self.e_oeminfo, 4),
+			("e_res2", format!("{:04X?}", e_res2)),
+			hex_field("e_lfanew", self.e_lfanew, 8),
+		]
+	}
+}
+
+impl ImageFileHeader {
+	/// Names of the flags set in `characteristics`
+	pub fn characteristics_names(&self) -> Vec<&'static str> {
+		let characteristics = self.characteristics;
+		IMAGE_FILE_CHARACTERISTICS
+			.iter()
+			.filter(|(flag, _)| characteristics & flag != 0)
+			.map(|(_, name)| *name)
+			.collect()
+	}
+
+	/// Name of the field at `offset` bytes into the header
+	pub fn field_at(offset: usize) -> Option<&'static str> {
+		let mut start = 0;
+		IMAGE_FILE_HEADER_LAYOUT.iter().find_map(|(name, size)| {
+			start += size;
+			(offset < start).then_some(*name)
+		})
+	}
+
+	/// Each field's name and value, in declaration order
+	pub fn fields(&self) -> Vec<(&'static str, String)> {
+		vec![
+			hex_field("machine", self.machine, 4),
+			hex_field("number_of_sections", self.number_of_sections, 4),
+			hex_field("time_date_stamp", self.time_date_stamp, 8),
+			hex_field("pointer_to_symbol_table", self.pointer_to_symbol_table, 8),
+			hex_field("number_of_symbols", self.number_of_symbols, 8),
+			hex_field("size_of_optional_header", self.size_of_optional_header, 4),
+			hex_field("characteristics", self.characteristics, 4),
+		]
+	}
+}
@@ -0,0 +1,85 @@
+//! Detection of a running game, which keeps the exe and the data archives open, and other
+//! interaction with programs outside the tool.
+
+use anyhow::{anyhow, Context, Result};
+use std::path::Path;
+
+const GAME_PROCESS_NAME: &str = "BattleBrothers.exe";
+
+/// Windows refuses to open an exe for writing while it's running
+#[cfg(windows)]
+pub fn is_exe_locked(exe_path: &Path) -> bool {
+	const ERROR_SHARING_VIOLATION: i32 = 32;
+	match std::fs::OpenOptions::new().write(true).open(exe_path) {
+		Err(e) => e.raw_os_error() == Some(ERROR_SHARING_VIOLATION),
+		Ok(_) => false,
+	}
+}
+
+#[cfg(not(windows))]
+pub fn is_exe_locked(_exe_path: &Path) -> bool {
+	false
+}
+
+#[cfg(windows)]
+pub fn is_game_running() -> Result<bool> {
+	let output = std::process::Command::new("tasklist")
+		.args(["/NH", "/FI", &format!("IMAGENAME eq {}", GAME_PROCESS_NAME)])
+		.output()
+		.context("Couldn't run tasklist")?;
+	Ok(String::from_utf8_lossy(&output.stdout)
+		.to_lowercase()
+		.contains(&GAME_PROCESS_NAME.to_lowercase()))
+}
+
+/// Under WINE the game runs as a process whose first argument is the path of the exe.
+/// Always false where there is no `/proc`, such as on macOS.
+#[cfg(not(windows))]
+pub fn is_game_running() -> Result<bool> {
+	let entries = match std::fs::read_dir("/proc") {
+		Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+		result => result.context("Couldn't list processes")?,
+	};
+	let own_pid = std::process::id().to_string();
+	for entry in entries.filter_map(|entry| entry.ok()) {
+		let pid = entry.file_name().to_string_lossy().into_owned();
+		if !pid.chars().all(|c| c.is_ascii_digit()) || pid == own_pid {
+			continue;
+		}
+		// Processes can exit while they are listed, or belong to other users
+		let Ok(cmdline) = std::fs::read(entry.path().join("cmdline")) else {
+			continue;
+		};
+		let program = cmdline.split(|b| *b == 0).next().unwrap_or_default();
+		let program = String::from_utf8_lossy(program);
+		let file_name = program.rsplit(['/', '\\']).next().unwrap_or_default();
+		if file_name.eq_ignore_ascii_case(GAME_PROCESS_NAME) {
+			return Ok(true);
+		}
+	}
+	Ok(false)
+}
+
+/// Opens a URL in the default browser, erroring if there is no way to do that, e.g. on a
+/// headless machine
+pub fn open_in_browser(url: &str) -> Result<()> {
+	let (program, args): (&str, &[&str]) = if cfg!(windows) {
+		// Unlike `start`, doesn't need the `&`s in the URL escaped for cmd
+		("rundll32", &["url.dll,FileProtocolHandler"])
+	} else if cfg!(target_os = "macos") {
+		("open", &[])
+	} else {
+		("xdg-open", &[])
+	};
+	let status = std::process::Command::new(program)
+		.args(args)
+		.arg(url)
+		.stdout(std::process::Stdio::null())
+		.stderr(std::process::Stdio::null())
+		.status()
+		.with_context(|| format!("Couldn't run {}", program))?;
+	if !status.success() {
+		return Err(anyhow!("{} failed with {}", program, status));
+	}
+	Ok(())
+}
@@ -0,0 +1,1282 @@
+//! End-to-end tests of the command line interface, run against a fake game install.
+//!
+//! Every test works in its own temporary folder, which is also the working directory the
+//! config file is written to.
+
+use assert_cmd::Command;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use tempfile::TempDir;
+
+const EXE_SIZE: usize = 0x400;
+const PE_HEADER_OFFSET: usize = 0x40;
+const IMAGE_FILE_CHARACTERISTICS_OFFSET: usize = PE_HEADER_OFFSET + 4 + 18;
+
+/// A minimal 32-bit PE with a single section, whose hash isn't a known game version
+fn fake_exe() -> Vec<u8> {
+	let mut exe = vec![0u8; EXE_SIZE];
+	exe[0..2].copy_from_slice(b"MZ");
+	exe[0x3C..0x40].copy_from_slice(&(PE_HEADER_OFFSET as i32).to_le_bytes());
+	let pe = &mut exe[PE_HEADER_OFFSET..];
+	pe[0..4].copy_from_slice(b"PE\0\0");
+	pe[4..6].copy_from_slice(&0x014Cu16.to_le_bytes()); // i386
+	pe[6..8].copy_from_slice(&1u16.to_le_bytes()); // number of sections
+	pe[20..22].copy_from_slice(&0xE0u16.to_le_bytes()); // size of optional header
+	pe[22..24].copy_from_slice(&0x0102u16.to_le_bytes()); // executable, 32-bit
+	pe[24..26].copy_from_slice(&0x010Bu16.to_le_bytes()); // PE32 optional header
+	let section = &mut pe[24 + 0xE0..];
+	section[0..5].copy_from_slice(b".text");
+	exe
+}
+
+fn write_zip(path: &Path, files: &[(&str, &str)]) {
+	let mut zip = zip::ZipWriter::new(File::create(path).unwrap());
+	for (name, contents) in files {
+		zip.start_file(*name, zip::write::SimpleFileOptions::default())
+			.unwrap();
+		zip.write_all(contents.as_bytes()).unwrap();
+	}
+	zip.finish().unwrap();
+}
+
+struct FakeInstall {
+	dir: TempDir,
+}
+
+impl FakeInstall {
+	fn new() -> Self {
+		let dir = TempDir::new().unwrap();
+		let game = dir.path().join("Battle Brothers");
+		std::fs::create_dir_all(game.join("win32")).unwrap();
+		std::fs::create_dir_all(game.join("data")).unwrap();
+		std::fs::write(game.join("win32").join("BattleBrothers.exe"), fake_exe()).unwrap();
+		write_zip(
+			&game.join("data").join("data_001.dat"),
+			&[("scripts/main.nut", "")],
+		);
+		write_zip(
+			&game.join("data").join("mod_msu_1.6.0.zip"),
+			&[
+				("preload/on_running.txt", "gfx/ui/msu.png"),
+				("gfx/ui/msu.png", ""),
+			],
+		);
+		write_zip(
+			&game.join("data").join("mod_foo.zip"),
+			&[
+				("preload/on_running.txt", "gfx/foo.png"),
+				("preload/on_start.txt", "sounds/foo.wav"),
+				("gfx/foo.png", ""),
+				("sounds/foo.wav", ""),
+			],
+		);
+		Self { dir }
+	}
+
+	fn game(&self) -> PathBuf {
+		self.dir.path().join("Battle Brothers")
+	}
+
+	fn exe(&self) -> PathBuf {
+		self.game().join("win32").join("BattleBrothers.exe")
+	}
+
+	fn is_laa(&self) -> bool {
+		let exe = std::fs::read(self.exe()).unwrap();
+		exe[IMAGE_FILE_CHARACTERISTICS_OFFSET] & 0x20 != 0
+	}
+
+	fn cmd(&self) -> Command {
+		let mut cmd = Command::cargo_bin("bb-patcher").unwrap();
+		cmd.current_dir(self.dir.path())
+			.env_remove("BB_PATCHER_ASSUME_PATH")
+			.env("NO_COLOR", "1");
+		cmd
+	}
+
+	fn run(&self, args: &[&str]) -> (i32, String, String) {
+		let output = self.cmd().args(args).output().unwrap();
+		(
+			output.status.code().unwrap(),
+			String::from_utf8(output.stdout).unwrap(),
+			String::from_utf8(output.stderr).unwrap(),
+		)
+	}
+
+	fn set_path(&self) {
+		let (code, stdout, _) = self.run(&["set-path", self.game().to_str().unwrap()]);
+		assert_eq!(code, 0);
+		assert!(stdout.contains("Game path set to"), "{}", stdout);
+	}
+}
+
+#[test]
+fn set_path_is_saved_to_the_config_file() {
+	let install = FakeInstall::new();
+	install.set_path();
+
+	let config =
+		std::fs::read_to_string(install.dir.path().join("bb-patcher-config.toml")).unwrap();
+	assert!(config.contains("bb_path"), "{}", config);
+
+	let (code, stdout, _) = install.run(&["config"]);
+	assert_eq!(code, 0);
+	assert!(stdout.contains("Executable:"), "{}", stdout);
+	assert!(stdout.contains("(found)"), "{}", stdout);
+	assert!(!stdout.contains("NOT FOUND"), "{}", stdout);
+}
+
+#[test]
+fn set_path_accepts_the_exe() {
+	let install = FakeInstall::new();
+	let (code, stdout, _) = install.run(&["set-path", install.exe().to_str().unwrap()]);
+	assert_eq!(code, 0);
+	assert!(stdout.contains("Battle Brothers"), "{}", stdout);
+}
+
+#[test]
+fn set_path_rejects_a_folder_without_the_game() {
+	let install = FakeInstall::new();
+	let empty = install.dir.path().join("empty");
+	std::fs::create_dir(&empty).unwrap();
+	let (code, _, stderr) = install.run(&["set-path", empty.to_str().unwrap()]);
+	assert_eq!(code, 1);
+	assert!(
+		stderr.contains("BattleBrothers.exe not found"),
+		"{}",
+		stderr
+	);
+}
+
+#[test]
+fn assume_path_is_not_saved_to_the_config_file() {
+	let install = FakeInstall::new();
+	let game = install.game();
+	let (code, stdout, _) = install.run(&[
+		"preload",
+		"--disable",
+		"--assume-path",
+		game.to_str().unwrap(),
+	]);
+	assert_eq!(code, 0);
+	assert!(stdout.contains("Disabled preload generation"), "{}", stdout);
+
+	let config =
+		std::fs::read_to_string(install.dir.path().join("bb-patcher-config.toml")).unwrap();
+	assert!(config.contains("preload_enabled = false"), "{}", config);
+	assert!(!config.contains("bb_path"), "{}", config);
+}
+
+#[test]
+fn force_unknown_as_only_applies_to_the_saved_install() {
+	let install = FakeInstall::new();
+	install.set_path();
+	install.run(&["config", "--force-unknown-as", "gog"]);
+	let other = install.dir.path().join("Other Install");
+	std::fs::create_dir_all(other.join("win32")).unwrap();
+	std::fs::create_dir_all(other.join("data")).unwrap();
+	std::fs::write(other.join("win32").join("BattleBrothers.exe"), fake_exe()).unwrap();
+
+	let (code, _, stderr) = install.run(&["patch4gb", "--assume-path", other.to_str().unwrap()]);
+	assert_eq!(code, 1);
+	assert!(stderr.contains("Unknown version"), "{}", stderr);
+
+	let (code, ..) = install.run(&[
+		"patch4gb",
+		"--assume-path",
+		install.game().to_str().unwrap(),
+	]);
+	assert_eq!(code, 0);
+	assert!(install.is_laa());
+}
+
+#[test]
+fn env_file_settings_are_overridden_by_flags() {
+	let install = FakeInstall::new();
+	let env_file = install.dir.path().join("ci.env");
+	std::fs::write(
+		&env_file,
+		format!(
+			"# CI settings\ngame_path=\"{}\"\nbackup_retention = 3\n",
+			install.game().display()
+		),
+	)
+	.unwrap();
+	let env_file = env_file.to_str().unwrap();
+
+	let (code, stdout, _) = install.run(&["--env-file", env_file, "detect"]);
+	assert_eq!(code, 0);
+	assert!(stdout.contains("Version: Unknown"), "{}", stdout);
+
+	let (_, stdout, _) = install.run(&["--env-file", env_file, "--dump-config", "patch4gb"]);
+	assert!(stdout.contains("(--env-file)"), "{}", stdout);
+	assert!(
+		stdout.contains("backup_retention = 3  (--env-file)"),
+		"{}",
+		stdout
+	);
+	let (_, stdout, _) = install.run(&[
+		"--env-file",
+		env_file,
+		"--dump-config",
+		"patch4gb",
+		"--backup-retention",
+		"5",
+	]);
+	assert!(
+		stdout.contains("backup_retention = 5  (--backup-retention)"),
+		"{}",
+		stdout
+	);
+
+	std::fs::write(install.dir.path().join("bad.env"), "wine_cmd=wine\n").unwrap();
+	let (code, _, stderr) = install.run(&["--env-file", "bad.env", "detect"]);
+	assert_eq!(code, 1);
+	assert!(stderr.contains("unknown key \"wine_cmd\""), "{}", stderr);
+}
+
+#[cfg(unix)]
+#[test]
+fn timeout_stops_a_stalled_command() {
+	let install = FakeInstall::new();
+	// Reading a FIFO without a writer blocks forever, like a stalled network drive
+	let fifo = install.dir.path().join("path-fifo");
+	let status = std::process::Command::new("mkfifo")
+		.arg(&fifo)
+		.status()
+		.unwrap();
+	assert!(status.success());
+
+	let (code, _, stderr) = install.run(&[
+		"--timeout",
+		"1",
+		"detect",
+		"--path-file",
+		fifo.to_str().unwrap(),
+	]);
+	assert_eq!(code, 7);
+	assert!(stderr.contains("Timed out after 1 second(s)"), "{}", stderr);
+
+	install.set_path();
+	let (code, ..) = install.run(&["--timeout", "60", "detect"]);
+	assert_eq!(code, 0);
+}
+
+#[test]
+fn detect_reports_an_unknown_exe() {
+	let install = FakeInstall::new();
+	install.set_path();
+	let (code, stdout, _) = install.run(&["detect"]);
+	assert_eq!(code, 0);
+	assert!(stdout.contains("Version: Unknown"), "{}", stdout);
+	assert!(stdout.contains("SHA-256:"), "{}", stdout);
+}
+
+#[test]
+fn detect_looks_up_the_game_version() {
+	let install = FakeInstall::new();
+	install.set_path();
+	let (_, stdout, _) = install.run(&["hash"]);
+	let hash = stdout.split_whitespace().last().unwrap().to_owned();
+	let db = install.dir.path().join("versions.toml");
+	std::fs::write(&db, format!("{} = \"1.5.1.12\"\n", hash)).unwrap();
+
+	let (code, stdout, _) = install.run(&["detect", "--game-version-db", db.to_str().unwrap()]);
+	assert_eq!(code, 0);
+	assert!(stdout.contains("Game version: 1.5.1.12"), "{}", stdout);
+}
+
+#[test]
+fn detect_and_check_cache_the_hash() {
+	let install = FakeInstall::new();
+	install.set_path();
+	let cache_path = install.dir.path().join("bb-patcher-hash-cache.json");
+	let (code, ..) = install.run(&["detect"]);
+	assert_eq!(code, 0);
+	assert!(!cache_path.exists());
+	let (code, ..) = install.run(&["detect", "--hash-cache"]);
+	assert_eq!(code, 0);
+	let cache = std::fs::read_to_string(&cache_path).unwrap();
+	let (_, stdout, _) = install.run(&["hash"]);
+	let hash = stdout.split_whitespace().last().unwrap().to_owned();
+	assert!(cache.contains(&hash), "{}", cache);
+
+	// A cached hash is trusted as long as the exe's size and modification time don't change
+	std::fs::write(&cache_path, cache.replace(&hash, &"00".repeat(32))).unwrap();
+	let (code, ..) = install.run(&["check", "--since-hash", &hash, "--hash-cache"]);
+	assert_eq!(code, 3);
+	let (code, ..) = install.run(&["check", "--since-hash", &hash]);
+	assert_eq!(code, 0);
+	let (code, ..) = install.run(&[
+		"check",
+		"--since-hash",
+		&hash,
+		"--hash-cache",
+		"--no-hash-cache",
+	]);
+	assert_eq!(code, 0);
+}
+
+#[test]
+fn check_reports_an_unpatched_exe() {
+	let install = FakeInstall::new();
+	install.set_path();
+	let (code, stdout, _) = install.run(&["check"]);
+	assert_eq!(code, 0);
+	assert!(stdout.contains("NOT PATCHED"), "{}", stdout);
+}
+
+#[test]
+fn patch4gb_refuses_an_unknown_exe() {
+	let install = FakeInstall::new();
+	let (code, _, stderr) = install.run(&["patch4gb", "--path", install.game().to_str().unwrap()]);
+	assert_eq!(code, 1);
+	assert!(stderr.contains("Unknown version"), "{}", stderr);
+	assert!(!install.is_laa());
+}
+
+#[test]
+fn patch4gb_dry_run_diff_leaves_the_exe_untouched() {
+	let install = FakeInstall::new();
+	install.set_path();
+	let (code, _, stderr) = install.run(&["patch4gb", "--dry-run-diff"]);
+	assert_eq!(code, 1);
+	assert!(stderr.contains("Unknown version"), "{}", stderr);
+
+	install.run(&["config", "--force-unknown-as", "gog"]);
+	let (code, stdout, _) = install.run(&["patch4gb", "--dry-run-diff"]);
+	assert_eq!(code, 0);
+	assert!(
+		stdout.contains("0x00000056  02    22    characteristics"),
+		"{}",
+		stdout
+	);
+	assert_eq!(std::fs::read(install.exe()).unwrap(), fake_exe());
+}
+
+#[test]
+fn patch4gb_refuses_a_corrupt_steamless_exe() {
+	let install = FakeInstall::new();
+	install.set_path();
+	install.run(&["config", "--force-unknown-as", "steamless"]);
+	let mut exe = fake_exe();
+	let size_of_raw_data = PE_HEADER_OFFSET + 24 + 0xE0 + 16;
+	exe[size_of_raw_data..size_of_raw_data + 4].copy_from_slice(&0x1000u32.to_le_bytes());
+	std::fs::write(install.exe(), exe).unwrap();
+
+	let (code, _, stderr) = install.run(&["patch4gb"]);
+	assert_eq!(code, 1);
+	assert!(stderr.contains("Steamless may have produced"), "{}", stderr);
+	assert!(stderr.contains("past the end of the file"), "{}", stderr);
+	assert!(!install.is_laa());
+}
+
+#[test]
+fn pe_offset_is_used_by_patch4gb_check_and_detect() {
+	let install = FakeInstall::new();
+	install.set_path();
+	install.run(&["config", "--force-unknown-as", "gog"]);
+	let mut exe = fake_exe();
+	exe[0x3C..0x40].copy_from_slice(&0x1000i32.to_le_bytes());
+	std::fs::write(install.exe(), exe).unwrap();
+	let (code, ..) = install.run(&["check"]);
+	assert_eq!(code, 1);
+
+	let (code, ..) = install.run(&["patch4gb", "--pe-offset", "0x40"]);
+	assert_eq!(code, 0);
+	assert!(install.is_laa());
+	let (code, stdout, _) = install.run(&["check", "--pe-offset", "0x40"]);
+	assert_eq!(code, 0);
+	assert!(stdout.contains("Status: PATCHED"), "{}", stdout);
+	assert!(stdout.contains("forced by --pe-offset"), "{}", stdout);
+	let (code, stdout, _) = install.run(&["detect", "--pe-offset", "64"]);
+	assert_eq!(code, 0);
+	assert!(stdout.contains("Already patched"), "{}", stdout);
+}
+
+#[test]
+fn patch4gb_check_and_restore() {
+	let install = FakeInstall::new();
+	install.set_path();
+	let (code, ..) = install.run(&["config", "--force-unknown-as", "gog"]);
+	assert_eq!(code, 0);
+
+	let (code, stdout, _) = install.run(&["patch4gb"]);
+	assert_eq!(code, 0);
+	assert!(stdout.contains("Patched GOG Version"), "{}", stdout);
+	assert!(install.is_laa());
+
+	let (code, stdout, _) = install.run(&["check"]);
+	assert_eq!(code, 0);
+	assert!(stdout.contains("Status: PATCHED"), "{}", stdout);
+	assert!(stdout.contains("Hash: unchanged"), "{}", stdout);
+
+	let hash_file = install.dir.path().join("exe.sha256");
+	let (code, stdout, _) =
+		install.run(&["patch4gb", "--output-hash", hash_file.to_str().unwrap()]);
+	assert_eq!(code, 6);
+	assert!(stdout.contains("Already patched"), "{}", stdout);
+	let (_, stdout, _) = install.run(&["hash"]);
+	let hash = std::fs::read_to_string(hash_file).unwrap();
+	assert!(stdout.contains(hash.trim()), "{}", stdout);
+	let (code, ..) = install.run(&["patch4gb", "--no-op-ok"]);
+	assert_eq!(code, 0);
+
+	let backup = format!("{}.gog_backup", install.exe().to_str().unwrap());
+	let (code, stdout, _) = install.run(&["restore", "--backup", &backup, "--yes"]);
+	assert_eq!(code, 0);
+	assert!(stdout.contains("Restored"), "{}", stdout);
+	assert!(!install.is_laa());
+	assert_eq!(std::fs::read(install.exe()).unwrap(), fake_exe());
+}
+
+#[test]
+fn set_path_forgets_the_patched_hash_of_the_previous_install() {
+	let install = FakeInstall::new();
+	install.set_path();
+	install.run(&["config", "--force-unknown-as", "gog"]);
+	let (code, ..) = install.run(&["patch4gb"]);
+	assert_eq!(code, 0);
+
+	let other = install.dir.path().join("Other Install");
+	std::fs::create_dir_all(other.join("win32")).unwrap();
+	std::fs::create_dir_all(other.join("data")).unwrap();
+	std::fs::write(other.join("win32").join("BattleBrothers.exe"), fake_exe()).unwrap();
+	std::fs::copy(
+		install.game().join("data").join("data_001.dat"),
+		other.join("data").join("data_001.dat"),
+	)
+	.unwrap();
+	let (code, ..) = install.run(&["set-path", other.to_str().unwrap()]);
+	assert_eq!(code, 0);
+
+	let (code, stdout, _) = install.run(&["check"]);
+	assert_eq!(code, 0);
+	assert!(!stdout.contains("Hash:"), "{}", stdout);
+}
+
+#[test]
+fn checksum_fix_writes_the_computed_checksum() {
+	let install = FakeInstall::new();
+	install.set_path();
+	let (code, stdout, _) = install.run(&["checksum"]);
+	assert_eq!(code, 0);
+	assert!(stdout.contains("Stored:   0x00000000"), "{}", stdout);
+	assert!(stdout.contains("No checksum is stored"), "{}", stdout);
+
+	let (code, stdout, _) = install.run(&["checksum", "--fix"]);
+	assert_eq!(code, 0);
+	assert!(stdout.contains("Fixed: wrote"), "{}", stdout);
+	let backup = format!("{}.checksum_backup", install.exe().to_str().unwrap());
+	assert_eq!(std::fs::read(backup).unwrap(), fake_exe());
+	let (code, stdout, _) = install.run(&["checksum"]);
+	assert_eq!(code, 0);
+	assert!(stdout.contains("The checksum is correct"), "{}", stdout);
+	let (code, stdout, _) = install.run(&["check"]);
+	assert_eq!(code, 0);
+	assert!(stdout.contains("Hash: unchanged"), "{}", stdout);
+
+	let mut exe = std::fs::read(install.exe()).unwrap();
+	*exe.last_mut().unwrap() ^= 0xFF;
+	std::fs::write(install.exe(), exe).unwrap();
+	let (code, stdout, _) = install.run(&["checksum"]);
+	assert_eq!(code, 9);
+	assert!(stdout.contains("The checksum is wrong"), "{}", stdout);
+}
+
+#[test]
+fn verify_install_fails_on_a_stale_preload() {
+	let install = FakeInstall::new();
+	install.set_path();
+	let (code, stdout, _) = install.run(&["verify-install"]);
+	assert_eq!(code, 8);
+	assert!(
+		stdout.contains("[FAIL] exe: Unknown exe without the 4GB patch"),
+		"{}",
+		stdout
+	);
+	assert!(stdout.contains("[PASS] msu: MSU 1.6.0"), "{}", stdout);
+	assert!(stdout.contains("[WARN] preload: no preload"), "{}", stdout);
+
+	install.run(&["config", "--force-unknown-as", "gog"]);
+	install.run(&["patch4gb"]);
+	install.run(&["preload"]);
+	let (code, stdout, _) = install.run(&["verify-install"]);
+	assert_eq!(code, 0);
+	assert!(
+		stdout.contains("[PASS] exe: 4GB patched, unchanged since bb-patcher patched it"),
+		"{}",
+		stdout
+	);
+	assert!(stdout.contains("Verdict: PASS"), "{}", stdout);
+
+	write_zip(
+		&install.game().join("data").join("mod_bar.zip"),
+		&[
+			("preload/on_running.txt", "gfx/bar.png"),
+			("gfx/bar.png", ""),
+		],
+	);
+	let (code, stdout, _) = install.run(&["verify-install"]);
+	assert_eq!(code, 8);
+	assert!(
+		stdout.contains("[FAIL] preload: doesn't match the installed mods"),
+		"{}",
+		stdout
+	);
+}
+
+#[test]
+fn verify_install_follows_the_preload_build_options() {
+	let install = FakeInstall::new();
+	install.set_path();
+	install.run(&["config", "--force-unknown-as", "gog"]);
+	install.run(&["patch4gb"]);
+	let data = install.game().join("data");
+	std::fs::rename(
+		data.join("mod_msu_1.6.0.zip"),
+		data.join("mod_msu_dev_1.6.0.zip"),
+	)
+	.unwrap();
+	let overrides = install.dir.path().join("overrides.txt");
+	std::fs::write(&overrides, "gfx/foo.png on_start\n").unwrap();
+	let (code, ..) = install.run(&[
+		"preload",
+		"--msu-id",
+		"mod_msu_dev",
+		"--registration-phase-override",
+		overrides.to_str().unwrap(),
+	]);
+	assert_eq!(code, 0);
+
+	let (code, stdout, _) = install.run(&["verify-install"]);
+	assert_eq!(code, 8);
+	assert!(stdout.contains("[FAIL] msu"), "{}", stdout);
+	let (code, stdout, _) = install.run(&["verify-install", "--msu-id", "mod_msu_dev"]);
+	assert_eq!(code, 0);
+	assert!(stdout.contains("[PASS] preload: up to date"), "{}", stdout);
+}
+
+#[test]
+fn patch4gb_requires_a_clean_backup() {
+	let install = FakeInstall::new();
+	install.set_path();
+	install.run(&["config", "--force-unknown-as", "gog"]);
+
+	let (code, _, stderr) = install.run(&["patch4gb", "--require-clean-backup"]);
+	assert_eq!(code, 1);
+	assert!(
+		stderr.contains("is not a known unpatched version"),
+		"{}",
+		stderr
+	);
+	assert!(!install.is_laa());
+	let backup = format!("{}.gog_backup", install.exe().to_str().unwrap());
+	assert!(!Path::new(&backup).exists());
+}
+
+#[test]
+fn patch4gb_prunes_old_backups() {
+	let install = FakeInstall::new();
+	install.set_path();
+	install.run(&["config", "--force-unknown-as", "gog"]);
+	let backups = || {
+		std::fs::read_dir(install.exe().parent().unwrap())
+			.unwrap()
+			.filter(|entry| {
+				let name = entry.as_ref().unwrap().file_name();
+				name.to_string_lossy().contains("_backup")
+			})
+			.count()
+	};
+
+	for i in 1..=3u8 {
+		let mut exe = fake_exe();
+		exe[EXE_SIZE - 1] = i;
+		std::fs::write(install.exe(), exe).unwrap();
+		let (code, stdout, _) = install.run(&["patch4gb", "--backup-retention", "2"]);
+		assert_eq!(code, 0, "{}", stdout);
+		assert_eq!(backups(), usize::from(i).min(2));
+	}
+	let (_, stdout, _) = install.run(&["list-backups"]);
+	assert!(stdout.contains("gog_backup."), "{}", stdout);
+}
+
+#[test]
+fn patch4gb_retention_keeps_the_backup_it_just_made() {
+	let install = FakeInstall::new();
+	install.set_path();
+	install.run(&["config", "--force-unknown-as", "gog"]);
+	let (code, ..) = install.run(&["patch4gb"]);
+	assert_eq!(code, 0);
+	let plain = PathBuf::from(format!("{}.gog_backup", install.exe().to_str().unwrap()));
+	assert!(plain.exists());
+
+	let mut exe = fake_exe();
+	exe[EXE_SIZE - 1] = 1;
+	std::fs::write(install.exe(), &exe).unwrap();
+	let (code, stdout, _) =
+		install.run(&["patch4gb", "--backup-compress", "--backup-retention", "1"]);
+	assert_eq!(code, 0, "{}", stdout);
+	assert!(install.is_laa());
+	assert!(!plain.exists(), "{}", stdout);
+	let compressed = format!("{}.bak.zip", plain.to_str().unwrap());
+	let mut zip = zip::ZipArchive::new(File::open(compressed).unwrap()).unwrap();
+	let mut backed_up = Vec::new();
+	std::io::Read::read_to_end(&mut zip.by_index(0).unwrap(), &mut backed_up).unwrap();
+	assert_eq!(backed_up, exe);
+}
+
+#[test]
+fn restore_asks_for_confirmation() {
+	let install = FakeInstall::new();
+	install.set_path();
+	install.run(&["config", "--force-unknown-as", "gog"]);
+	install.run(&["patch4gb"]);
+
+	let backup = format!("{}.gog_backup", install.exe().to_str().unwrap());
+	let (code, _, stderr) = install.run(&["restore", "--backup", &backup]);
+	assert_eq!(code, 1);
+	assert!(stderr.contains("Restore cancelled"), "{}", stderr);
+	assert!(install.is_laa());
+}
+
+#[test]
+fn uninstall_removes_backups_preload_and_config() {
+	let install = FakeInstall::new();
+	install.set_path();
+	install.run(&["config", "--force-unknown-as", "gog"]);
+	install.run(&["patch4gb"]);
+	install.run(&["preload"]);
+	let backup = PathBuf::from(format!("{}.gog_backup", install.exe().to_str().unwrap()));
+	let preload = install.game().join("data").join("~mod_msu_launcher.zip");
+	assert!(backup.exists() && preload.exists());
+
+	let (code, _, stderr) = install.run(&["uninstall"]);
+	assert_eq!(code, 1);
+	assert!(stderr.contains("Uninstall cancelled"), "{}", stderr);
+	assert!(backup.exists());
+
+	// The exe was patched as GOG, but its only backup is of an unknown version
+	let (code, stdout, _) = install.run(&["uninstall", "--clear-config", "--yes"]);
+	assert_eq!(code, 0);
+	assert!(stdout.contains("Restored"), "{}", stdout);
+	assert_eq!(std::fs::read(install.exe()).unwrap(), fake_exe());
+	assert!(!backup.exists());
+	assert!(!preload.exists());
+	assert!(!install.dir.path().join("bb-patcher-config.toml").exists());
+}
+
+#[test]
+fn uninstall_keeps_the_backups_without_an_unpatched_one() {
+	let install = FakeInstall::new();
+	install.set_path();
+	install.run(&["config", "--force-unknown-as", "gog"]);
+	install.run(&["patch4gb"]);
+	let backup = PathBuf::from(format!("{}.gog_backup", install.exe().to_str().unwrap()));
+	std::fs::copy(install.exe(), &backup).unwrap();
+
+	let (code, stdout, _) = install.run(&["uninstall", "--yes"]);
+	assert_eq!(code, 0);
+	assert!(
+		stdout.contains("there is no unpatched backup"),
+		"{}",
+		stdout
+	);
+	assert!(backup.exists());
+	assert!(install.is_laa());
+}
+
+#[test]
+fn check_exits_with_3_when_the_hash_changed() {
+	let install = FakeInstall::new();
+	install.set_path();
+	let (code, stdout, _) = install.run(&["check", "--since-hash", "00"]);
+	assert_eq!(code, 3);
+	assert!(stdout.contains("Hash: CHANGED"), "{}", stdout);
+}
+
+#[test]
+fn preload_registers_the_resources_of_every_mod() {
+	let install = FakeInstall::new();
+	install.set_path();
+	let (code, stdout, _) = install.run(&["preload"]);
+	assert_eq!(code, 0);
+	assert!(
+		stdout.contains("with 1 on_start and 2 on_running resources"),
+		"{}",
+		stdout
+	);
+	assert!(stdout.contains("found MSU 1.6.0"), "{}", stdout);
+
+	let preload = install.game().join("data").join("~mod_msu_launcher.zip");
+	let mut zip = zip::ZipArchive::new(File::open(preload).unwrap()).unwrap();
+	let mut on_running = String::new();
+	std::io::Read::read_to_string(
+		&mut zip.by_name("preload/on_running.txt").unwrap(),
+		&mut on_running,
+	)
+	.unwrap();
+	assert_eq!(on_running, "gfx/foo.png\ngfx/ui/msu.png\n");
+}
+
+#[test]
+fn preload_skips_archives_downloaded_twice() {
+	let install = FakeInstall::new();
+	install.set_path();
+	let data = install.game().join("data");
+	std::fs::copy(data.join("mod_foo.zip"), data.join("mod_foo (1).zip")).unwrap();
+
+	let (code, stdout, _) = install.run(&["preload"]);
+	assert_eq!(code, 0);
+	assert!(
+		stdout.contains("\"mod_foo (1).zip\" is byte-identical to \"mod_foo.zip\""),
+		"{}",
+		stdout
+	);
+	assert!(
+		stdout.contains("with 1 on_start and 2 on_running resources"),
+		"{}",
+		stdout
+	);
+}
+
+#[test]
+fn preload_profile_resources_lists_the_largest_resources() {
+	let install = FakeInstall::new();
+	install.set_path();
+	write_zip(
+		&install.game().join("data").join("mod_big.zip"),
+		&[
+			("preload/on_running.txt", "gfx/big.png"),
+			("gfx/big.png", &"x".repeat(5000)),
+		],
+	);
+
+	let (code, stdout, _) = install.run(&["preload", "--profile-resources", "--dry-run"]);
+	assert_eq!(code, 0);
+	assert!(stdout.contains("Scanned 4 archive(s) in"), "{}", stdout);
+	assert!(stdout.contains("Slowest archives:"), "{}", stdout);
+	let largest = stdout.split("Largest resources:").nth(1).unwrap();
+	let first = largest.lines().nth(1).unwrap();
+	assert!(
+		first.contains("5000 bytes  gfx/big.png (mod_big.zip)"),
+		"{}",
+		stdout
+	);
+}
+
+#[test]
+fn preload_skips_copies_of_an_earlier_preload() {
+	let install = FakeInstall::new();
+	install.set_path();
+	install.run(&["preload"]);
+	let data = install.game().join("data");
+	std::fs::copy(
+		data.join("~mod_msu_launcher.zip"),
+		data.join("~mod_msu_launcher (1).zip"),
+	)
+	.unwrap();
+
+	let (code, stdout, _) = install.run(&["preload"]);
+	assert_eq!(code, 0);
+	assert!(
+		stdout.contains("\"~mod_msu_launcher (1).zip\" is a preload generated by an earlier run"),
+		"{}",
+		stdout
+	);
+	assert!(
+		!stdout.contains("ships its own preload content"),
+		"{}",
+		stdout
+	);
+}
+
+#[cfg(unix)]
+#[test]
+fn preload_scans_a_mod_bundling_the_preload_script() {
+	let install = FakeInstall::new();
+	install.set_path();
+	write_zip(
+		&install.game().join("data").join("mod_bar.zip"),
+		&[
+			("scripts/!mods_preload/mod_msu_launcher.nut", ""),
+			("preload/on_running.txt", "gfx/bar.png"),
+			("gfx/bar.png", ""),
+		],
+	);
+
+	let (code, stdout, _) = install.run(&["preload"]);
+	assert_eq!(code, 0);
+	assert!(
+		stdout.contains("ships its own preload content"),
+		"{}",
+		stdout
+	);
+	assert!(
+		!stdout.contains("generated by an earlier run"),
+		"{}",
+		stdout
+	);
+	assert!(
+		stdout.contains("with 1 on_start and 3 on_running resources"),
+		"{}",
+		stdout
+	);
+}
+
+#[test]
+fn preload_follows_symlinked_mods() {
+	let install = FakeInstall::new();
+	install.set_path();
+	let central = install.dir.path().join("central");
+	std::fs::create_dir(&central).unwrap();
+	write_zip(
+		&central.join("mod_linked.zip"),
+		&[("preload/on_start.txt", "gfx/linked.png")],
+	);
+	let data = install.game().join("data");
+	std::os::unix::fs::symlink(central.join("mod_linked.zip"), data.join("mod_linked.zip"))
+		.unwrap();
+	std::os::unix::fs::symlink(central.join("missing.zip"), data.join("mod_broken.zip")).unwrap();
+
+	let (code, stdout, _) = install.run(&["--verbose", "preload"]);
+	assert_eq!(code, 0);
+	assert!(
+		stdout.contains("with 2 on_start and 2 on_running resources"),
+		"{}",
+		stdout
+	);
+	assert!(
+		stdout.contains("Symlinked mod: mod_linked.zip ->"),
+		"{}",
+		stdout
+	);
+	assert!(
+		stdout.contains("whose target doesn't exist: mod_broken.zip"),
+		"{}",
+		stdout
+	);
+}
+
+#[test]
+fn preload_dry_run_only_writes_the_report() {
+	let install = FakeInstall::new();
+	install.set_path();
+	let report = install.dir.path().join("report.json");
+	let (code, stdout, _) =
+		install.run(&["preload", "--dry-run", "--report", report.to_str().unwrap()]);
+	assert_eq!(code, 0);
+	assert!(
+		stdout.contains("Dry run, would write preload"),
+		"{}",
+		stdout
+	);
+	assert!(!install
+		.game()
+		.join("data")
+		.join("~mod_msu_launcher.zip")
+		.exists());
+
+	let report: serde_json::Value =
+		serde_json::from_str(&std::fs::read_to_string(report).unwrap()).unwrap();
+	assert_eq!(report["dry_run"], true);
+	assert_eq!(report["on_start"], serde_json::json!(["sounds/foo.wav"]));
+}
+
+#[test]
+fn preload_verify_resources_flags_names_invalid_on_windows() {
+	let install = FakeInstall::new();
+	install.set_path();
+	write_zip(
+		&install.game().join("data").join("mod_bar.zip"),
+		&[
+			("preload/on_running.txt", "gfx/con.png\ngfx/what?.png"),
+			("gfx/con.png", ""),
+			("gfx/what?.png", ""),
+		],
+	);
+
+	let (code, stdout, _) = install.run(&["preload", "--verify-resources"]);
+	assert_eq!(code, 0);
+	assert!(
+		stdout.contains("gfx/con.png (CON is a reserved device name)"),
+		"{}",
+		stdout
+	);
+	assert!(
+		stdout.contains("gfx/what?.png ('?' is not allowed in names)"),
+		"{}",
+		stdout
+	);
+
+	let (code, ..) = install.run(&["preload", "--verify-resources", "--strict"]);
+	assert_eq!(code, 1);
+}
+
+#[test]
+fn preload_count_only_estimates_without_creating_the_preload() {
+	let install = FakeInstall::new();
+	install.set_path();
+	let (code, stdout, _) = install.run(&["--json", "preload", "--count-only"]);
+	assert_eq!(code, 0);
+	let count: serde_json::Value = serde_json::from_str(&stdout).unwrap();
+	assert_eq!(count["mods"], 2);
+	assert_eq!(count["estimated_resources"], 3);
+	assert!(!install
+		.game()
+		.join("data")
+		.join("~mod_msu_launcher.zip")
+		.exists());
+}
+
+#[test]
+fn preload_strict_builds_without_msu() {
+	let install = FakeInstall::new();
+	install.set_path();
+	std::fs::remove_file(install.game().join("data").join("mod_msu_1.6.0.zip")).unwrap();
+	std::fs::write(
+		install.game().join("data").join("mod_bar.zip"),
+		b"Rar!\x1a\x07\x00",
+	)
+	.unwrap();
+
+	let (code, stdout, _) = install.run(&["preload", "--strict"]);
+	assert_eq!(code, 0);
+	assert!(
+		stdout.contains("Note: MSU (mod id \"mod_msu\") is not installed"),
+		"{}",
+		stdout
+	);
+	assert!(
+		stdout.contains("Warning: \"mod_bar.zip\" is a RAR archive"),
+		"{}",
+		stdout
+	);
+	assert!(install
+		.game()
+		.join("data")
+		.join("~mod_msu_launcher.zip")
+		.exists());
+}
+
+#[test]
+fn preload_notes_denylisted_mods() {
+	let install = FakeInstall::new();
+	install.set_path();
+	let denylist = install.dir.path().join("denylist.txt");
+	std::fs::write(&denylist, "# known-bad mods\nmod_foo crashes on startup\n").unwrap();
+
+	let (code, stdout, _) = install.run(&["preload", "--denylist", denylist.to_str().unwrap()]);
+	assert_eq!(code, 0);
+	assert!(
+		stdout.contains("Note: \"mod_foo.zip\" is a known-bad mod: crashes on startup"),
+		"{}",
+		stdout
+	);
+
+	let (code, ..) = install.run(&[
+		"preload",
+		"--strict",
+		"--denylist",
+		denylist.to_str().unwrap(),
+	]);
+	assert_eq!(code, 1);
+}
+
+#[test]
+fn preload_verify_resources_flags_broken_scripts() {
+	let install = FakeInstall::new();
+	install.set_path();
+	write_zip(
+		&install.game().join("data").join("mod_bar.zip"),
+		&[
+			("scripts/bom.nut", "\u{FEFF}local x = 1;"),
+			("scripts/code.nut", "// größe\nlocal größe = 1;"),
+			("scripts/string.nut", "local name = \"größe\"; /* ü */"),
+		],
+	);
+
+	let (code, stdout, _) = install.run(&["preload", "--verify-resources"]);
+	assert_eq!(code, 0);
+	assert!(
+		stdout.contains("scripts/bom.nut (UTF-8 with BOM)"),
+		"{}",
+		stdout
+	);
+	assert!(
+		stdout.contains(
+			"scripts/code.nut (non-ASCII character outside of strings and comments on line 2)"
+		),
+		"{}",
+		stdout
+	);
+	assert!(!stdout.contains("scripts/string.nut"), "{}", stdout);
+}
+
+#[test]
+fn preload_intra_mod_sort_keeps_the_declared_order() {
+	let install = FakeInstall::new();
+	install.set_path();
+	write_zip(
+		&install.game().join("data").join("mod_bar.zip"),
+		&[("preload/on_running.txt", "gfx/z.png\ngfx/a.png")],
+	);
+	let extracted = install.dir.path().join("extracted");
+	let (code, stdout, _) = install.run(&[
+		"preload",
+		"--intra-mod-sort",
+		"declared",
+		"--extract-to",
+		extracted.to_str().unwrap(),
+	]);
+	assert_eq!(code, 0);
+	assert!(
+		stdout.contains("\"mod_bar.zip\" registers its resources in an explicit order"),
+		"{}",
+		stdout
+	);
+	let on_running =
+		std::fs::read_to_string(extracted.join("preload").join("on_running.txt")).unwrap();
+	assert_eq!(
+		on_running,
+		"gfx/z.png\ngfx/a.png\ngfx/foo.png\ngfx/ui/msu.png\n"
+	);
+}
+
+#[test]
+fn preload_since_only_rescans_newer_mods() {
+	let install = FakeInstall::new();
+	install.set_path();
+	let data = install.game().join("data");
+	install.run(&["preload"]);
+	let old = std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_000_000_000);
+	for name in ["data_001.dat", "mod_msu_1.6.0.zip", "mod_foo.zip"] {
+		File::options()
+			.write(true)
+			.open(data.join(name))
+			.unwrap()
+			.set_modified(old)
+			.unwrap();
+	}
+	write_zip(
+		&data.join("mod_bar.zip"),
+		&[
+			("preload/on_running.txt", "gfx/bar.png"),
+			("gfx/bar.png", ""),
+		],
+	);
+
+	let preload = data.join("~mod_msu_launcher.zip");
+	let args = [
+		"preload",
+		"--merge",
+		preload.to_str().unwrap(),
+		"--since",
+		"2010-01-01",
+	];
+	let (code, stdout, _) = install.run(&args);
+	assert_eq!(code, 0);
+	assert!(
+		stdout.contains("rescanned 1 mod(s) modified since --since: mod_bar.zip"),
+		"{}",
+		stdout
+	);
+	assert!(
+		stdout.contains("with 1 on_start and 3 on_running resources"),
+		"{}",
+		stdout
+	);
+	assert!(stdout.contains("found MSU 1.6.0"), "{}", stdout);
+
+	std::fs::remove_file(&preload).unwrap();
+	let (code, stdout, _) = install.run(&args);
+	assert_eq!(code, 0);
+	assert!(stdout.contains("scanned all mods instead"), "{}", stdout);
+}
+
+#[test]
+fn preload_tag_sources_embeds_the_mod_ids() {
+	let install = FakeInstall::new();
+	install.set_path();
+	let extracted = install.dir.path().join("extracted");
+	let (code, ..) = install.run(&[
+		"preload",
+		"--tag-sources",
+		"--extract-to",
+		extracted.to_str().unwrap(),
+	]);
+	assert_eq!(code, 0);
+	let script = std::fs::read_to_string(
+		extracted
+			.join("scripts")
+			.join("!mods_preload")
+			.join("mod_msu_launcher.nut"),
+	)
+	.unwrap();
+	assert!(
+		script.contains("[\"gfx/ui/msu.png\"] = [\"mod_msu\"],"),
+		"{}",
+		script
+	);
+}
+
+#[test]
+fn preload_disable_removes_the_preload() {
+	let install = FakeInstall::new();
+	install.set_path();
+	install.run(&["preload"]);
+	let preload = install.game().join("data").join("~mod_msu_launcher.zip");
+	assert!(preload.exists());
+
+	let (code, stdout, _) = install.run(&["preload", "--disable"]);
+	assert_eq!(code, 0);
+	assert!(stdout.contains("Disabled preload generation"), "{}", stdout);
+	assert!(!preload.exists());
+
+	let (code, stdout, _) = install.run(&["preload"]);
+	assert_eq!(code, 0);
+	assert!(stdout.contains("disabled in the config"), "{}", stdout);
+	assert!(!preload.exists());
+}
+
+#[test]
+fn preload_output_to_stdout_only_streams_the_zip() {
+	let install = FakeInstall::new();
+	install.set_path();
+	install.run(&["preload", "--disable"]);
+
+	let output = install
+		.cmd()
+		.args(["preload", "--output", "-"])
+		.output()
+		.unwrap();
+	assert_eq!(output.status.code(), Some(1));
+	assert!(output.stdout.is_empty());
+
+	let output = install
+		.cmd()
+		.args(["preload", "--enable", "--output", "-"])
+		.output()
+		.unwrap();
+	assert_eq!(output.status.code(), Some(0));
+	assert!(output.stdout.starts_with(b"PK"));
+	let stderr = String::from_utf8(output.stderr).unwrap();
+	assert!(stderr.contains("Enabled preload generation"), "{}", stderr);
+}
+
+#[test]
+fn preload_failing_to_replace_the_output_leaves_no_partial_file() {
+	let install = FakeInstall::new();
+	install.set_path();
+	let output = install.dir.path().join("output");
+	std::fs::create_dir(&output).unwrap();
+
+	let (code, ..) = install.run(&["preload", "--enable", "--output", output.to_str().unwrap()]);
+	assert_eq!(code, 1);
+	assert!(output.is_dir());
+	assert!(!install.dir.path().join("output.partial").exists());
+}
+
+#[test]
+fn preload_analyze_reports_without_creating_the_preload() {
+	let install = FakeInstall::new();
+	install.set_path();
+	write_zip(
+		&install.game().join("data").join("mod_bar.zip"),
+		&[("preload/on_running.txt", "gfx/foo.png\ngfx/missing.png")],
+	);
+
+	let (code, stdout, _) = install.run(&["preload", "--analyze"]);
+	assert_eq!(code, 0);
+	assert!(
+		stdout.contains("registered by more than one mod: gfx/foo.png"),
+		"{}",
+		stdout
+	);
+	assert!(stdout.contains("Verdict: WARN"), "{}", stdout);
+	assert!(!install
+		.game()
+		.join("data")
+		.join("~mod_msu_launcher.zip")
+		.exists());
+
+	let (code, ..) = install.run(&["preload", "--analyze", "--strict"]);
+	assert_eq!(code, 4);
+}
+
+#[test]
+fn import_manifest_lists_the_differences_to_an_exported_one() {
+	let install = FakeInstall::new();
+	install.set_path();
+	let data = install.game().join("data");
+	write_zip(&data.join("mod_baz.zip"), &[]);
+	let manifest = install.dir.path().join("manifest.json");
+	let (code, ..) = install.run(&["export-manifest", "--output", manifest.to_str().unwrap()]);
+	assert_eq!(code, 0);
+
+	let (code, stdout, _) = install.run(&["import-manifest", manifest.to_str().unwrap()]);
+	assert_eq!(code, 0);
+	assert!(
+		stdout.contains("The install matches the manifest"),
+		"{}",
+		stdout
+	);
+
+	std::fs::rename(
+		data.join("mod_msu_1.6.0.zip"),
+		data.join("mod_msu_1.7.0.zip"),
+	)
+	.unwrap();
+	std::fs::remove_file(data.join("mod_baz.zip")).unwrap();
+	std::fs::write(data.join("mod_foo.zip"), "not a zip").unwrap();
+	write_zip(&data.join("mod_bar.zip"), &[]);
+	let (code, stdout, _) = install.run(&["import-manifest", manifest.to_str().unwrap()]);
+	assert_eq!(code, 10);
+	assert!(
+		stdout.contains("version mismatch: mod_msu is 1.7.0 here, 1.6.0 in the manifest"),
+		"{}",
+		stdout
+	);
+	assert!(
+		stdout.contains("missing mod: mod_baz (mod_baz.zip)"),
+		"{}",
+		stdout
+	);
+	assert!(
+		stdout.contains(
+			"unscanned mod: mod_foo is installed as mod_foo.zip, but was left out: not a valid \
+			zip archive"
+		),
+		"{}",
+		stdout
+	);
+	assert!(
+		stdout.contains("extra mod: mod_bar (mod_bar.zip)"),
+		"{}",
+		stdout
+	);
+}
+
+#[test]
+fn invalid_arguments_exit_with_2() {
+	let install = FakeInstall::new();
+	let (code, ..) = install.run(&["patch4gb", "--no-such-flag"]);
+	assert_eq!(code, 2);
+	let (code, ..) = install.run(&["--print-exit-codes", "detect"]);
+	assert_eq!(code, 2);
+	let (code, stdout, _) = install.run(&["--json"]);
+	assert_eq!(code, 2);
+	assert!(stdout.is_empty(), "{}", stdout);
+}
+
+#[test]
+fn errors_are_printed_as_json() {
+	let install = FakeInstall::new();
+	let missing = install.dir.path().join("missing");
+	let (code, _, stderr) = install.run(&["--json", "detect", "--path", missing.to_str().unwrap()]);
+	assert_eq!(code, 1);
+	let error: serde_json::Value = serde_json::from_str(&stderr).unwrap();
+	assert!(error["error"].as_str().unwrap().contains("Invalid path"));
+}